@@ -0,0 +1,91 @@
+//! Record build info for the `build_info` metric and the readiness checks
+//!
+//! Sets `GIT_SHA`, `BUILD_TIMESTAMP`, `FEATURES` and the versions of the
+//! migrations of each backend (`MIGRATIONS_MYSQL`, ...) at compile time.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let git_sha = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|sha| sha.trim().to_string())
+        .unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=GIT_SHA={}", git_sha);
+    watch_git();
+    println!("cargo:rerun-if-changed=migrations");
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+
+    // Cargo sets CARGO_FEATURE_{NAME} for each enabled feature
+    let prefix = "CARGO_FEATURE_";
+    let mut features = env::vars()
+        .map(|(name, _)| name)
+        .filter(|name| name.starts_with(prefix))
+        .map(|name| name[prefix.len()..].to_lowercase().replace('_', "-"))
+        .collect::<Vec<_>>();
+    features.sort();
+    println!("cargo:rustc-env=FEATURES={}", features.join(","));
+
+    for backend in &["mysql", "postgres", "sqlite"] {
+        println!(
+            "cargo:rustc-env=MIGRATIONS_{}={}",
+            backend.to_uppercase(),
+            migration_versions(backend).join(",")
+        );
+    }
+}
+
+/// Rerun on a new commit or checkout
+///
+/// HEAD only changes when switching branches, and the ref it points to on
+/// every commit. `git gc` moves refs into `packed-refs`, and a commit on a
+/// packed ref writes a new file, so its folder is watched until it exists.
+/// Cargo reruns on every build while it watches a missing path, e.g.
+/// building from a source archive, so those are skipped.
+fn watch_git() {
+    let mut paths = vec![
+        PathBuf::from(".git/HEAD"),
+        PathBuf::from(".git/packed-refs"),
+    ];
+    if let Ok(head) = fs::read_to_string(".git/HEAD") {
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            let reference = Path::new(".git").join(reference);
+            match reference.parent() {
+                Some(folder) if !reference.exists() => paths.push(folder.to_path_buf()),
+                _ => paths.push(reference),
+            }
+        }
+    }
+    for path in paths.iter().filter(|path| path.exists()) {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+}
+
+/// Versions of the migrations of a backend, named the way diesel records them
+///
+/// e.g. `2019-09-30-115154_create_users` is version `20190930115154`
+fn migration_versions(backend: &str) -> Vec<String> {
+    let mut versions = fs::read_dir(format!("migrations/{}", backend))
+        .expect("Could not read the migrations folder")
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            Some(name.split('_').next()?.replace('-', ""))
+        })
+        .collect::<Vec<_>>();
+    versions.sort();
+    versions
+}
@@ -0,0 +1,18 @@
+SET time_zone = '+00:00';
+
+ALTER TABLE users
+  MODIFY id VARBINARY(36) NOT NULL,
+  MODIFY created_by VARBINARY(36) NOT NULL,
+  MODIFY updated_by VARBINARY(36) NOT NULL;
+
+UPDATE users SET
+  id = LOWER(INSERT(INSERT(INSERT(INSERT(HEX(id), 9, 0, '-'), 14, 0, '-'), 19, 0, '-'), 24, 0, '-')),
+  created_by = LOWER(INSERT(INSERT(INSERT(INSERT(HEX(created_by), 9, 0, '-'), 14, 0, '-'), 19, 0, '-'), 24, 0, '-')),
+  updated_by = LOWER(INSERT(INSERT(INSERT(INSERT(HEX(updated_by), 9, 0, '-'), 14, 0, '-'), 19, 0, '-'), 24, 0, '-'));
+
+ALTER TABLE users
+  MODIFY id VARCHAR(36) NOT NULL,
+  MODIFY created_by VARCHAR(36) NOT NULL,
+  MODIFY created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  MODIFY updated_by VARCHAR(36) NOT NULL,
+  MODIFY updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP;
@@ -0,0 +1,19 @@
+-- Convert the TIMESTAMP columns as UTC, they are stored as UTC DATETIMEs
+SET time_zone = '+00:00';
+
+ALTER TABLE users
+  MODIFY id VARBINARY(36) NOT NULL,
+  MODIFY created_by VARBINARY(36) NOT NULL,
+  MODIFY updated_by VARBINARY(36) NOT NULL;
+
+UPDATE users SET
+  id = UNHEX(REPLACE(id, '-', '')),
+  created_by = UNHEX(REPLACE(created_by, '-', '')),
+  updated_by = UNHEX(REPLACE(updated_by, '-', ''));
+
+ALTER TABLE users
+  MODIFY id BINARY(16) NOT NULL,
+  MODIFY created_by BINARY(16) NOT NULL,
+  MODIFY created_at DATETIME(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+  MODIFY updated_by BINARY(16) NOT NULL,
+  MODIFY updated_at DATETIME(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6);
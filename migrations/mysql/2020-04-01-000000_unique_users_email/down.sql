@@ -0,0 +1 @@
+DROP INDEX email ON users;
@@ -0,0 +1,2 @@
+-- MySQL reports the index name in duplicate entry errors, so name it after the column
+CREATE UNIQUE INDEX email ON users (email);
@@ -0,0 +1,6 @@
+ALTER TABLE users
+  ALTER COLUMN id TYPE VARCHAR(36) USING id::TEXT,
+  ALTER COLUMN created_by TYPE VARCHAR(36) USING created_by::TEXT,
+  ALTER COLUMN created_at TYPE TIMESTAMP USING created_at AT TIME ZONE 'UTC',
+  ALTER COLUMN updated_by TYPE VARCHAR(36) USING updated_by::TEXT,
+  ALTER COLUMN updated_at TYPE TIMESTAMP USING updated_at AT TIME ZONE 'UTC';
@@ -0,0 +1,6 @@
+ALTER TABLE users
+  ALTER COLUMN id TYPE UUID USING id::UUID,
+  ALTER COLUMN created_by TYPE UUID USING created_by::UUID,
+  ALTER COLUMN created_at TYPE TIMESTAMPTZ USING created_at AT TIME ZONE 'UTC',
+  ALTER COLUMN updated_by TYPE UUID USING updated_by::UUID,
+  ALTER COLUMN updated_at TYPE TIMESTAMPTZ USING updated_at AT TIME ZONE 'UTC';
@@ -0,0 +1,35 @@
+#!/bin/sh
+# Generate a development CA and a localhost certificate signed by it
+#
+# Usage: scripts/gen-certs.sh [dir], dir defaults to certs
+#
+# For local development and tests only. The keys are PKCS#8 P-256 keys,
+# and the certificates are valid for a year.
+set -eu
+
+dir="${1:-certs}"
+mkdir -p "$dir"
+cd "$dir"
+
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+  -keyout ca-key.pem -out ca.pem -days 365 -sha256 \
+  -subj "/CN=rust_actix_example development CA" \
+  -addext "basicConstraints=critical,CA:TRUE" \
+  -addext "keyUsage=critical,keyCertSign,cRLSign"
+
+openssl req -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+  -keyout localhost-key.pem -out localhost.csr -sha256 \
+  -subj "/CN=localhost"
+
+cat > localhost.ext <<EXT
+basicConstraints=CA:FALSE
+keyUsage=critical,digitalSignature,keyEncipherment
+extendedKeyUsage=serverAuth
+subjectAltName=DNS:localhost,IP:127.0.0.1
+EXT
+
+openssl x509 -req -in localhost.csr -CA ca.pem -CAkey ca-key.pem \
+  -CAcreateserial -out localhost.pem -days 365 -sha256 \
+  -extfile localhost.ext
+
+rm -f localhost.csr localhost.ext ca.srl
@@ -1,8 +1,14 @@
+use crate::cache::Cache;
 use crate::config::CONFIG;
 use crate::errors::ApiError;
-use actix_identity::{CookieIdentityPolicy, IdentityService};
+use crate::session::{SessionIdentityPolicy, SessionStore};
+use actix_identity::{CookieIdentityPolicy, IdentityPolicy, IdentityService, RequestIdentity};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, AUTHORIZATION};
+use actix_web::{Error, HttpMessage};
 use argon2rs::argon2i_simple;
 use chrono::{Duration, Utc};
+use futures::future::{FutureExt, LocalBoxFuture};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use uuid::Uuid;
 
@@ -25,7 +31,7 @@ impl PrivateClaim {
 
 /// Create a json web token (JWT)
 pub fn create_jwt(private_claim: PrivateClaim) -> Result<String, ApiError> {
-    let encoding_key = EncodingKey::from_secret(&CONFIG.jwt_key.as_ref());
+    let encoding_key = EncodingKey::from_secret(CONFIG.jwt_key.as_ref());
     encode(
         &Header::default(),
         &private_claim,
@@ -36,36 +42,100 @@ pub fn create_jwt(private_claim: PrivateClaim) -> Result<String, ApiError> {
 
 /// Decode a json web token (JWT)
 pub fn decode_jwt(token: &str) -> Result<PrivateClaim, ApiError> {
-    let decoding_key = DecodingKey::from_secret(&CONFIG.jwt_key.as_ref());
+    let decoding_key = DecodingKey::from_secret(CONFIG.jwt_key.as_ref());
     decode::<PrivateClaim>(token, &decoding_key, &Validation::default())
         .map(|data| data.claims)
         .map_err(|e| ApiError::CannotDecodeJwtToken(e.to_string()))
 }
 
+/// The JWT of a request
+///
+/// A bearer token in the `Authorization` header takes precedence over the
+/// identity in the cookie or session.
+pub fn request_jwt<R: RequestIdentity + HttpMessage>(req: &R) -> Option<String> {
+    bearer_token(req.headers())
+        .map(String::from)
+        .or_else(|| req.get_identity())
+}
+
+/// The token of an `Authorization: Bearer <token>` header
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    if value.len() > 7 && value[..7].eq_ignore_ascii_case("bearer ") {
+        Some(value[7..].trim())
+    } else {
+        None
+    }
+}
+
 /// Encrypt a password
 ///
 /// Uses the argon2i algorithm.
 /// auth_salt is environment-configured.
 pub fn hash(password: &str) -> String {
-    argon2i_simple(&password, &CONFIG.auth_salt)
+    argon2i_simple(password, &CONFIG.auth_salt)
         .iter()
         .map(|b| format!("{:02x}", b))
         .collect()
 }
 
+/// The identity policy selected by `SESSION_STORE`
+pub enum AuthIdentityPolicy {
+    Cookie(CookieIdentityPolicy),
+    Session(SessionIdentityPolicy),
+}
+
+impl IdentityPolicy for AuthIdentityPolicy {
+    type Future = LocalBoxFuture<'static, Result<Option<String>, Error>>;
+    type ResponseFuture = LocalBoxFuture<'static, Result<(), Error>>;
+
+    fn from_request(&self, request: &mut ServiceRequest) -> Self::Future {
+        match self {
+            AuthIdentityPolicy::Cookie(policy) => policy.from_request(request).boxed_local(),
+            AuthIdentityPolicy::Session(policy) => policy.from_request(request),
+        }
+    }
+
+    fn to_response<B>(
+        &self,
+        identity: Option<String>,
+        changed: bool,
+        response: &mut ServiceResponse<B>,
+    ) -> Self::ResponseFuture {
+        match self {
+            AuthIdentityPolicy::Cookie(policy) => policy
+                .to_response(identity, changed, response)
+                .boxed_local(),
+            AuthIdentityPolicy::Session(policy) => policy.to_response(identity, changed, response),
+        }
+    }
+}
+
 /// Gets the identidy service for injection into an Actix app
-pub fn get_identity_service() -> IdentityService<CookieIdentityPolicy> {
-    IdentityService::new(
-        CookieIdentityPolicy::new(&CONFIG.session_key.as_ref())
-            .name(&CONFIG.session_name)
-            .max_age_time(chrono::Duration::minutes(CONFIG.session_timeout))
-            .secure(CONFIG.session_secure),
-    )
+pub fn get_identity_service(cache: Cache) -> IdentityService<AuthIdentityPolicy> {
+    identity_service(CONFIG.session_store, cache)
+}
+
+/// Gets the identity service for a session store
+///
+/// Server-side sessions are kept in the application cache.
+pub fn identity_service(store: SessionStore, cache: Cache) -> IdentityService<AuthIdentityPolicy> {
+    let policy = match store {
+        SessionStore::Cookie => AuthIdentityPolicy::Cookie(
+            CookieIdentityPolicy::new(CONFIG.session_key.as_ref())
+                .name(&CONFIG.session_name)
+                .max_age_time(chrono::Duration::minutes(CONFIG.session_timeout))
+                .secure(CONFIG.session_secure),
+        ),
+        SessionStore::Redis => AuthIdentityPolicy::Session(SessionIdentityPolicy::new(cache)),
+    };
+    IdentityService::new(policy)
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use actix_web::test;
     static EMAIL: &str = "test@test.com";
 
     #[test]
@@ -97,4 +167,16 @@ pub mod tests {
         let decoded = decode_jwt(&jwt).unwrap();
         assert_eq!(private_claim, decoded);
     }
+
+    #[test]
+    fn it_reads_a_bearer_token() {
+        let req = test::TestRequest::with_header(AUTHORIZATION, "Bearer abc.def")
+            .to_http_request();
+        assert_eq!(bearer_token(req.headers()), Some("abc.def"));
+        assert_eq!(request_jwt(&req), Some("abc.def".into()));
+
+        let req = test::TestRequest::with_header(AUTHORIZATION, "Basic abc").to_http_request();
+        assert_eq!(bearer_token(req.headers()), None);
+        assert_eq!(request_jwt(&req), None);
+    }
 }
@@ -0,0 +1,308 @@
+//! Cache backend that keeps entries in process, used without Redis and in tests
+//!
+//! The least recently used entry is evicted once the capacity is reached,
+//! and expired entries are dropped when they're read. Counters are kept
+//! apart, so caching values can't evict (and so reset) a rate limit, and
+//! expired counters are dropped once there are more than the capacity.
+//!
+//! Sessions need Redis, since an evicted session would log its user out.
+
+use crate::cache::CacheBackend;
+use crate::errors::ApiError;
+use crate::state::{Slot, Store};
+use async_trait::async_trait;
+use lru::LruCache;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Entry {
+    value: String,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn new(value: String, ttl: Option<Duration>) -> Self {
+        Self {
+            value,
+            expires_at: ttl.map(|ttl| Instant::now() + ttl),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Instant::now())
+    }
+}
+
+pub struct MemoryCache {
+    entries: Mutex<LruCache<String, Entry>>,
+    counters: Store,
+    capacity: usize,
+}
+
+impl MemoryCache {
+    /// Create a cache that holds at most `capacity` entries
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            counters: Store::default(),
+            capacity,
+        }
+    }
+
+    /// An entry, or else a counter
+    fn get_value(&self, entries: &mut LruCache<String, Entry>, key: &str) -> Option<String> {
+        Self::get_entry(entries, key).or_else(|| {
+            self.counters
+                .get(&Slot::<u64>::new(key))
+                .map(|count| count.to_string())
+        })
+    }
+
+    fn get_entry(entries: &mut LruCache<String, Entry>, key: &str) -> Option<String> {
+        // lru only looks entries up by the owned key type
+        let key = key.to_string();
+        let expired = entries.peek(&key)?.is_expired();
+        if expired {
+            entries.pop(&key);
+            return None;
+        }
+        entries.get(&key).map(|entry| entry.value.clone())
+    }
+
+    /// Sets are kept as JSON arrays
+    fn get_members(
+        entries: &mut LruCache<String, Entry>,
+        key: &str,
+    ) -> Result<BTreeSet<String>, ApiError> {
+        Self::get_entry(entries, key)
+            .map(|value| serde_json::from_str(&value))
+            .transpose()
+            .map(Option::unwrap_or_default)
+            .map_err(|error| ApiError::CacheError(error.to_string()))
+    }
+
+    fn encode(members: &BTreeSet<String>) -> Result<String, ApiError> {
+        serde_json::to_string(members).map_err(|error| ApiError::CacheError(error.to_string()))
+    }
+}
+
+#[async_trait(?Send)]
+impl CacheBackend for MemoryCache {
+    async fn get(&self, key: &str) -> Result<Option<String>, ApiError> {
+        let mut entries = self.entries.lock().expect("Could not acquire lock");
+        Ok(self.get_value(&mut entries, key))
+    }
+
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>, ApiError> {
+        let mut entries = self.entries.lock().expect("Could not acquire lock");
+        Ok(keys
+            .iter()
+            .map(|key| self.get_value(&mut entries, key))
+            .collect())
+    }
+
+    async fn set(&self, key: &str, value: String, ttl: Option<Duration>) -> Result<(), ApiError> {
+        let mut entries = self.entries.lock().expect("Could not acquire lock");
+        entries.put(key.to_string(), Entry::new(value, ttl));
+        Ok(())
+    }
+
+    async fn set_if_absent(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Duration,
+    ) -> Result<bool, ApiError> {
+        let mut entries = self.entries.lock().expect("Could not acquire lock");
+        if Self::get_entry(&mut entries, key).is_some() {
+            return Ok(false);
+        }
+        entries.put(key.to_string(), Entry::new(value, Some(ttl)));
+        Ok(true)
+    }
+
+    async fn set_if_present(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Duration,
+    ) -> Result<bool, ApiError> {
+        let mut entries = self.entries.lock().expect("Could not acquire lock");
+        if Self::get_entry(&mut entries, key).is_none() {
+            return Ok(false);
+        }
+        entries.put(key.to_string(), Entry::new(value, Some(ttl)));
+        Ok(true)
+    }
+
+    async fn delete_many(&self, keys: &[String]) -> Result<(), ApiError> {
+        let mut entries = self.entries.lock().expect("Could not acquire lock");
+        for key in keys {
+            entries.pop(key);
+            self.counters.delete(&Slot::<u64>::new(key.as_str()));
+        }
+        Ok(())
+    }
+
+    async fn delete_if_equal(&self, key: &str, value: &str) -> Result<bool, ApiError> {
+        let mut entries = self.entries.lock().expect("Could not acquire lock");
+        if Self::get_entry(&mut entries, key).as_deref() != Some(value) {
+            return Ok(false);
+        }
+        entries.pop(&key.to_string());
+        Ok(true)
+    }
+
+    async fn add_member(&self, key: &str, member: &str, ttl: Duration) -> Result<(), ApiError> {
+        let mut entries = self.entries.lock().expect("Could not acquire lock");
+        let mut members = Self::get_members(&mut entries, key)?;
+        members.insert(member.to_string());
+        entries.put(
+            key.to_string(),
+            Entry::new(Self::encode(&members)?, Some(ttl)),
+        );
+        Ok(())
+    }
+
+    async fn remove_members(&self, key: &str, members: &[String]) -> Result<usize, ApiError> {
+        let mut entries = self.entries.lock().expect("Could not acquire lock");
+        let mut set = Self::get_members(&mut entries, key)?;
+        let removed = members.iter().filter(|member| set.remove(*member)).count();
+        if set.is_empty() {
+            entries.pop(&key.to_string());
+        } else if let Some(entry) = entries.get_mut(&key.to_string()) {
+            entry.value = Self::encode(&set)?;
+        }
+        Ok(removed)
+    }
+
+    async fn members(&self, key: &str) -> Result<Vec<String>, ApiError> {
+        let mut entries = self.entries.lock().expect("Could not acquire lock");
+        Ok(Self::get_members(&mut entries, key)?.into_iter().collect())
+    }
+
+    async fn increment(&self, key: &str, ttl: Duration) -> Result<u64, ApiError> {
+        if self.counters.len() > self.capacity {
+            self.counters.evict_expired();
+        }
+        let slot = Slot::<u64>::new(key);
+        Ok(self
+            .counters
+            .update(&slot, Some(ttl), |count| count.unwrap_or(0) + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn it_sets_gets_and_deletes_an_entry() {
+        let cache = MemoryCache::new(10);
+        cache.set("key", "123".into(), None).await.unwrap();
+        assert_eq!(cache.get("key").await.unwrap(), Some("123".into()));
+        cache.delete_many(&["key".into()]).await.unwrap();
+        assert_eq!(cache.get("key").await.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn it_expires_an_entry() {
+        let cache = MemoryCache::new(10);
+        let ttl = Some(Duration::from_millis(10));
+        cache.set("key", "123".into(), ttl).await.unwrap();
+        actix_rt::time::delay_for(Duration::from_millis(20)).await;
+        assert_eq!(cache.get("key").await.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn it_evicts_the_least_recently_used_entry() {
+        let cache = MemoryCache::new(2);
+        cache.set("a", "1".into(), None).await.unwrap();
+        cache.set("b", "2".into(), None).await.unwrap();
+        cache.get("a").await.unwrap();
+        cache.set("c", "3".into(), None).await.unwrap();
+        let keys = vec!["a".into(), "b".into(), "c".into()];
+        let values = cache.get_many(&keys).await.unwrap();
+        assert_eq!(values, vec![Some("1".into()), None, Some("3".into())]);
+    }
+
+    #[actix_rt::test]
+    async fn it_increments_a_counter_until_it_expires() {
+        let cache = MemoryCache::new(10);
+        let ttl = Duration::from_millis(10);
+        assert_eq!(cache.increment("counter", ttl).await.unwrap(), 1);
+        assert_eq!(cache.increment("counter", ttl).await.unwrap(), 2);
+        actix_rt::time::delay_for(Duration::from_millis(20)).await;
+        assert_eq!(cache.increment("counter", ttl).await.unwrap(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_evict_a_counter_to_cache_a_value() {
+        let cache = MemoryCache::new(1);
+        let ttl = Duration::from_secs(60);
+        cache.increment("counter", ttl).await.unwrap();
+        cache.set("a", "1".into(), None).await.unwrap();
+        cache.set("b", "2".into(), None).await.unwrap();
+        assert_eq!(cache.get("counter").await.unwrap(), Some("1".into()));
+        assert_eq!(cache.increment("counter", ttl).await.unwrap(), 2);
+        cache.delete_many(&["counter".into()]).await.unwrap();
+        assert_eq!(cache.get("counter").await.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn it_only_sets_an_absent_entry() {
+        let cache = MemoryCache::new(10);
+        let ttl = Duration::from_secs(60);
+        assert!(cache.set_if_absent("lock", "1".into(), ttl).await.unwrap());
+        assert!(!cache.set_if_absent("lock", "1".into(), ttl).await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn it_only_sets_a_present_entry() {
+        let cache = MemoryCache::new(10);
+        let ttl = Duration::from_secs(60);
+        assert!(!cache.set_if_present("key", "1".into(), ttl).await.unwrap());
+        assert_eq!(cache.get("key").await.unwrap(), None);
+        cache.set("key", "1".into(), None).await.unwrap();
+        assert!(cache.set_if_present("key", "2".into(), ttl).await.unwrap());
+        assert_eq!(cache.get("key").await.unwrap(), Some("2".into()));
+    }
+
+    #[actix_rt::test]
+    async fn it_adds_and_removes_members_of_a_set() {
+        let cache = MemoryCache::new(10);
+        let ttl = Duration::from_secs(60);
+        cache.add_member("set", "a", ttl).await.unwrap();
+        cache.add_member("set", "b", ttl).await.unwrap();
+        cache.add_member("set", "a", ttl).await.unwrap();
+        assert_eq!(cache.members("set").await.unwrap(), vec!["a", "b"]);
+        let members = vec!["a".into(), "c".into()];
+        assert_eq!(cache.remove_members("set", &members).await.unwrap(), 1);
+        assert_eq!(cache.members("set").await.unwrap(), vec!["b"]);
+        cache.remove_members("set", &["b".into()]).await.unwrap();
+        assert_eq!(cache.get("set").await.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn it_expires_a_set() {
+        let cache = MemoryCache::new(10);
+        cache
+            .add_member("set", "a", Duration::from_millis(10))
+            .await
+            .unwrap();
+        actix_rt::time::delay_for(Duration::from_millis(20)).await;
+        assert!(cache.members("set").await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn it_only_deletes_an_entry_holding_the_value() {
+        let cache = MemoryCache::new(10);
+        cache.set("lock", "a".into(), None).await.unwrap();
+        assert!(!cache.delete_if_equal("lock", "b").await.unwrap());
+        assert_eq!(cache.get("lock").await.unwrap(), Some("a".into()));
+        assert!(cache.delete_if_equal("lock", "a").await.unwrap());
+        assert_eq!(cache.get("lock").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,540 @@
+//! Typed cache on top of a pluggable backend
+//!
+//! Values are stored as JSON, so any serde type can be cached. A missing
+//! entry is `Ok(None)`, while backend or (de)serialization failures are errors.
+//!
+//! The backend is Redis when `REDIS_URL` is set, and an in-process LRU cache
+//! otherwise, so handlers can always extract a `Cache`.
+//!
+//! Build keys with `key`, which namespaces them under `CACHE_PREFIX` and
+//! `CACHE_VERSION`. Bumping `CACHE_VERSION` invalidates every entry at once.
+//!
+//! `get_or_set` counts hits and misses, see `stats`.
+
+use crate::cache::memory::MemoryCache;
+use crate::cache::redis::RedisCache;
+use crate::config::CONFIG;
+use crate::errors::ApiError;
+use actix_rt::time::delay_for;
+use actix_web::web::Data;
+use async_trait::async_trait;
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use uuid::Uuid;
+
+pub mod memory;
+pub mod redis;
+
+/// Extract the cache in a handler
+///
+/// Actix data needs a sized type, so the trait object is boxed.
+pub type Cache = Data<Box<dyn CacheBackend>>;
+
+/// Storage of raw cache entries
+#[async_trait(?Send)]
+pub trait CacheBackend: Send + Sync {
+    /// Get an entry, or None on a miss
+    async fn get(&self, key: &str) -> Result<Option<String>, ApiError>;
+
+    /// Get several entries in the order of the keys
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>, ApiError>;
+
+    /// Insert or update an entry, expiring after the ttl if any
+    async fn set(&self, key: &str, value: String, ttl: Option<Duration>) -> Result<(), ApiError>;
+
+    /// Insert or update several entries, expiring after the ttl if any
+    async fn set_many(
+        &self,
+        entries: Vec<(String, String)>,
+        ttl: Option<Duration>,
+    ) -> Result<(), ApiError> {
+        for (key, value) in entries {
+            self.set(&key, value, ttl).await?;
+        }
+        Ok(())
+    }
+
+    /// Insert an entry only if it doesn't exist, returning whether it was inserted
+    async fn set_if_absent(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Duration,
+    ) -> Result<bool, ApiError>;
+
+    /// Update an entry only if it exists, returning whether it was updated
+    async fn set_if_present(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Duration,
+    ) -> Result<bool, ApiError>;
+
+    /// Delete several entries
+    async fn delete_many(&self, keys: &[String]) -> Result<(), ApiError>;
+
+    /// Atomically delete an entry only if it holds the value, returning whether it was deleted
+    async fn delete_if_equal(&self, key: &str, value: &str) -> Result<bool, ApiError>;
+
+    /// Atomically add a member to a set, restarting the set's ttl
+    async fn add_member(&self, key: &str, member: &str, ttl: Duration) -> Result<(), ApiError>;
+
+    /// Atomically remove members from a set, returning how many were in it
+    async fn remove_members(&self, key: &str, members: &[String]) -> Result<usize, ApiError>;
+
+    /// The members of a set, empty if it doesn't exist
+    async fn members(&self, key: &str) -> Result<Vec<String>, ApiError>;
+
+    /// Atomically increment a counter, starting its ttl when it's created
+    async fn increment(&self, key: &str, ttl: Duration) -> Result<u64, ApiError>;
+
+    /// Check that the backend is reachable
+    async fn ping(&self) -> Result<(), ApiError> {
+        Ok(())
+    }
+
+    /// Close the backend's connections at shutdown, if it has any
+    async fn close(&self) {}
+}
+
+/// How long a loader may hold the lock on a missing entry
+const LOCK_TTL: Duration = Duration::from_secs(5);
+
+/// What `invalidate` leaves in place of an entry, which reads as a miss
+const TOMBSTONE: &str = "!invalidated";
+
+/// How long an invalidated entry can't be filled again, as long as a loader
+/// may take
+const TOMBSTONE_TTL: Duration = LOCK_TTL;
+
+/// How often and how long to wait for another loader to fill an entry
+const LOCK_RETRIES: u32 = 10;
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+static HITS: AtomicUsize = AtomicUsize::new(0);
+static MISSES: AtomicUsize = AtomicUsize::new(0);
+
+/// Hits and misses of `get_or_set` since the server started
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+/// Get the hit and miss counters
+pub fn stats() -> CacheStats {
+    CacheStats {
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+    }
+}
+
+/// The configured time to live of cached entries
+pub fn ttl() -> Duration {
+    Duration::from_secs(CONFIG.cache_ttl)
+}
+
+/// Build a namespaced and versioned cache key
+///
+/// e.g. `key("users", user_id)` returns `api:v1:users:<user_id>`.
+pub fn key<T: Display>(namespace: &str, key: T) -> String {
+    format!(
+        "{}:v{}:{}:{}",
+        CONFIG.cache_prefix, CONFIG.cache_version, namespace, key
+    )
+}
+
+/// Retrieve an entry in the cache
+pub async fn get<T: DeserializeOwned>(cache: Cache, key: &str) -> Result<Option<T>, ApiError> {
+    decode(live(cache.get(key).await?))
+}
+
+/// Retrieve several entries in the cache, in the order of the keys
+pub async fn mget<K, T>(cache: Cache, keys: &[K]) -> Result<Vec<Option<T>>, ApiError>
+where
+    K: AsRef<str>,
+    T: DeserializeOwned,
+{
+    if keys.is_empty() {
+        return Ok(vec![]);
+    }
+    let keys = to_strings(keys);
+    let values = cache.get_many(&keys).await?;
+    values.into_iter().map(live).map(decode).collect()
+}
+
+/// Insert or update an entry in the cache that expires after the ttl
+pub async fn set_ex<'a, T: Serialize>(
+    cache: Cache,
+    key: &'a str,
+    value: &'a T,
+    ttl: Duration,
+) -> Result<(), ApiError> {
+    cache.set(key, encode(value)?, Some(ttl)).await
+}
+
+/// Update an entry in the cache only if it exists, restarting its expiry
+///
+/// Returns whether it was updated, so that a deleted entry isn't restored.
+pub async fn set_ex_if_present<T: Serialize>(
+    cache: Cache,
+    key: &str,
+    value: &T,
+    ttl: Duration,
+) -> Result<bool, ApiError> {
+    cache.set_if_present(key, encode(value)?, ttl).await
+}
+
+/// Delete an entry in the cache
+pub async fn delete(cache: Cache, key: &str) -> Result<(), ApiError> {
+    cache.delete_many(&[key.to_string()]).await
+}
+
+/// Delete several entries in the cache
+pub async fn delete_many<K: AsRef<str>>(cache: Cache, keys: &[K]) -> Result<(), ApiError> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    cache.delete_many(&to_strings(keys)).await
+}
+
+/// Retrieve an entry in the cache or load and cache it on a miss
+///
+/// Only one caller loads a missing entry, others wait for it to be filled
+/// (up to `LOCK_RETRIES` times) so that an expired entry doesn't send every
+/// request to the loader at once. Cache failures are logged and fall back to
+/// the loader, so only the loader's errors are returned.
+///
+/// The loaded value is only cached if the entry is still missing, so a value
+/// loaded before a write's `invalidate` isn't cached after it.
+pub async fn get_or_set<T, F, Fut>(
+    cache: Cache,
+    key: &str,
+    ttl: Duration,
+    loader: F,
+) -> Result<T, ApiError>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, ApiError>>,
+{
+    match cache.get(key).await {
+        Ok(Some(value)) if value == TOMBSTONE => {
+            // Invalidated moments ago, so nothing loaded now can be cached yet
+            MISSES.fetch_add(1, Ordering::Relaxed);
+            return loader().await;
+        }
+        result => match result.and_then(decode) {
+            Ok(Some(value)) => {
+                HITS.fetch_add(1, Ordering::Relaxed);
+                return Ok(value);
+            }
+            Ok(None) => {
+                MISSES.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(error) => {
+                MISSES.fetch_add(1, Ordering::Relaxed);
+                warn!("Cache unavailable for {}: {:?}", key, error);
+                return loader().await;
+            }
+        },
+    }
+
+    let lock = format!("{}:lock", key);
+    let token = Uuid::new_v4().to_simple().to_string();
+    if try_lock(cache.clone(), &lock, &token).await {
+        let value = loader().await;
+        if let Ok(value) = &value {
+            if let Err(error) = fill(cache.clone(), key, value, ttl).await {
+                warn!("Could not cache {}: {:?}", key, error);
+            }
+        }
+        unlock(cache, &lock, &token).await;
+        return value;
+    }
+
+    for _ in 0..LOCK_RETRIES {
+        delay_for(LOCK_RETRY_DELAY).await;
+        if let Ok(Some(value)) = get(cache.clone(), key).await {
+            return Ok(value);
+        }
+    }
+    loader().await
+}
+
+/// Cache a loaded value, unless the entry was invalidated while it loaded
+async fn fill<T: Serialize>(
+    cache: Cache,
+    key: &str,
+    value: &T,
+    ttl: Duration,
+) -> Result<(), ApiError> {
+    cache
+        .set_if_absent(key, encode(value)?, ttl)
+        .await
+        .map(|_| ())
+}
+
+/// Lock a missing entry for loading, returning false if another caller holds the lock
+///
+/// The lock holds a token unique to the caller, so that `unlock` never
+/// releases a lock that expired and was taken by another caller meanwhile.
+async fn try_lock(cache: Cache, lock: &str, token: &str) -> bool {
+    // Without a working lock, load the entry rather than waiting for nothing
+    cache
+        .set_if_absent(lock, token.into(), LOCK_TTL)
+        .await
+        .unwrap_or(true)
+}
+
+/// Release a lock, if the caller holding the token still owns it
+async fn unlock(cache: Cache, lock: &str, token: &str) {
+    if let Err(error) = cache.delete_if_equal(lock, token).await {
+        warn!("Could not release {}: {:?}", lock, error);
+    }
+}
+
+/// Read-through caching for handlers
+pub async fn cached<T, F, Fut>(cache: Cache, key: &str, loader: F) -> Result<T, ApiError>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, ApiError>>,
+{
+    get_or_set(cache, key, ttl(), loader).await
+}
+
+/// Remove stale entries after a write, logging rather than failing on errors
+///
+/// Entries are replaced by a tombstone for `TOMBSTONE_TTL` rather than
+/// deleted, so that a loader that read the database before the write can't
+/// cache its stale value after it.
+pub async fn invalidate<K: AsRef<str>>(cache: Cache, keys: &[K]) {
+    let tombstones = keys
+        .iter()
+        .map(|key| (key.as_ref().to_string(), TOMBSTONE.to_string()))
+        .collect();
+    if let Err(error) = cache.set_many(tombstones, Some(TOMBSTONE_TTL)).await {
+        warn!("Could not invalidate cache entries: {:?}", error);
+    }
+}
+
+/// An entry, or None if it's a miss or a tombstone
+fn live(value: Option<String>) -> Option<String> {
+    value.filter(|value| value != TOMBSTONE)
+}
+
+fn to_strings<K: AsRef<str>>(keys: &[K]) -> Vec<String> {
+    keys.iter().map(|key| key.as_ref().to_string()).collect()
+}
+
+/// Serialize a value to JSON
+fn encode<T: Serialize>(value: &T) -> Result<String, ApiError> {
+    serde_json::to_string(value).map_err(|error| ApiError::CacheError(error.to_string()))
+}
+
+/// Deserialize an entry from JSON
+fn decode<T: DeserializeOwned>(value: Option<String>) -> Result<Option<T>, ApiError> {
+    value
+        .map(|value| serde_json::from_str(&value))
+        .transpose()
+        .map_err(|error| ApiError::CacheError(error.to_string()))
+}
+
+/// Create the cache backend chosen by the config
+///
+/// Create it once and share it between workers, so that every worker sees
+/// the same in-process entries.
+pub fn new_cache() -> Cache {
+    let backend: Box<dyn CacheBackend> = if CONFIG.redis_url.is_empty() {
+        Box::new(MemoryCache::new(CONFIG.cache_capacity))
+    } else {
+        // Start a new supervisor with redis actor
+        Box::new(RedisCache::start(&CONFIG.redis_url))
+    };
+    Data::new(backend)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::get_cache;
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Entry {
+        value: String,
+    }
+
+    fn entry(value: &str) -> Entry {
+        Entry {
+            value: value.into(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn it_creates_new_application_cache_and_sets_and_reads_it() {
+        let cache = get_cache();
+        set_ex(cache.clone(), "testing", &entry("123"), ttl())
+            .await
+            .unwrap();
+        let value = get::<Entry>(cache, "testing").await.unwrap();
+        assert_eq!(value, Some(entry("123")));
+    }
+
+    #[actix_rt::test]
+    async fn it_removes_an_entry_in_application_cache() {
+        let cache = get_cache();
+        set_ex(cache.clone(), "testing", &entry("123"), ttl())
+            .await
+            .unwrap();
+        let value = get::<Entry>(cache.clone(), "testing").await.unwrap();
+        assert_eq!(value, Some(entry("123")));
+        delete(cache.clone(), "testing").await.unwrap();
+        let value = get::<Entry>(cache, "testing").await.unwrap();
+        assert_eq!(value, None);
+    }
+
+    #[actix_rt::test]
+    async fn it_gets_and_sets_many_entries() {
+        let cache = get_cache();
+        let entries = vec![
+            ("testing-1".into(), encode(&entry("1")).unwrap()),
+            ("testing-2".into(), encode(&entry("2")).unwrap()),
+        ];
+        cache.set_many(entries, Some(ttl())).await.unwrap();
+        let keys = ["testing-1", "testing-missing", "testing-2"];
+        let values = mget::<_, Entry>(cache.clone(), &keys).await.unwrap();
+        assert_eq!(values, vec![Some(entry("1")), None, Some(entry("2"))]);
+        delete_many(cache, &keys).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn it_only_loads_an_entry_on_a_miss() {
+        let cache = get_cache();
+        let ttl = Duration::from_secs(60);
+        delete(cache.clone(), "testing-loader").await.unwrap();
+        let loaded = get_or_set(cache.clone(), "testing-loader", ttl, || async {
+            Ok(entry("loaded"))
+        })
+        .await
+        .unwrap();
+        assert_eq!(loaded, entry("loaded"));
+        let cached = get_or_set(cache, "testing-loader", ttl, || async {
+            Ok(entry("loaded again"))
+        })
+        .await
+        .unwrap();
+        assert_eq!(cached, entry("loaded"));
+    }
+
+    #[actix_rt::test]
+    async fn it_waits_for_a_locked_entry_instead_of_loading_it() {
+        let cache = get_cache();
+        let ttl = Duration::from_secs(60);
+        delete(cache.clone(), "testing-locked").await.unwrap();
+        assert!(try_lock(cache.clone(), "testing-locked:lock", "a").await);
+        assert!(!try_lock(cache.clone(), "testing-locked:lock", "b").await);
+
+        // Another caller fills the entry while this one waits on the lock
+        let filler = cache.clone();
+        actix_rt::spawn(async move {
+            delay_for(LOCK_RETRY_DELAY).await;
+            set_ex(filler, "testing-locked", &entry("filled"), ttl)
+                .await
+                .unwrap();
+        });
+        let value = get_or_set(cache.clone(), "testing-locked", ttl, || async {
+            Ok(entry("loaded"))
+        })
+        .await
+        .unwrap();
+        assert_eq!(value, entry("filled"));
+        delete_many(cache, &["testing-locked", "testing-locked:lock"])
+            .await
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_cache_a_value_loaded_before_an_invalidation() {
+        let cache = get_cache();
+        let ttl = Duration::from_secs(60);
+        delete(cache.clone(), "testing-stale").await.unwrap();
+
+        // A write invalidates the entry after the loader read the old value
+        let writer = cache.clone();
+        let stale = get_or_set(cache.clone(), "testing-stale", ttl, || async move {
+            invalidate(writer, &["testing-stale"]).await;
+            Ok(entry("stale"))
+        })
+        .await
+        .unwrap();
+        assert_eq!(stale, entry("stale"));
+        let value = get::<Entry>(cache.clone(), "testing-stale").await.unwrap();
+        assert_eq!(value, None);
+
+        let fresh = get_or_set(cache.clone(), "testing-stale", ttl, || async {
+            Ok(entry("fresh"))
+        })
+        .await
+        .unwrap();
+        assert_eq!(fresh, entry("fresh"));
+        delete_many(cache, &["testing-stale", "testing-stale:lock"])
+            .await
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn it_only_releases_a_lock_it_owns() {
+        let cache = get_cache();
+        assert!(try_lock(cache.clone(), "testing-owned:lock", "a").await);
+        unlock(cache.clone(), "testing-owned:lock", "b").await;
+        assert!(!try_lock(cache.clone(), "testing-owned:lock", "b").await);
+        unlock(cache.clone(), "testing-owned:lock", "a").await;
+        assert!(try_lock(cache.clone(), "testing-owned:lock", "b").await);
+        delete(cache, "testing-owned:lock").await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn it_counts_hits_and_misses() {
+        let cache = get_cache();
+        let ttl = Duration::from_secs(60);
+        delete(cache.clone(), "testing-stats").await.unwrap();
+        let before = stats();
+        for _ in 0..2 {
+            get_or_set(cache.clone(), "testing-stats", ttl, || async {
+                Ok(entry("stats"))
+            })
+            .await
+            .unwrap();
+        }
+        let after = stats();
+        assert!(after.hits > before.hits);
+        assert!(after.misses > before.misses);
+    }
+
+    #[test]
+    fn it_builds_a_namespaced_and_versioned_key() {
+        let expected = format!(
+            "{}:v{}:users:123",
+            CONFIG.cache_prefix, CONFIG.cache_version
+        );
+        assert_eq!(key("users", 123), expected);
+    }
+
+    #[test]
+    fn it_decodes_a_miss_and_a_hit() {
+        let miss = decode::<Entry>(None).unwrap();
+        assert_eq!(miss, None);
+        let hit = decode::<Entry>(Some(r#"{"value":"123"}"#.into())).unwrap();
+        assert_eq!(hit, Some(entry("123")));
+    }
+
+    #[test]
+    fn it_errors_on_an_undecodable_entry() {
+        assert!(decode::<Entry>(Some("not json".into())).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+//! Cache backend that talks to Redis through the actix Redis actor
+
+use crate::cache::CacheBackend;
+use crate::errors::ApiError;
+use crate::metrics::REDIS_COMMAND_DURATION;
+use crate::telemetry::{Span, SpanKind};
+use actix::prelude::*;
+use actix_redis::{Command, RedisActor};
+use async_trait::async_trait;
+use redis_async::resp::RespValue;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Delete KEYS[1] only if it holds ARGV[1], in one step
+const DELETE_IF_EQUAL: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// Add ARGV[1] to the set KEYS[1] and restart its expiry to ARGV[2] seconds
+const ADD_MEMBER: &str = r#"
+redis.call("SADD", KEYS[1], ARGV[1])
+return redis.call("EXPIRE", KEYS[1], ARGV[2])
+"#;
+
+/// Increment KEYS[1], starting its expiry of ARGV[1] seconds when it's created
+const INCREMENT: &str = r#"
+local count = redis.call("INCR", KEYS[1])
+if count == 1 then
+    redis.call("EXPIRE", KEYS[1], ARGV[1])
+end
+return count
+"#;
+
+/// The actor's address is dropped on `close`, which stops the actor
+pub struct RedisCache {
+    addr: Mutex<Option<Addr<RedisActor>>>,
+}
+
+impl RedisCache {
+    /// Start a supervised Redis actor for the address
+    pub fn start(redis_url: &str) -> Self {
+        Self {
+            addr: Mutex::new(Some(RedisActor::start(redis_url))),
+        }
+    }
+
+    /// Send a command to the redis actor
+    async fn send(&self, command: RespValue) -> Result<RespValue, ApiError> {
+        let addr = self.addr.lock().expect("Could not acquire lock").clone();
+        let addr = addr.ok_or_else(|| ApiError::CacheError("Redis is closed".into()))?;
+        let error_message = format!("Could not send {:?} command to Redis", command);
+        let error = ApiError::CacheError(error_message);
+        let name = command_name(&command);
+        let mut span = Span::start(format!("redis {}", name), SpanKind::Client);
+        span.set_attribute("db.system", "redis");
+        let start = Instant::now();
+        let response = addr.send(Command(command)).await;
+        REDIS_COMMAND_DURATION.observe(&[&name], start.elapsed().as_secs_f64());
+        let result = match response.map_err(|_| error)? {
+            Ok(RespValue::Error(message)) => Err(ApiError::CacheError(message)),
+            Ok(value) => Ok(value),
+            Err(message) => Err(ApiError::CacheError(format!("{:?}", message))),
+        };
+        if let Err(error) = &result {
+            span.set_error(error);
+        }
+        result
+    }
+}
+
+#[async_trait(?Send)]
+impl CacheBackend for RedisCache {
+    async fn get(&self, key: &str) -> Result<Option<String>, ApiError> {
+        let command = resp_array!["GET", key];
+        to_string(self.send(command).await?)
+    }
+
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>, ApiError> {
+        let command = command("MGET", keys.iter().map(|key| key.into()));
+        match self.send(command).await? {
+            RespValue::Array(values) => values.into_iter().map(to_string).collect(),
+            value => Err(unexpected(value)),
+        }
+    }
+
+    async fn set(&self, key: &str, value: String, ttl: Option<Duration>) -> Result<(), ApiError> {
+        let command = match ttl {
+            Some(ttl) => resp_array!["SET", key, value, "EX", seconds(ttl)],
+            None => resp_array!["SET", key, value],
+        };
+        self.send(command).await.map(|_| ())
+    }
+
+    async fn set_many(
+        &self,
+        entries: Vec<(String, String)>,
+        ttl: Option<Duration>,
+    ) -> Result<(), ApiError> {
+        // MSET can't set an expiry, so send a SET EX per entry instead
+        if let Some(ttl) = ttl {
+            for (key, value) in entries {
+                self.set(&key, value, Some(ttl)).await?;
+            }
+            return Ok(());
+        }
+        let arguments = entries
+            .into_iter()
+            .flat_map(|(key, value)| vec![key.into(), value.into()]);
+        self.send(command("MSET", arguments)).await.map(|_| ())
+    }
+
+    async fn set_if_absent(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Duration,
+    ) -> Result<bool, ApiError> {
+        let command = resp_array!["SET", key, value, "NX", "EX", seconds(ttl)];
+        match self.send(command).await? {
+            RespValue::Nil => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    async fn set_if_present(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Duration,
+    ) -> Result<bool, ApiError> {
+        let command = resp_array!["SET", key, value, "XX", "EX", seconds(ttl)];
+        match self.send(command).await? {
+            RespValue::Nil => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    async fn delete_many(&self, keys: &[String]) -> Result<(), ApiError> {
+        let command = command("DEL", keys.iter().map(|key| key.into()));
+        self.send(command).await.map(|_| ())
+    }
+
+    async fn delete_if_equal(&self, key: &str, value: &str) -> Result<bool, ApiError> {
+        let command = resp_array!["EVAL", DELETE_IF_EQUAL, "1", key, value];
+        match self.send(command).await? {
+            RespValue::Integer(deleted) => Ok(deleted == 1),
+            value => Err(unexpected(value)),
+        }
+    }
+
+    async fn add_member(&self, key: &str, member: &str, ttl: Duration) -> Result<(), ApiError> {
+        let command = resp_array!["EVAL", ADD_MEMBER, "1", key, member, seconds(ttl)];
+        self.send(command).await.map(|_| ())
+    }
+
+    async fn remove_members(&self, key: &str, members: &[String]) -> Result<usize, ApiError> {
+        if members.is_empty() {
+            return Ok(0);
+        }
+        let arguments = std::iter::once(key.into()).chain(members.iter().map(|m| m.into()));
+        match self.send(command("SREM", arguments)).await? {
+            RespValue::Integer(removed) => Ok(removed as usize),
+            value => Err(unexpected(value)),
+        }
+    }
+
+    async fn members(&self, key: &str) -> Result<Vec<String>, ApiError> {
+        match self.send(resp_array!["SMEMBERS", key]).await? {
+            RespValue::Array(members) => members
+                .into_iter()
+                .map(|member| to_string(member).map(Option::unwrap_or_default))
+                .collect(),
+            value => Err(unexpected(value)),
+        }
+    }
+
+    async fn increment(&self, key: &str, ttl: Duration) -> Result<u64, ApiError> {
+        // In one step, so that a counter is never left without an expiry
+        let command = resp_array!["EVAL", INCREMENT, "1", key, seconds(ttl)];
+        match self.send(command).await? {
+            RespValue::Integer(count) => Ok(count as u64),
+            value => Err(unexpected(value)),
+        }
+    }
+
+    async fn ping(&self) -> Result<(), ApiError> {
+        match self.send(resp_array!["PING"]).await? {
+            RespValue::SimpleString(ref pong) if pong == "PONG" => Ok(()),
+            value => Err(unexpected(value)),
+        }
+    }
+
+    /// Drop the actor's address, so that it stops once its commands are done
+    async fn close(&self) {
+        self.addr.lock().expect("Could not acquire lock").take();
+    }
+}
+
+/// Build a command with a variable number of arguments
+fn command<I: IntoIterator<Item = RespValue>>(name: &str, arguments: I) -> RespValue {
+    let mut command = vec![name.into()];
+    command.extend(arguments);
+    RespValue::Array(command)
+}
+
+/// The name of a command, e.g. `GET`, to label its latency
+fn command_name(command: &RespValue) -> String {
+    match command {
+        RespValue::Array(arguments) => match arguments.first() {
+            Some(RespValue::BulkString(name)) => String::from_utf8_lossy(name).to_uppercase(),
+            _ => "UNKNOWN".into(),
+        },
+        _ => "UNKNOWN".into(),
+    }
+}
+
+/// Convert a reply into a string, where Nil is a miss
+fn to_string(value: RespValue) -> Result<Option<String>, ApiError> {
+    match value {
+        RespValue::Nil => Ok(None),
+        RespValue::BulkString(bytes) => String::from_utf8(bytes)
+            .map(Some)
+            .map_err(|error| ApiError::CacheError(error.to_string())),
+        value => Err(unexpected(value)),
+    }
+}
+
+fn unexpected(value: RespValue) -> ApiError {
+    ApiError::CacheError(format!("Unexpected reply from Redis: {:?}", value))
+}
+
+/// Redis expiries are whole seconds, and zero is rejected
+fn seconds(ttl: Duration) -> String {
+    ttl.as_secs().max(1).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn it_fails_commands_once_closed() {
+        let cache = RedisCache::start("127.0.0.1:1");
+        cache.close().await;
+        assert_eq!(
+            cache.ping().await,
+            Err(ApiError::CacheError("Redis is closed".into()))
+        );
+    }
+
+    #[test]
+    fn it_converts_a_miss_and_a_hit() {
+        assert_eq!(to_string(RespValue::Nil).unwrap(), None);
+        let bulk = RespValue::BulkString(b"123".to_vec());
+        assert_eq!(to_string(bulk).unwrap(), Some("123".into()));
+    }
+
+    #[test]
+    fn it_errors_on_an_unexpected_reply() {
+        assert!(to_string(RespValue::Integer(1)).is_err());
+    }
+
+    #[test]
+    fn it_rounds_ttls_to_at_least_one_second() {
+        assert_eq!(seconds(Duration::from_millis(10)), "1");
+        assert_eq!(seconds(Duration::from_secs(90)), "90");
+    }
+
+    #[test]
+    fn it_builds_a_command_with_variable_arguments() {
+        let keys = ["a".to_string(), "b".to_string()];
+        let command = command("DEL", keys.iter().map(|key| key.into()));
+        assert_eq!(command, resp_array!["DEL", "a", "b"]);
+    }
+
+    #[test]
+    fn it_names_a_command() {
+        assert_eq!(command_name(&resp_array!["get", "key"]), "GET");
+        assert_eq!(command_name(&RespValue::Nil), "UNKNOWN");
+    }
+}
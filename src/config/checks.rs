@@ -0,0 +1,338 @@
+//! Check the values that parsed but can't work, e.g. a short JWT key
+
+use super::Config;
+use crate::database::DatabaseConnection;
+use crate::session::SessionStore;
+use actix_web::http::{uri::Authority, HeaderValue};
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use uuid::Uuid;
+
+/// Placeholder salt of .env.example that must not reach production
+const PLACEHOLDER_SALT: &str = "CHANGEME";
+
+/// Shortest salt argon2 accepts
+const MIN_SALT_LENGTH: usize = 8;
+
+/// Shortest JWT and cookie signing keys, in bytes
+const MIN_KEY_LENGTH: usize = 32;
+
+/// Values of `X-Frame-Options` that browsers still support
+const FRAME_OPTIONS: [&str; 2] = ["DENY", "SAMEORIGIN"];
+
+/// Tokens of `Referrer-Policy`, which may list several as fallbacks
+const REFERRER_POLICIES: [&str; 8] = [
+    "no-referrer",
+    "no-referrer-when-downgrade",
+    "origin",
+    "origin-when-cross-origin",
+    "same-origin",
+    "strict-origin",
+    "strict-origin-when-cross-origin",
+    "unsafe-url",
+];
+
+/// Every problem with the config
+pub fn check(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    required(&mut problems, "DATABASE_URL", &config.database_url);
+    if config.database != DatabaseConnection::compiled() {
+        problems.push("DATABASE must match the database feature the server was built with".into());
+    }
+    salt(&mut problems, &config.auth_salt);
+    key(&mut problems, "JWT_KEY", &config.jwt_key);
+    key(&mut problems, "SESSION_KEY", &config.session_key);
+
+    address(&mut problems, "SERVER", &config.server);
+    if !config.metrics_server.is_empty() {
+        address(&mut problems, "METRICS_SERVER", &config.metrics_server);
+    }
+    if !config.tls_redirect_server.is_empty() {
+        address(
+            &mut problems,
+            "TLS_REDIRECT_SERVER",
+            &config.tls_redirect_server,
+        );
+        if required(
+            &mut problems,
+            "TLS_REDIRECT_HOST",
+            &config.tls_redirect_host,
+        ) {
+            host(
+                &mut problems,
+                "TLS_REDIRECT_HOST",
+                &config.tls_redirect_host,
+            );
+        }
+    }
+
+    if config.tls_cert.is_empty() != config.tls_key.is_empty() {
+        problems.push("TLS_CERT and TLS_KEY must be set together".into());
+    }
+    if config.tls_cert.is_empty() && !config.tls_client_ca.is_empty() {
+        problems.push("TLS_CLIENT_CA needs TLS_CERT and TLS_KEY".into());
+    }
+    if config.tls_cert.is_empty() && !config.tls_redirect_server.is_empty() {
+        problems.push("TLS_REDIRECT_SERVER needs TLS_CERT and TLS_KEY".into());
+    }
+
+    header(&mut problems, "SECURITY_CSP", &config.security_csp);
+    header(
+        &mut problems,
+        "SECURITY_STATIC_CSP",
+        &config.security_static_csp,
+    );
+    frame_options(&mut problems, &config.security_frame_options);
+    referrer_policy(&mut problems, &config.security_referrer_policy);
+
+    // The in-process cache evicts entries, which would log their users out
+    if config.session_store == SessionStore::Redis && config.redis_url.is_empty() {
+        problems.push("SESSION_STORE=redis needs REDIS_URL".into());
+    }
+
+    if config.cors_allow_credentials && config.cors_allowed_origins.iter().any(|o| o == "*") {
+        problems.push("CORS_ALLOWED_ORIGINS can't be * with CORS_ALLOW_CREDENTIALS".into());
+    }
+
+    for path in config
+        .cors_public_paths
+        .iter()
+        .filter(|path| !path.is_empty())
+    {
+        if !path.starts_with('/') {
+            problems.push(format!("CORS_PUBLIC_PATHS must be paths, got `{}`", path));
+        }
+    }
+
+    for id in config.admin_users.iter().filter(|id| !id.is_empty()) {
+        if Uuid::parse_str(id).is_err() {
+            problems.push(format!("ADMIN_USERS must be user ids, got `{}`", id));
+        }
+    }
+
+    for proxy in config.trusted_proxies.iter().filter(|ip| !ip.is_empty()) {
+        if proxy.parse::<IpAddr>().is_err() {
+            problems.push(format!(
+                "TRUSTED_PROXIES must be IP addresses, got `{}`",
+                proxy
+            ));
+        }
+    }
+
+    if !(0.0..=1.0).contains(&config.otel_traces_sampler_arg) {
+        problems.push("OTEL_TRACES_SAMPLER_ARG must be between 0 and 1".into());
+    }
+
+    positive(
+        &mut problems,
+        "CACHE_CAPACITY",
+        i64::try_from(config.cache_capacity).unwrap_or(i64::MAX),
+    );
+    positive(&mut problems, "JWT_EXPIRATION", config.jwt_expiration);
+    positive(
+        &mut problems,
+        "RATE_LIMIT_API",
+        config.rate_limit_api as i64,
+    );
+    positive(
+        &mut problems,
+        "RATE_LIMIT_AUTH",
+        config.rate_limit_auth as i64,
+    );
+    positive(
+        &mut problems,
+        "RATE_LIMIT_PERIOD",
+        config.rate_limit_period as i64,
+    );
+    positive(&mut problems, "SESSION_TIMEOUT", config.session_timeout);
+    problems
+}
+
+fn required(problems: &mut Vec<String>, name: &str, value: &str) -> bool {
+    if value.is_empty() {
+        problems.push(format!("{} is required", name));
+        return false;
+    }
+    true
+}
+
+fn salt(problems: &mut Vec<String>, salt: &str) {
+    if !required(problems, "AUTH_SALT", salt) {
+        return;
+    }
+    if salt == PLACEHOLDER_SALT {
+        problems.push(format!(
+            "AUTH_SALT must be changed from {}",
+            PLACEHOLDER_SALT
+        ));
+    } else if salt.len() < MIN_SALT_LENGTH {
+        problems.push(format!(
+            "AUTH_SALT must be at least {} characters",
+            MIN_SALT_LENGTH
+        ));
+    }
+}
+
+fn key(problems: &mut Vec<String>, name: &str, key: &str) {
+    if required(problems, name, key) && key.len() < MIN_KEY_LENGTH {
+        problems.push(format!(
+            "{} must be at least {} characters",
+            name, MIN_KEY_LENGTH
+        ));
+    }
+}
+
+/// A `host:port` to bind, e.g. `127.0.0.1:3000` or `localhost:3000`
+fn address(problems: &mut Vec<String>, name: &str, address: &str) {
+    if address.parse::<SocketAddr>().is_ok() {
+        return;
+    }
+    let mut parts = address.rsplitn(2, ':');
+    let port = parts.next().and_then(|port| port.parse::<u16>().ok());
+    let host = parts.next().unwrap_or_default();
+    let valid_host = !host.is_empty() && !host.contains(char::is_whitespace);
+    if port.is_none() || !valid_host {
+        problems.push(format!(
+            "{} must be a host:port address, got `{}`",
+            name, address
+        ));
+    }
+}
+
+/// A public `host` or `host:port`, e.g. `example.com` or `localhost:3000`
+fn host(problems: &mut Vec<String>, name: &str, host: &str) {
+    let valid = host.parse::<Authority>().is_ok() && !host.contains('@');
+    if !valid {
+        problems.push(format!(
+            "{} must be a host or host:port, got `{}`",
+            name, host
+        ));
+    }
+}
+
+/// A header value, which an empty setting leaves out
+fn header(problems: &mut Vec<String>, name: &str, value: &str) {
+    if HeaderValue::from_str(value).is_err() {
+        problems.push(format!("{} must be a valid header value", name));
+    }
+}
+
+fn frame_options(problems: &mut Vec<String>, value: &str) {
+    let valid = value.is_empty()
+        || FRAME_OPTIONS
+            .iter()
+            .any(|option| option.eq_ignore_ascii_case(value));
+    if !valid {
+        problems.push(format!(
+            "SECURITY_FRAME_OPTIONS must be {}, got `{}`",
+            FRAME_OPTIONS.join(" or "),
+            value
+        ));
+    }
+}
+
+/// One policy or a comma separated list, e.g. `no-referrer, strict-origin`
+fn referrer_policy(problems: &mut Vec<String>, value: &str) {
+    let valid = value.is_empty()
+        || value.split(',').all(|policy| {
+            REFERRER_POLICIES
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(policy.trim()))
+        });
+    if !valid {
+        problems.push(format!(
+            "SECURITY_REFERRER_POLICY must be a referrer policy, got `{}`",
+            value
+        ));
+    }
+}
+
+fn positive(problems: &mut Vec<String>, name: &str, value: i64) {
+    if value <= 0 {
+        problems.push(format!("{} must be greater than 0", name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(check: impl Fn(&mut Vec<String>)) -> Vec<String> {
+        let mut problems = Vec::new();
+        check(&mut problems);
+        problems
+    }
+
+    #[test]
+    fn it_checks_addresses() {
+        assert!(problems(|p| address(p, "SERVER", "127.0.0.1:3000")).is_empty());
+        assert!(problems(|p| address(p, "SERVER", "[::1]:3000")).is_empty());
+        assert!(problems(|p| address(p, "SERVER", "localhost:3000")).is_empty());
+        assert_eq!(
+            problems(|p| address(p, "SERVER", "localhost")),
+            vec!["SERVER must be a host:port address, got `localhost`"]
+        );
+        assert_eq!(problems(|p| address(p, "SERVER", ":3000")).len(), 1);
+        assert_eq!(
+            problems(|p| address(p, "SERVER", "localhost:99999")).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn it_checks_hosts() {
+        assert!(problems(|p| host(p, "TLS_REDIRECT_HOST", "example.com")).is_empty());
+        assert!(problems(|p| host(p, "TLS_REDIRECT_HOST", "localhost:3000")).is_empty());
+        assert!(problems(|p| host(p, "TLS_REDIRECT_HOST", "[::1]:8443")).is_empty());
+        assert_eq!(
+            problems(|p| host(p, "TLS_REDIRECT_HOST", "https://example.com")),
+            vec!["TLS_REDIRECT_HOST must be a host or host:port, got `https://example.com`"]
+        );
+        assert_eq!(
+            problems(|p| host(p, "TLS_REDIRECT_HOST", "evil.com@example.com")).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn it_checks_security_headers() {
+        assert!(problems(|p| header(p, "SECURITY_CSP", "default-src 'none'")).is_empty());
+        assert_eq!(
+            problems(|p| header(p, "SECURITY_CSP", "default-src\n'none'")),
+            vec!["SECURITY_CSP must be a valid header value"]
+        );
+        assert!(problems(|p| frame_options(p, "sameorigin")).is_empty());
+        assert!(problems(|p| frame_options(p, "")).is_empty());
+        assert_eq!(
+            problems(|p| frame_options(p, "ALLOW-FROM https://example.com")),
+            vec![
+                "SECURITY_FRAME_OPTIONS must be DENY or SAMEORIGIN, got `ALLOW-FROM https://example.com`"
+            ]
+        );
+        assert!(problems(|p| referrer_policy(p, "no-referrer, strict-origin")).is_empty());
+        assert_eq!(problems(|p| referrer_policy(p, "never")).len(), 1);
+    }
+
+    #[test]
+    fn it_rejects_the_placeholder_salt() {
+        assert_eq!(
+            problems(|p| salt(p, "CHANGEME")),
+            vec!["AUTH_SALT must be changed from CHANGEME"]
+        );
+        assert_eq!(problems(|p| salt(p, "short")).len(), 1);
+        assert!(problems(|p| salt(p, "8f2b6a1c9d4e7f30")).is_empty());
+    }
+
+    #[test]
+    fn it_checks_key_lengths() {
+        assert_eq!(
+            problems(|p| key(p, "JWT_KEY", "")),
+            vec!["JWT_KEY is required"]
+        );
+        assert_eq!(
+            problems(|p| key(p, "JWT_KEY", "short")),
+            vec!["JWT_KEY must be at least 32 characters"]
+        );
+    }
+}
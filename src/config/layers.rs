@@ -0,0 +1,270 @@
+//! Gather settings from each layer as `NAME = value` strings
+//!
+//! Names are upper snake case, like environment variables, so each layer
+//! can override the others before envy injects them into the Config struct.
+
+use super::Config;
+use dotenv::dotenv;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Settings that can be read from a file named by `{NAME}_FILE` instead,
+/// e.g. a Docker or Kubernetes secret
+const SECRETS: &[&str] = &[
+    "AUTH_SALT",
+    "DATABASE_URL",
+    "JWT_KEY",
+    "REDIS_URL",
+    "SESSION_KEY",
+];
+
+/// Environment variables, augmented by a .env file
+pub fn env() -> HashMap<String, String> {
+    dotenv().ok();
+    std::env::vars().collect()
+}
+
+/// Command line arguments, which the test harness keeps for itself
+#[cfg(not(test))]
+pub fn args() -> Vec<String> {
+    std::env::args().skip(1).collect()
+}
+
+#[cfg(test)]
+pub fn args() -> Vec<String> {
+    Vec::new()
+}
+
+/// Parse `--name value`, `--name=value` and `--flag` arguments
+pub fn parse_args(args: &[String], problems: &mut Vec<String>) -> HashMap<String, String> {
+    let mut settings = HashMap::new();
+    let mut args = args.iter().peekable();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            problems.push(format!("Unexpected argument `{}`", arg));
+            continue;
+        }
+        let flag = &arg[2..];
+        let (name, value) = match flag.find('=') {
+            Some(index) => (&flag[..index], flag[index + 1..].to_string()),
+            None => match args.peek() {
+                Some(value) if !value.starts_with("--") => (flag, args.next().unwrap().clone()),
+                _ => (flag, "true".to_string()),
+            },
+        };
+        settings.insert(name.to_uppercase().replace('-', "_"), value);
+    }
+    settings
+}
+
+/// Settings from a TOML or YAML file
+///
+/// Tables are flattened, so `[session] key = "..."` sets `SESSION_KEY`, and
+/// arrays are joined with commas.
+pub fn file(path: &str, problems: &mut Vec<String>) -> HashMap<String, String> {
+    let mut settings = HashMap::new();
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) => {
+            problems.push(format!(
+                "Could not read the config file {}: {}",
+                path, error
+            ));
+            return settings;
+        }
+    };
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str());
+    let parsed = match extension {
+        Some("toml") => toml::from_str::<Value>(&contents).map_err(|error| error.to_string()),
+        Some("yaml") | Some("yml") => {
+            serde_yaml::from_str::<Value>(&contents).map_err(|error| error.to_string())
+        }
+        _ => Err("expected a .toml, .yaml or .yml file".into()),
+    };
+    match parsed {
+        Ok(value) => flatten("", &value, &mut settings),
+        Err(error) => problems.push(format!("Invalid config file {}: {}", path, error)),
+    }
+    settings
+}
+
+fn flatten(prefix: &str, value: &Value, settings: &mut HashMap<String, String>) {
+    let value = match value {
+        Value::Object(table) => {
+            for (key, value) in table {
+                let name = if prefix.is_empty() {
+                    key.to_uppercase()
+                } else {
+                    format!("{}_{}", prefix, key.to_uppercase())
+                };
+                flatten(&name, value, settings);
+            }
+            return;
+        }
+        Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(","),
+        Value::String(value) => value.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    };
+    settings.insert(prefix.to_string(), value);
+}
+
+/// Replace each secret with the contents of its `{NAME}_FILE`, if set
+pub fn read_secret_files(settings: &mut HashMap<String, String>, problems: &mut Vec<String>) {
+    for name in SECRETS {
+        let file = format!("{}_FILE", name);
+        let path = match settings.get(&file) {
+            Some(path) if !path.is_empty() => path.clone(),
+            _ => continue,
+        };
+        match fs::read_to_string(&path) {
+            Ok(secret) => {
+                let secret = secret.trim_end_matches(['\n', '\r']);
+                settings.insert(name.to_string(), secret.into());
+            }
+            Err(error) => problems.push(format!("{}: could not read {}: {}", file, path, error)),
+        }
+    }
+}
+
+/// Inject the settings into the Config struct, collecting every value that
+/// can't be parsed
+///
+/// envy stops at the first bad value, so it's dropped in favor of its default
+/// and the rest are parsed again.
+pub fn deserialize(
+    mut settings: HashMap<String, String>,
+    problems: &mut Vec<String>,
+) -> Option<Config> {
+    loop {
+        let error = match envy::from_iter::<_, Config>(settings.clone()) {
+            Ok(config) => return Some(config),
+            Err(error) => error.to_string(),
+        };
+        let names = culprits(&error, &settings);
+        if names.is_empty() {
+            problems.push(error);
+            return None;
+        }
+        let message = match error.find(" while parsing value") {
+            Some(index) => &error[..index],
+            None => &error,
+        };
+        problems.push(format!("{}: {}", names.join(", "), message));
+        for name in names {
+            settings.remove(&name);
+        }
+    }
+}
+
+/// The settings a deserialization error is about
+fn culprits(error: &str, settings: &HashMap<String, String>) -> Vec<String> {
+    // Numbers and booleans name their variable
+    if let Some(index) = error.rfind(" provided by ") {
+        return vec![error[index + " provided by ".len()..].to_string()];
+    }
+    // Enums only name the value, e.g. unknown variant `xml`
+    let value = match error.split('`').nth(1) {
+        Some(value) => value,
+        None => return Vec::new(),
+    };
+    let mut names = settings
+        .iter()
+        .filter(|(_, setting)| setting.as_str() == value)
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use uuid::Uuid;
+
+    fn args(args: &[&str]) -> HashMap<String, String> {
+        let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let mut problems = Vec::new();
+        let settings = parse_args(&args, &mut problems);
+        assert_eq!(problems, Vec::<String>::new());
+        settings
+    }
+
+    fn write(name: &str, contents: &str) -> String {
+        let path = temp_dir().join(format!("{}-{}", Uuid::new_v4(), name));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into()
+    }
+
+    #[test]
+    fn it_parses_flags() {
+        let settings = args(&[
+            "--server",
+            "0.0.0.0:3000",
+            "--log-format=json",
+            "--session-secure",
+        ]);
+        assert_eq!(settings["SERVER"], "0.0.0.0:3000");
+        assert_eq!(settings["LOG_FORMAT"], "json");
+        assert_eq!(settings["SESSION_SECURE"], "true");
+    }
+
+    #[test]
+    fn it_rejects_positional_arguments() {
+        let mut problems = Vec::new();
+        parse_args(&["server".to_string()], &mut problems);
+        assert_eq!(problems, vec!["Unexpected argument `server`".to_string()]);
+    }
+
+    #[test]
+    fn it_flattens_a_toml_file() {
+        let path = write(
+            "config.toml",
+            "server = \"0.0.0.0:3000\"\ncache_ttl = 30\n\n[session]\nsecure = true\n",
+        );
+        let settings = file(&path, &mut Vec::new());
+        assert_eq!(settings["SERVER"], "0.0.0.0:3000");
+        assert_eq!(settings["CACHE_TTL"], "30");
+        assert_eq!(settings["SESSION_SECURE"], "true");
+    }
+
+    #[test]
+    fn it_flattens_a_yaml_file() {
+        let path = write(
+            "config.yaml",
+            "server: 0.0.0.0:3000\nsession:\n  name: auth\n",
+        );
+        let settings = file(&path, &mut Vec::new());
+        assert_eq!(settings["SERVER"], "0.0.0.0:3000");
+        assert_eq!(settings["SESSION_NAME"], "auth");
+    }
+
+    #[test]
+    fn it_reads_secrets_from_files() {
+        let mut settings = HashMap::new();
+        settings.insert("JWT_KEY_FILE".to_string(), write("jwt_key", "secret\n"));
+        read_secret_files(&mut settings, &mut Vec::new());
+        assert_eq!(settings["JWT_KEY"], "secret");
+    }
+
+    #[test]
+    fn it_reports_a_missing_secret_file() {
+        let mut settings = HashMap::new();
+        settings.insert("JWT_KEY_FILE".to_string(), "/not/a/file".to_string());
+        let mut problems = Vec::new();
+        read_secret_files(&mut settings, &mut problems);
+        assert!(problems[0].starts_with("JWT_KEY_FILE: could not read /not/a/file"));
+    }
+}
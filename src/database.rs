@@ -1,13 +1,19 @@
 //! Database-related functions
 use crate::config::{Config, CONFIG};
-use actix_web::web;
+use crate::errors::ApiError;
+use crate::metrics::{register_pool, PoolEvents};
+use actix_web::web::{self, Data};
 use diesel::{
+    connection::SimpleConnection,
     mysql::MysqlConnection,
     pg::PgConnection,
     r2d2::{ConnectionManager, PoolError},
+    result::QueryResult,
     sqlite::SqliteConnection,
     Connection,
 };
+use diesel_migrations::{MigrationConnection, RunMigrationsError};
+use std::time::Duration;
 
 #[serde(untagged)]
 #[derive(Clone, Deserialize, Debug, PartialEq)]
@@ -19,47 +25,76 @@ pub enum DatabaseConnection {
     Sqlite,
 }
 
-pub type Pool<T> = r2d2::Pool<ConnectionManager<T>>;
-pub type CockroachPool = Pool<PgConnection>;
-pub type MysqlPool = Pool<MysqlConnection>;
-pub type PostgresPool = Pool<PgConnection>;
-pub type SqlitePool = Pool<SqliteConnection>;
+/// Migrations for each backend, embedded into the binary at compile time
+///
+/// Column types differ per backend, so every backend has its own folder.
+mod migrations {
+    use diesel_migrations::{MigrationConnection, RunMigrationsError};
+
+    pub mod mysql {
+        use super::*;
+        embed_migrations!("migrations/mysql");
+
+        /// Comma separated versions, listed by build.rs
+        pub const VERSIONS: &str = env!("MIGRATIONS_MYSQL");
+
+        pub fn run<T: MigrationConnection>(conn: &T) -> Result<(), RunMigrationsError> {
+            embedded_migrations::run(conn)
+        }
+    }
+
+    pub mod postgres {
+        use super::*;
+        embed_migrations!("migrations/postgres");
+
+        /// Comma separated versions, listed by build.rs
+        pub const VERSIONS: &str = env!("MIGRATIONS_POSTGRES");
+
+        pub fn run<T: MigrationConnection>(conn: &T) -> Result<(), RunMigrationsError> {
+            embedded_migrations::run(conn)
+        }
+    }
+
+    pub mod sqlite {
+        use super::*;
+        embed_migrations!("migrations/sqlite");
+
+        /// Comma separated versions, listed by build.rs
+        pub const VERSIONS: &str = env!("MIGRATIONS_SQLITE");
+
+        pub fn run<T: MigrationConnection>(conn: &T) -> Result<(), RunMigrationsError> {
+            embedded_migrations::run(conn)
+        }
+    }
+}
 
-#[cfg(feature = "cockraoch")]
-pub type PoolType = CockroachPool;
+pub type Pool<T> = r2d2::Pool<ConnectionManager<T>>;
+#[cfg(feature = "cockroach")]
+pub type ConnectionType = PgConnection;
 
 #[cfg(feature = "mysql")]
-pub type PoolType = MysqlPool;
+pub type ConnectionType = MysqlConnection;
 
 #[cfg(feature = "postgres")]
-pub type PoolType = PostgresPool;
+pub type ConnectionType = PgConnection;
 
 #[cfg(feature = "sqlite")]
-pub type PoolType = SqlitePool;
-
-#[derive(Clone)]
-pub enum InferPool {
-    Cockroach(CockroachPool),
-    Mysql(MysqlPool),
-    Postgres(PostgresPool),
-    Sqlite(SqlitePool),
-}
+pub type ConnectionType = SqliteConnection;
 
-impl InferPool {
-    pub fn init_pool(config: Config) -> Result<Self, r2d2::Error> {
-        match config.database {
-            DatabaseConnection::Cockroach => {
-                init_pool::<PgConnection>(config).map(InferPool::Cockroach)
-            }
-            DatabaseConnection::Mysql => init_pool::<MysqlConnection>(config).map(InferPool::Mysql),
-            DatabaseConnection::Postgres => {
-                init_pool::<PgConnection>(config).map(InferPool::Postgres)
-            }
-            DatabaseConnection::Sqlite => {
-                init_pool::<SqliteConnection>(config).map(InferPool::Sqlite)
-            }
+pub type PoolType = Pool<ConnectionType>;
+
+impl DatabaseConnection {
+    /// The backend of the enabled feature, which `DATABASE` must match
+    pub fn compiled() -> Self {
+        if cfg!(feature = "cockroach") {
+            DatabaseConnection::Cockroach
+        } else if cfg!(feature = "postgres") {
+            DatabaseConnection::Postgres
+        } else if cfg!(feature = "sqlite") {
+            DatabaseConnection::Sqlite
+        } else {
+            DatabaseConnection::Mysql
         }
-        .map_err(Into::into)
     }
 }
 
@@ -68,15 +103,111 @@ where
     T: Connection + 'static,
 {
     let manager = ConnectionManager::<T>::new(config.database_url);
-    Pool::builder().build(manager)
+    let pool = Pool::builder()
+        .event_handler(Box::new(PoolEvents))
+        .build(manager)?;
+    register_pool(&pool);
+    Ok(pool)
+}
+
+/// Connections that know which migrations folder belongs to their backend
+pub trait EmbeddedMigrations: MigrationConnection {
+    /// Comma separated versions of the embedded migrations
+    const MIGRATION_VERSIONS: &'static str;
+
+    fn run_embedded_migrations(&self) -> Result<(), RunMigrationsError>;
+}
+
+impl EmbeddedMigrations for MysqlConnection {
+    const MIGRATION_VERSIONS: &'static str = migrations::mysql::VERSIONS;
+
+    fn run_embedded_migrations(&self) -> Result<(), RunMigrationsError> {
+        migrations::mysql::run(self)
+    }
+}
+
+// CockroachDB shares the Postgres migrations
+impl EmbeddedMigrations for PgConnection {
+    const MIGRATION_VERSIONS: &'static str = migrations::postgres::VERSIONS;
+
+    fn run_embedded_migrations(&self) -> Result<(), RunMigrationsError> {
+        migrations::postgres::run(self)
+    }
+}
+
+impl EmbeddedMigrations for SqliteConnection {
+    const MIGRATION_VERSIONS: &'static str = migrations::sqlite::VERSIONS;
+
+    fn run_embedded_migrations(&self) -> Result<(), RunMigrationsError> {
+        migrations::sqlite::run(self)
+    }
+}
+
+/// Run any migrations of the connection's backend that haven't been run yet
+#[cfg(test)]
+pub fn run_migrations<T>(conn: &T) -> Result<(), RunMigrationsError>
+where
+    T: EmbeddedMigrations,
+{
+    conn.run_embedded_migrations()
+}
+
+/// Versions of the embedded migrations that haven't been run yet
+///
+/// Errors if the migrations table doesn't exist, as no migration has been run.
+pub fn pending_migrations<T>(conn: &T) -> QueryResult<Vec<String>>
+where
+    T: EmbeddedMigrations,
+{
+    let run = conn.previously_run_migration_versions()?;
+    Ok(T::MIGRATION_VERSIONS
+        .split(',')
+        .filter(|version| !version.is_empty() && !run.contains(*version))
+        .map(Into::into)
+        .collect())
+}
+
+/// Extract the database checks in a handler
+pub type Database = Data<Box<dyn DatabaseCheck>>;
+
+/// Checks of the database behind a pool, run by the readiness endpoint
+pub trait DatabaseCheck: Send + Sync {
+    /// Run `SELECT 1`, waiting at most `timeout` for a connection
+    fn ping(&self, timeout: Duration) -> Result<(), ApiError>;
+
+    /// Versions of the embedded migrations that haven't been run yet
+    fn pending_migrations(&self, timeout: Duration) -> Result<Vec<String>, ApiError>;
 }
 
-pub fn add_pool(cfg: &mut web::ServiceConfig) {
-    let pool = InferPool::init_pool(CONFIG.clone()).expect("Failed to create connection pool");
-    match pool {
-        InferPool::Cockroach(cockroach_pool) => cfg.data(cockroach_pool),
-        InferPool::Mysql(mysql_pool) => cfg.data(mysql_pool),
-        InferPool::Postgres(postgres_pool) => cfg.data(postgres_pool),
-        InferPool::Sqlite(sqlite_pool) => cfg.data(sqlite_pool),
-    };
+impl<T> DatabaseCheck for Pool<T>
+where
+    T: EmbeddedMigrations + Send + 'static,
+{
+    fn ping(&self, timeout: Duration) -> Result<(), ApiError> {
+        let conn = self.get_timeout(timeout)?;
+        conn.batch_execute("SELECT 1")?;
+        Ok(())
+    }
+
+    fn pending_migrations(&self, timeout: Duration) -> Result<Vec<String>, ApiError> {
+        let conn = self.get_timeout(timeout)?;
+        Ok(pending_migrations(&*conn)?)
+    }
+}
+
+/// Create the pool of the enabled backend, once for every worker to share
+pub fn new_pool() -> Result<PoolType, PoolError> {
+    init_pool(CONFIG.clone())
+}
+
+/// Add the shared pool to actix data
+///
+/// Invoke in handlers using pool: Data<PoolType>
+pub fn add_pool<T>(pool: Pool<T>) -> impl FnOnce(&mut web::ServiceConfig)
+where
+    T: Connection + 'static,
+{
+    move |cfg| {
+        cfg.data(pool);
+    }
 }
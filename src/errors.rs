@@ -1,93 +1,301 @@
+use crate::locale::{translate, DEFAULT_LOCALE};
+use crate::validate::{add_error, FieldErrors, UNIQUE};
 use actix_web::{
-    error::{BlockingError, ResponseError},
-    http::StatusCode,
+    error::{
+        BlockingError, JsonPayloadError, PathError, PayloadError, QueryPayloadError, ResponseError,
+        UrlencodedError,
+    },
+    http::{header::RETRY_AFTER, StatusCode},
     HttpResponse,
 };
 use derive_more::Display;
 use diesel::{
     r2d2::PoolError,
-    result::{DatabaseErrorKind, Error as DBError},
+    result::{DatabaseErrorInformation, DatabaseErrorKind, Error as DBError},
 };
+use log::{error, warn};
+use serde_json::Value;
+use std::collections::HashMap;
 use uuid::parser::ParseError;
 
 #[derive(Debug, Display, PartialEq)]
-#[allow(dead_code)]
 pub enum ApiError {
+    #[display(fmt = "")]
+    AlreadyExists(FieldErrors),
     BadRequest(String),
     BlockingError(String),
     CacheError(String),
     CannotDecodeJwtToken(String),
     CannotEncodeJwtToken(String),
+    Conflict(String),
+    Forbidden(String),
     InternalServerError(String),
     NotFound(String),
     ParseError(String),
+    PayloadTooLarge(String),
     PoolError(String),
+    SerializationFailure(String),
+    TooManyRequests(String),
     #[display(fmt = "")]
-    ValidationError(Vec<String>),
+    ValidationError(FieldErrors),
     Unauthorized(String),
+    UnsupportedMediaType(String),
 }
 
-/// User-friendly error messages
-#[derive(Debug, Deserialize, Serialize)]
-pub struct ErrorResponse {
-    errors: Vec<String>,
+/// Content type of error responses
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// Detail of bodies over the extractor's limit
+const PAYLOAD_TOO_LARGE: &str = "The request body is too large";
+
+/// RFC 7807 problem details
+///
+/// `code` is stable, so clients can match on it instead of `detail`.
+/// `request_id` is filled in by the RequestId middleware.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "FieldErrors::is_empty")]
+    pub errors: FieldErrors,
 }
 
-/// Automatically convert ApiErrors to external Response Errors
-impl ResponseError for ApiError {
-    fn error_response(&self) -> HttpResponse {
+impl Problem {
+    pub fn new(status: StatusCode, code: &str, detail: String) -> Self {
+        Self {
+            problem_type: format!("/problems/{}", code.replace('_', "-")),
+            title: status.canonical_reason().unwrap_or("Unknown").into(),
+            status: status.as_u16(),
+            detail,
+            code: code.into(),
+            request_id: None,
+            errors: FieldErrors::new(),
+        }
+    }
+}
+
+impl ApiError {
+    /// A stable, machine-readable code for the error
+    pub fn code(&self) -> &'static str {
         match self {
-            ApiError::BadRequest(error) => {
-                HttpResponse::BadRequest().json::<ErrorResponse>(error.into())
-            }
-            ApiError::NotFound(message) => {
-                HttpResponse::NotFound().json::<ErrorResponse>(message.into())
-            }
-            ApiError::ValidationError(errors) => {
-                HttpResponse::UnprocessableEntity().json::<ErrorResponse>(errors.to_vec().into())
-            }
-            ApiError::Unauthorized(error) => {
-                HttpResponse::Unauthorized().json::<ErrorResponse>(error.into())
+            ApiError::AlreadyExists(_) => "already_exists",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::BlockingError(_) => "blocking_error",
+            ApiError::CacheError(_) => "cache_unavailable",
+            ApiError::CannotDecodeJwtToken(_) => "invalid_token",
+            ApiError::CannotEncodeJwtToken(_) => "token_encoding_failed",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::InternalServerError(_) => "internal_server_error",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::ParseError(_) => "parse_error",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::PoolError(_) => "database_unavailable",
+            ApiError::SerializationFailure(_) => "serialization_failure",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::ValidationError(_) => "validation_failed",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+        }
+    }
+
+    /// The detail sent to clients, in English, which never includes internal messages
+    ///
+    /// Other locales are rendered by the Localize middleware.
+    fn detail(&self) -> String {
+        let mut args = HashMap::new();
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Conflict(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::ParseError(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::TooManyRequests(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::UnsupportedMediaType(message) => {
+                args.insert("detail".to_string(), Value::from(message.as_str()));
             }
-            _ => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+            _ => (),
         }
+        translate(DEFAULT_LOCALE, &format!("errors.{}", self.code()), &args).unwrap_or_default()
+    }
+
+    /// The problem details of the error
+    pub fn problem(&self) -> Problem {
+        let mut problem = Problem::new(self.status_code(), self.code(), self.detail());
+        if let ApiError::AlreadyExists(errors) | ApiError::ValidationError(errors) = self {
+            problem.errors = errors.clone();
+        }
+        problem
+    }
+
+    /// A value of the field is already taken
+    pub fn already_exists(field: &str) -> Self {
+        let mut errors = FieldErrors::new();
+        add_error(&mut errors, field, UNIQUE, HashMap::new());
+        ApiError::AlreadyExists(errors)
     }
-}
 
-/// Utility to make transforming a string reference into an ErrorResponse
-impl From<&String> for ErrorResponse {
-    fn from(error: &String) -> Self {
-        ErrorResponse {
-            errors: vec![error.into()],
+    /// Reject field errors: a conflict when every error is a taken unique
+    /// value, so that clients can tell it from invalid input
+    pub fn from_field_errors(errors: FieldErrors) -> Self {
+        let conflict = errors
+            .values()
+            .flatten()
+            .all(|error| error.code == UNIQUE);
+        if conflict {
+            ApiError::AlreadyExists(errors)
+        } else {
+            ApiError::ValidationError(errors)
         }
     }
 }
 
-/// Utility to make transforming a vector of strings into an ErrorResponse
-impl From<Vec<String>> for ErrorResponse {
-    fn from(errors: Vec<String>) -> Self {
-        ErrorResponse { errors }
+/// Automatically convert ApiErrors to external Response Errors
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) | ApiError::ParseError(_) => StatusCode::BAD_REQUEST,
+            ApiError::CacheError(_)
+            | ApiError::PoolError(_)
+            | ApiError::SerializationFailure(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::CannotDecodeJwtToken(_) | ApiError::Unauthorized(_) => {
+                StatusCode::UNAUTHORIZED
+            }
+            ApiError::AlreadyExists(_) | ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::BlockingError(_)
+            | ApiError::CannotEncodeJwtToken(_)
+            | ApiError::InternalServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Respond with the problem details, kept in the response's extensions
+    /// so that middleware can add to them
+    fn error_response(&self) -> HttpResponse {
+        let problem = self.problem();
+
+        // Internal messages are only logged
+        match self {
+            ApiError::CannotDecodeJwtToken(_) => warn!("{}: {}", problem.code, self),
+            _ if self.status_code().is_server_error() => error!("{}: {}", problem.code, self),
+            _ => (),
+        }
+
+        let mut builder = HttpResponse::build(self.status_code());
+        // Serialization failures succeed when the transaction is run again
+        if let ApiError::SerializationFailure(_) = self {
+            builder.header(RETRY_AFTER, "1");
+        }
+        let mut response = builder.content_type(PROBLEM_CONTENT_TYPE).json(&problem);
+        response.extensions_mut().insert(problem);
+        response
     }
 }
 
 /// Convert DBErrors to ApiErrors
+///
+/// Constraint violations name the offending field where the backend reports
+/// it, but never include the raw database message.
 impl From<DBError> for ApiError {
     fn from(error: DBError) -> ApiError {
-        // Right now we just care about UniqueViolation from diesel
-        // But this would be helpful to easily map errors as our app grows
         match error {
-            DBError::DatabaseError(kind, info) => {
-                if let DatabaseErrorKind::UniqueViolation = kind {
-                    let message = info.details().unwrap_or_else(|| info.message()).to_string();
-                    return ApiError::BadRequest(message);
+            DBError::DatabaseError(kind, info) => match kind {
+                DatabaseErrorKind::UniqueViolation => {
+                    let field = unique_field(&*info).unwrap_or_else(|| "value".into());
+                    ApiError::already_exists(&field)
                 }
-                ApiError::InternalServerError("Unknown database error".into())
-            }
-            _ => ApiError::InternalServerError("Unknown database error".into()),
+                DatabaseErrorKind::ForeignKeyViolation => ApiError::Conflict(
+                    "The record is referenced by or references another record".into(),
+                ),
+                // Keep serialization failures distinct so transactions can retry them
+                DatabaseErrorKind::SerializationFailure => {
+                    ApiError::SerializationFailure(info.message().to_string())
+                }
+                _ => constraint_violation(&*info)
+                    .unwrap_or_else(|| ApiError::InternalServerError(info.message().to_string())),
+            },
+            DBError::NotFound => ApiError::NotFound("Record not found".into()),
+            error => ApiError::InternalServerError(error.to_string()),
         }
     }
 }
 
+/// The field of a unique violation
+///
+/// Postgres: `Key (email)=(satoshi@nakamotoinstitute.org) already exists.`
+/// MySQL: `Duplicate entry 'satoshi@nakamotoinstitute.org' for key 'users.email'`
+/// SQLite: `UNIQUE constraint failed: users.email`
+fn unique_field(info: &dyn DatabaseErrorInformation) -> Option<String> {
+    if let Some(field) = info
+        .details()
+        .and_then(|details| between(details, "Key (", ")="))
+    {
+        return Some(field.into());
+    }
+    let message = info.message();
+    between(message, "for key '", "'")
+        .or_else(|| after(message, "UNIQUE constraint failed: "))
+        .map(without_table)
+}
+
+/// Diesel 1.4 has no kinds for not-null and check violations, so they are
+/// recognized from what the backend reports and mapped to field errors
+///
+/// Postgres: the column or constraint name of the error
+/// MySQL: `Column 'email' cannot be null`, `Check constraint 'email_check' is violated.`
+/// SQLite: `NOT NULL constraint failed: users.email`, `CHECK constraint failed: email_check`
+fn constraint_violation(info: &dyn DatabaseErrorInformation) -> Option<ApiError> {
+    let message = info.message();
+    let (code, field) = if message.contains("not-null constraint") {
+        ("not_null", info.column_name()?.to_string())
+    } else if message.contains("check constraint") {
+        ("check", info.constraint_name()?.to_string())
+    } else if let Some(column) = between(message, "Column '", "' cannot be null") {
+        ("not_null", column.into())
+    } else if let Some(constraint) = between(message, "Check constraint '", "' is violated") {
+        ("check", constraint.into())
+    } else if let Some(column) = after(message, "NOT NULL constraint failed: ") {
+        ("not_null", without_table(column))
+    } else if let Some(constraint) = after(message, "CHECK constraint failed: ") {
+        ("check", constraint.into())
+    } else {
+        return None;
+    };
+
+    let mut errors = FieldErrors::new();
+    add_error(&mut errors, &field, code, HashMap::new());
+    Some(ApiError::ValidationError(errors))
+}
+
+/// The text between a prefix and the next suffix
+fn between<'a>(text: &'a str, prefix: &str, suffix: &str) -> Option<&'a str> {
+    let rest = after(text, prefix)?;
+    rest.find(suffix).map(|end| &rest[..end])
+}
+
+/// The text after a prefix
+fn after<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    text.find(prefix).map(|start| &text[start + prefix.len()..])
+}
+
+/// `users.email` -> `email`
+fn without_table(column: &str) -> String {
+    column.rsplit('.').next().unwrap_or(column).into()
+}
+
 /// Convert PoolErrors to ApiErrors
 impl From<PoolError> for ApiError {
     fn from(error: PoolError) -> ApiError {
@@ -102,6 +310,61 @@ impl From<ParseError> for ApiError {
     }
 }
 
+/// Convert JSON body extractor errors to ApiErrors
+impl From<&JsonPayloadError> for ApiError {
+    fn from(error: &JsonPayloadError) -> ApiError {
+        match error {
+            JsonPayloadError::Overflow => ApiError::PayloadTooLarge(PAYLOAD_TOO_LARGE.into()),
+            JsonPayloadError::ContentType => {
+                ApiError::UnsupportedMediaType("The content type must be application/json".into())
+            }
+            JsonPayloadError::Deserialize(error) => {
+                ApiError::ParseError(format!("Invalid JSON: {}", error))
+            }
+            JsonPayloadError::Payload(error) => payload_error(error),
+        }
+    }
+}
+
+/// Convert form body extractor errors to ApiErrors
+impl From<&UrlencodedError> for ApiError {
+    fn from(error: &UrlencodedError) -> ApiError {
+        match error {
+            UrlencodedError::Overflow { .. } => ApiError::PayloadTooLarge(PAYLOAD_TOO_LARGE.into()),
+            UrlencodedError::ContentType => ApiError::UnsupportedMediaType(
+                "The content type must be application/x-www-form-urlencoded".into(),
+            ),
+            UrlencodedError::Parse => ApiError::ParseError("Invalid form".into()),
+            UrlencodedError::Payload(error) => payload_error(error),
+            error => ApiError::BadRequest(error.to_string()),
+        }
+    }
+}
+
+/// Convert query string extractor errors to ApiErrors
+impl From<&QueryPayloadError> for ApiError {
+    fn from(error: &QueryPayloadError) -> ApiError {
+        let QueryPayloadError::Deserialize(error) = error;
+        ApiError::ParseError(format!("Invalid query string: {}", error))
+    }
+}
+
+/// Convert path extractor errors to ApiErrors
+impl From<&PathError> for ApiError {
+    fn from(error: &PathError) -> ApiError {
+        let PathError::Deserialize(error) = error;
+        ApiError::ParseError(format!("Invalid path: {}", error))
+    }
+}
+
+/// Errors reading a body, which is only too large when it reaches the limit
+fn payload_error(error: &PayloadError) -> ApiError {
+    match error {
+        PayloadError::Overflow => ApiError::PayloadTooLarge(PAYLOAD_TOO_LARGE.into()),
+        error => ApiError::BadRequest(format!("Could not read the request body: {}", error)),
+    }
+}
+
 /// Convert Thread BlockingErrors to ApiErrors
 impl From<BlockingError<ApiError>> for ApiError {
     fn from(error: BlockingError<ApiError>) -> ApiError {
@@ -111,3 +374,221 @@ impl From<BlockingError<ApiError>> for ApiError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::{Body, ResponseBody};
+
+    fn body(response: &HttpResponse) -> Problem {
+        match response.body() {
+            ResponseBody::Body(Body::Bytes(bytes)) => serde_json::from_slice(bytes).unwrap(),
+            _ => panic!("Response body is not bytes"),
+        }
+    }
+
+    #[test]
+    fn it_responds_with_problem_details() {
+        let response = ApiError::NotFound("User 1 not found".into()).error_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            PROBLEM_CONTENT_TYPE
+        );
+        let problem = body(&response);
+        assert_eq!(problem.problem_type, "/problems/not-found");
+        assert_eq!(problem.title, "Not Found");
+        assert_eq!(problem.status, 404);
+        assert_eq!(problem.detail, "User 1 not found");
+        assert_eq!(problem.code, "not_found");
+    }
+
+    #[test]
+    fn it_doesnt_leak_internal_messages() {
+        let response =
+            ApiError::PoolError("connection refused to 10.0.0.1".into()).error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let problem = body(&response);
+        assert_eq!(problem.code, "database_unavailable");
+        assert_eq!(problem.detail, "The server could not complete the request");
+    }
+
+    #[test]
+    fn it_maps_validation_errors_by_field() {
+        let mut errors = FieldErrors::new();
+        errors.insert("email".into(), vec![]);
+        let problem = ApiError::ValidationError(errors.clone()).problem();
+        assert_eq!(problem.status, 422);
+        assert_eq!(problem.errors, errors);
+    }
+
+    #[test]
+    fn it_keeps_the_problem_for_middleware() {
+        let response = ApiError::Unauthorized("Unauthorized".into()).error_response();
+        let extensions = response.extensions();
+        assert_eq!(extensions.get::<Problem>().unwrap().code, "unauthorized");
+    }
+
+    #[derive(Default)]
+    struct Info {
+        message: &'static str,
+        details: Option<&'static str>,
+        column: Option<&'static str>,
+        constraint: Option<&'static str>,
+    }
+
+    impl DatabaseErrorInformation for Info {
+        fn message(&self) -> &str {
+            self.message
+        }
+        fn details(&self) -> Option<&str> {
+            self.details
+        }
+        fn hint(&self) -> Option<&str> {
+            None
+        }
+        fn table_name(&self) -> Option<&str> {
+            None
+        }
+        fn column_name(&self) -> Option<&str> {
+            self.column
+        }
+        fn constraint_name(&self) -> Option<&str> {
+            self.constraint
+        }
+    }
+
+    fn db_error(kind: DatabaseErrorKind, info: Info) -> ApiError {
+        DBError::DatabaseError(kind, Box::new(info)).into()
+    }
+
+    fn field_error(error: ApiError) -> (String, String) {
+        match error {
+            ApiError::ValidationError(errors) => {
+                let (field, errors) = errors.into_iter().next().unwrap();
+                (field, errors[0].code.clone())
+            }
+            error => panic!("Not a validation error: {:?}", error),
+        }
+    }
+
+    #[test]
+    fn it_names_the_field_of_unique_violations() {
+        let postgres = Info {
+            message: "duplicate key value violates unique constraint \"users_email_key\"",
+            details: Some("Key (email)=(satoshi@nakamotoinstitute.org) already exists."),
+            ..Info::default()
+        };
+        let mysql = Info {
+            message: "Duplicate entry 'satoshi@nakamotoinstitute.org' for key 'users.email'",
+            ..Info::default()
+        };
+        let sqlite = Info {
+            message: "UNIQUE constraint failed: users.email",
+            ..Info::default()
+        };
+        for info in [postgres, mysql, sqlite] {
+            assert_eq!(
+                db_error(DatabaseErrorKind::UniqueViolation, info),
+                ApiError::already_exists("email")
+            );
+        }
+    }
+
+    #[test]
+    fn it_doesnt_leak_unrecognized_unique_violations() {
+        let info = Info {
+            message: "unique violation on 10.0.0.1",
+            ..Info::default()
+        };
+        assert_eq!(
+            db_error(DatabaseErrorKind::UniqueViolation, info),
+            ApiError::already_exists("value")
+        );
+    }
+
+    #[test]
+    fn it_maps_unique_violations_to_conflicts_with_field_errors() {
+        let error = ApiError::already_exists("email");
+        assert_eq!(error.status_code(), StatusCode::CONFLICT);
+        let problem = error.problem();
+        assert_eq!(problem.code, "already_exists");
+        assert_eq!(problem.errors["email"][0].message, "email is already taken");
+    }
+
+    #[test]
+    fn it_only_rejects_taken_values_as_conflicts() {
+        let mut errors = FieldErrors::new();
+        add_error(&mut errors, "email", UNIQUE, HashMap::new());
+        let error = ApiError::from_field_errors(errors.clone());
+        assert_eq!(error.status_code(), StatusCode::CONFLICT);
+
+        add_error(&mut errors, "password", "password_digit", HashMap::new());
+        let error = ApiError::from_field_errors(errors);
+        assert_eq!(error.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[test]
+    fn it_maps_foreign_key_violations_to_conflicts() {
+        let error = db_error(DatabaseErrorKind::ForeignKeyViolation, Info::default());
+        assert_eq!(error.status_code(), StatusCode::CONFLICT);
+        assert_eq!(error.code(), "conflict");
+    }
+
+    #[test]
+    fn it_maps_not_null_violations_to_field_errors() {
+        let postgres = Info {
+            message: "null value in column \"email\" violates not-null constraint",
+            column: Some("email"),
+            ..Info::default()
+        };
+        let mysql = Info {
+            message: "Column 'email' cannot be null",
+            ..Info::default()
+        };
+        let sqlite = Info {
+            message: "NOT NULL constraint failed: users.email",
+            ..Info::default()
+        };
+        for info in [postgres, mysql, sqlite] {
+            let error = db_error(DatabaseErrorKind::__Unknown, info);
+            assert_eq!(error.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(field_error(error), ("email".into(), "not_null".into()));
+        }
+    }
+
+    #[test]
+    fn it_maps_check_violations_to_field_errors() {
+        let postgres = Info {
+            message: "new row for relation \"users\" violates check constraint \"email_check\"",
+            constraint: Some("email_check"),
+            ..Info::default()
+        };
+        let mysql = Info {
+            message: "Check constraint 'email_check' is violated.",
+            ..Info::default()
+        };
+        let sqlite = Info {
+            message: "CHECK constraint failed: email_check",
+            ..Info::default()
+        };
+        for info in [postgres, mysql, sqlite] {
+            let error = db_error(DatabaseErrorKind::__Unknown, info);
+            assert_eq!(field_error(error), ("email_check".into(), "check".into()));
+        }
+    }
+
+    #[test]
+    fn it_maps_serialization_failures_to_a_retryable_error() {
+        let error = db_error(DatabaseErrorKind::SerializationFailure, Info::default());
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "1");
+    }
+
+    #[test]
+    fn it_maps_not_found_errors() {
+        let error: ApiError = DBError::NotFound.into();
+        assert_eq!(error.status_code(), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,13 +1,19 @@
-use crate::auth::{decode_jwt, PrivateClaim};
+use crate::auth::{decode_jwt, request_jwt};
+use crate::errors::ApiError;
+use crate::middleware::request_id::CurrentRequestId;
 use crate::models::user::AuthUser;
-use actix_identity::RequestIdentity;
+use crate::validate::validate;
 use actix_web::{
     dev::Payload,
-    web::{HttpRequest, HttpResponse},
+    error::JsonPayloadError,
+    web::{FormConfig, HttpRequest, HttpResponse, Json, JsonConfig, PathConfig, QueryConfig},
     Error,
     FromRequest,
 };
-use futures::future::{ok, err, Ready};
+use futures::future::{ok, err, FutureExt, LocalBoxFuture, Ready};
+use serde::de::DeserializeOwned;
+use std::ops::Deref;
+use validator::Validate;
 
 /// Extractor for pulling the identity out of a request.
 ///
@@ -18,14 +24,219 @@ impl FromRequest for AuthUser {
     type Future = Ready<Result<Self, Self::Error>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
-        let identity = RequestIdentity::get_identity(req);
-        if let Some(identity) = identity {
-            let private_claim: PrivateClaim = decode_jwt(&identity).unwrap();
+        let private_claim = request_jwt(req).and_then(|jwt| decode_jwt(&jwt).ok());
+        if let Some(private_claim) = private_claim {
             return ok(AuthUser {
-                id: private_claim.user_id.to_string(),
+                id: private_claim.user_id,
                 email: private_claim.email,
             });
         }
         err(HttpResponse::Unauthorized().into())
     }
 }
+
+/// Extractor for the id of the request, set by the RequestId middleware
+///
+/// Add "request_id: CurrentRequestId" to a handler to pass it on, e.g. to
+/// other services or in log lines.
+impl FromRequest for CurrentRequestId {
+    type Error = Error;
+    type Config = ();
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        match req.extensions().get::<CurrentRequestId>() {
+            Some(request_id) => ok(request_id.clone()),
+            None => err(ApiError::InternalServerError(
+                "The RequestId middleware is not registered".into(),
+            )
+            .into()),
+        }
+    }
+}
+
+/// A JSON body that has been validated
+///
+/// Rejects invalid bodies with a 422 and the errors of each field.
+#[derive(Debug)]
+pub struct ValidatedJson<T>(pub T);
+
+impl<T> FromRequest for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate + 'static,
+{
+    type Error = Error;
+    type Config = JsonConfig;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        Json::<T>::from_request(req, payload)
+            .map(|json| validated(json.map(Json::into_inner)).map(ValidatedJson))
+            .boxed_local()
+    }
+}
+
+/// Validate an extracted body, reporting JSON errors as problem details
+///
+/// Errors already converted by the JSON config are kept.
+fn validated<T: Validate>(extracted: Result<T, Error>) -> Result<T, Error> {
+    let value = extracted.map_err(|error| match error.as_error::<JsonPayloadError>() {
+        Some(error) => ApiError::from(error).into(),
+        None => error,
+    })?;
+    validate(&value)?;
+    Ok(value)
+}
+
+/// Report JSON body errors as problem details, including for plain `Json<T>`
+pub fn json_config() -> JsonConfig {
+    JsonConfig::default().error_handler(|error, _| ApiError::from(&error).into())
+}
+
+/// Report query string errors as problem details, including for plain `Query<T>`
+pub fn query_config() -> QueryConfig {
+    QueryConfig::default().error_handler(|error, _| ApiError::from(&error).into())
+}
+
+/// Report path errors as problem details, including for plain `Path<T>`
+pub fn path_config() -> PathConfig {
+    PathConfig::default().error_handler(|error, _| ApiError::from(&error).into())
+}
+
+/// Report form body errors as problem details, including for plain `Form<T>`
+pub fn form_config() -> FormConfig {
+    FormConfig::default().error_handler(|error, _| ApiError::from(&error).into())
+}
+
+impl<T> Deref for ValidatedJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Problem;
+    use crate::middleware::request_id::{RequestId, REQUEST_ID_HEADER};
+    use actix_web::dev::ServiceResponse;
+    use actix_web::{test, web, App};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Deserialize, Serialize, Validate)]
+    struct Params {
+        #[validate(length(min = 3))]
+        name: String,
+    }
+
+    async fn call(req: test::TestRequest) -> ServiceResponse {
+        let mut app = test::init_service(
+            App::new()
+                .app_data(JsonConfig::default().limit(64))
+                .route(
+                    "/json",
+                    web::post().to(|params: ValidatedJson<Params>| {
+                        HttpResponse::Ok().body(params.0.name)
+                    }),
+                ),
+        )
+        .await;
+        test::call_service(&mut app, req.to_request()).await
+    }
+
+    async fn problem(response: ServiceResponse) -> Problem {
+        let body = test::read_body(response).await;
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn it_extracts_valid_params() {
+        let req = test::TestRequest::post()
+            .uri("/json")
+            .set_json(&Params { name: "Ada".into() });
+        let response = call(req).await;
+        assert!(response.status().is_success());
+        assert_eq!(test::read_body(response).await, "Ada");
+    }
+
+    #[actix_rt::test]
+    async fn it_rejects_invalid_params_with_field_errors() {
+        let req = test::TestRequest::post()
+            .uri("/json")
+            .set_json(&Params { name: "A".into() });
+        let response = call(req).await;
+        assert_eq!(response.status(), 422);
+        let problem = problem(response).await;
+        assert_eq!(problem.errors["name"][0].code, "length");
+    }
+
+    #[actix_rt::test]
+    async fn it_reports_malformed_json() {
+        let req = test::TestRequest::post()
+            .uri("/json")
+            .header("content-type", "application/json")
+            .set_payload("{\"name\":");
+        let response = call(req).await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(problem(response).await.code, "parse_error");
+    }
+
+    #[actix_rt::test]
+    async fn it_reports_a_wrong_content_type() {
+        let req = test::TestRequest::post()
+            .uri("/json")
+            .header("content-type", "text/plain")
+            .set_payload("name=Ada");
+        let response = call(req).await;
+        assert_eq!(response.status(), 415);
+        assert_eq!(problem(response).await.code, "unsupported_media_type");
+    }
+
+    #[actix_rt::test]
+    async fn it_reports_an_oversized_body() {
+        let req = test::TestRequest::post().uri("/json").set_json(&Params {
+            name: "a".repeat(100),
+        });
+        let response = call(req).await;
+        assert_eq!(response.status(), 413);
+        assert_eq!(problem(response).await.code, "payload_too_large");
+    }
+
+    #[actix_rt::test]
+    async fn it_reports_errors_of_plain_extractors_with_the_configs() {
+        let mut app = test::init_service(App::new().app_data(json_config()).route(
+            "/",
+            web::post().to(|params: Json<Params>| {
+                HttpResponse::Ok().body(params.into_inner().name)
+            }),
+        ))
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/")
+            .header("content-type", "application/json")
+            .set_payload("[]")
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(problem(response).await.code, "parse_error");
+    }
+
+    #[actix_rt::test]
+    async fn it_extracts_the_request_id() {
+        let mut app = test::init_service(App::new().wrap(RequestId).route(
+            "/",
+            web::get().to(|request_id: CurrentRequestId| {
+                HttpResponse::Ok().body(request_id.0)
+            }),
+        ))
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header(REQUEST_ID_HEADER, "abc-123")
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(test::read_body(response).await, "abc-123");
+    }
+}
@@ -1,39 +1,50 @@
 use crate::auth::{create_jwt, hash, PrivateClaim};
-use crate::database::PoolType;
+use crate::config::CONFIG;
 use crate::errors::ApiError;
+use crate::extractors::ValidatedJson;
 use crate::handlers::user::UserResponse;
 use crate::helpers::{respond_json, respond_ok};
-use crate::models::user::find_by_auth;
-use crate::validate::validate;
+use crate::metrics::LOGINS;
+use crate::repositories::user::UserRepo;
 use actix_identity::Identity;
-use actix_web::web::{block, Data, HttpResponse, Json};
+use actix_web::web::{block, HttpResponse, Json};
 use serde::Serialize;
 use validator::Validate;
 
 #[derive(Clone, Debug, Deserialize, Serialize, Validate)]
 pub struct LoginRequest {
-    #[validate(email(message = "email must be a valid email"))]
+    #[validate(email)]
     pub email: String,
 
-    #[validate(length(
-        min = 6,
-        message = "password is required and must be at least 6 characters"
-    ))]
+    #[validate(length(min = 6))]
     pub password: String,
 }
 
+/// The response of `token`, as in RFC 6750
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    /// Seconds until the token expires
+    pub expires_in: i64,
+}
+
+/// Check that the email + hashed password matches a user
+async fn authenticate(users: UserRepo, params: LoginRequest) -> Result<UserResponse, ApiError> {
+    let hashed = hash(&params.password);
+    let user = block(move || users.find_by_auth(&params.email, &hashed)).await;
+    LOGINS.inc(&[if user.is_ok() { "success" } else { "failure" }]);
+    Ok(user?)
+}
+
 /// Login a user
 /// Create and remember their JWT
 pub async fn login(
     id: Identity,
-    pool: Data<PoolType>,
-    params: Json<LoginRequest>,
+    users: UserRepo,
+    params: ValidatedJson<LoginRequest>,
 ) -> Result<Json<UserResponse>, ApiError> {
-    validate(&params)?;
-
-    // Validate that the email + hashed password matches
-    let hashed = hash(&params.password);
-    let user = block(move || find_by_auth(&pool, &params.email, &hashed)).await?;
+    let user = authenticate(users, params.0).await?;
 
     // Create a JWT
     let private_claim = PrivateClaim::new(user.id, user.email.clone());
@@ -41,7 +52,24 @@ pub async fn login(
 
     // Remember the token
     id.remember(jwt);
-    respond_json(user.into())
+    respond_json(user)
+}
+
+/// Create a JWT for a user to send as a bearer token
+///
+/// Nothing is remembered in a cookie, so requests with the token don't need
+/// a CSRF token.
+pub async fn token(
+    users: UserRepo,
+    params: ValidatedJson<LoginRequest>,
+) -> Result<Json<TokenResponse>, ApiError> {
+    let user = authenticate(users, params.0).await?;
+    let private_claim = PrivateClaim::new(user.id, user.email);
+    respond_json(TokenResponse {
+        access_token: create_jwt(private_claim)?,
+        token_type: "Bearer".into(),
+        expires_in: CONFIG.jwt_expiration * 3600,
+    })
 }
 
 /// Logout a user
@@ -54,7 +82,8 @@ pub async fn logout(id: Identity) -> Result<HttpResponse, ApiError> {
 #[cfg(test)]
 pub mod tests {
     use super::*;
-    use crate::tests::helpers::tests::get_data_pool;
+    use crate::auth::decode_jwt;
+    use crate::tests::helpers::tests::{get_data_user_repository, TEST_PASSWORD};
     use actix_identity::Identity;
     use actix_web::{test, FromRequest};
 
@@ -69,12 +98,13 @@ pub mod tests {
     }
 
     async fn login_user() -> Result<Json<UserResponse>, ApiError> {
+        let users = get_data_user_repository();
         let params = LoginRequest {
-            email: "satoshi@nakamotoinstitute.org".into(),
-            password: "123456".into(),
+            email: users.get_all().unwrap().0[0].email.clone(),
+            password: TEST_PASSWORD.into(),
         };
         let identity = get_identity().await;
-        login(identity, get_data_pool(), Json(params)).await
+        login(identity, users, ValidatedJson(params)).await
     }
 
     async fn logout_user() -> Result<HttpResponse, ApiError> {
@@ -94,4 +124,17 @@ pub mod tests {
         let response = logout_user().await;
         assert!(response.is_ok());
     }
+
+    #[actix_rt::test]
+    async fn it_creates_a_bearer_token() {
+        let users = get_data_user_repository();
+        let user = users.get_all().unwrap().0.remove(0);
+        let params = LoginRequest {
+            email: user.email,
+            password: TEST_PASSWORD.into(),
+        };
+        let response = token(users, ValidatedJson(params)).await.unwrap();
+        assert_eq!(response.token_type, "Bearer");
+        assert_eq!(decode_jwt(&response.access_token).unwrap().user_id, user.id);
+    }
 }
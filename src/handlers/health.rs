@@ -1,11 +1,61 @@
+use crate::cache::{stats, Cache, CacheStats};
+use crate::config::CONFIG;
+use crate::database::Database;
 use crate::errors::ApiError;
 use crate::helpers::respond_json;
-use actix_web::web::Json;
+use crate::session::SessionStore;
+use crate::shutdown::is_shutting_down;
+use actix_rt::time::timeout;
+use actix_web::{
+    http::StatusCode,
+    web::{block, Json},
+    HttpResponse,
+};
+use futures::{join, Future};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct HealthResponse {
     pub status: String,
     pub version: String,
+    pub cache: CacheStats,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct LiveResponse {
+    pub status: Status,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ReadyResponse {
+    pub status: Status,
+    pub shutting_down: bool,
+    pub checks: Checks,
+}
+
+/// `degraded` when a check that isn't critical fails
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Degraded,
+    Unavailable,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct Checks {
+    pub database: Check,
+    pub migrations: Check,
+    pub cache: Check,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct Check {
+    pub status: Status,
+    pub critical: bool,
+    pub latency_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 /// Handler to get the liveness of the service
@@ -13,16 +63,243 @@ pub async fn get_health() -> Result<Json<HealthResponse>, ApiError> {
     respond_json(HealthResponse {
         status: "ok".into(),
         version: env!("CARGO_PKG_VERSION").into(),
+        cache: stats(),
+    })
+}
+
+/// Handler to get the liveness of the process, without checking dependencies
+pub async fn get_live() -> Result<Json<LiveResponse>, ApiError> {
+    respond_json(LiveResponse { status: Status::Ok })
+}
+
+/// Handler to get whether the service can take traffic
+///
+/// Responds with 503 Service Unavailable when a critical dependency fails,
+/// or once a shutdown has started. The cache is only critical when it holds
+/// the sessions.
+pub async fn get_ready(database: Database, cache: Cache) -> HttpResponse {
+    readiness(database, cache, is_shutting_down()).await
+}
+
+async fn readiness(database: Database, cache: Cache, shutting_down: bool) -> HttpResponse {
+    let limit = Duration::from_millis(CONFIG.health_timeout);
+
+    let ping_database = database.clone();
+    let database_check = check(true, limit, async move {
+        block(move || ping_database.ping(limit)).await?;
+        Ok(())
+    });
+    let migrations_check = check(true, limit, async move {
+        let pending = block(move || database.pending_migrations(limit)).await?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+        Err(ApiError::InternalServerError(format!(
+            "Pending migrations: {}",
+            pending.join(", ")
+        )))
+    });
+    let cache_critical = CONFIG.session_store == SessionStore::Redis;
+    let cache_check = check(cache_critical, limit, async move { cache.ping().await });
+
+    let (database, migrations, cache) = join!(database_check, migrations_check, cache_check);
+    let checks = Checks {
+        database,
+        migrations,
+        cache,
+    };
+    let status = if shutting_down {
+        Status::Unavailable
+    } else {
+        checks.status()
+    };
+    let code = match status {
+        Status::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+    HttpResponse::build(code).json(ReadyResponse {
+        status,
+        shutting_down,
+        checks,
     })
 }
 
+impl Checks {
+    /// Unavailable if a critical check failed, degraded if any other did
+    fn status(&self) -> Status {
+        let checks = [&self.database, &self.migrations, &self.cache];
+        let failed = checks.iter().filter(|check| check.status != Status::Ok);
+        failed.fold(Status::Ok, |status, check| match (status, check.critical) {
+            (_, true) | (Status::Unavailable, _) => Status::Unavailable,
+            _ => Status::Degraded,
+        })
+    }
+}
+
+/// Time a check, failing it if it takes longer than the limit
+async fn check<F>(critical: bool, limit: Duration, check: F) -> Check
+where
+    F: Future<Output = Result<(), ApiError>>,
+{
+    let start = Instant::now();
+    let result = match timeout(limit, check).await {
+        Ok(result) => result.map_err(|error| error.to_string()),
+        Err(_) => Err(format!("Timed out after {}ms", limit.as_millis())),
+    };
+    Check {
+        status: if result.is_ok() {
+            Status::Ok
+        } else {
+            Status::Unavailable
+        },
+        critical,
+        latency_ms: start.elapsed().as_millis() as u64,
+        error: result.err(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cache::CacheBackend;
+    use crate::database::DatabaseCheck;
+    use crate::tests::helpers::tests::{get_cache, get_pool, TestConnection, TestPool};
+    use actix_web::{test, web, web::Data, App, Route};
+    use diesel::r2d2::ConnectionManager;
+    use diesel_migrations::setup_database;
+
+    fn database(pool: TestPool) -> Database {
+        Data::new(Box::new(pool) as Box<dyn DatabaseCheck>)
+    }
+
+    struct DownCache;
+
+    #[async_trait::async_trait(?Send)]
+    impl CacheBackend for DownCache {
+        async fn get(&self, _: &str) -> Result<Option<String>, ApiError> {
+            Ok(None)
+        }
+
+        async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>, ApiError> {
+            Ok(vec![None; keys.len()])
+        }
+
+        async fn set(&self, _: &str, _: String, _: Option<Duration>) -> Result<(), ApiError> {
+            Ok(())
+        }
+
+        async fn set_if_absent(&self, _: &str, _: String, _: Duration) -> Result<bool, ApiError> {
+            Ok(true)
+        }
+
+        async fn set_if_present(&self, _: &str, _: String, _: Duration) -> Result<bool, ApiError> {
+            Ok(false)
+        }
+
+        async fn delete_many(&self, _: &[String]) -> Result<(), ApiError> {
+            Ok(())
+        }
+
+        async fn delete_if_equal(&self, _: &str, _: &str) -> Result<bool, ApiError> {
+            Ok(false)
+        }
+
+        async fn add_member(&self, _: &str, _: &str, _: Duration) -> Result<(), ApiError> {
+            Ok(())
+        }
+
+        async fn remove_members(&self, _: &str, _: &[String]) -> Result<usize, ApiError> {
+            Ok(0)
+        }
+
+        async fn members(&self, _: &str) -> Result<Vec<String>, ApiError> {
+            Ok(vec![])
+        }
+
+        async fn increment(&self, _: &str, _: Duration) -> Result<u64, ApiError> {
+            Ok(1)
+        }
+
+        async fn ping(&self) -> Result<(), ApiError> {
+            Err(ApiError::CacheError("Connection refused".into()))
+        }
+    }
+
+    async fn ready(database: Database, cache: Cache) -> (StatusCode, ReadyResponse) {
+        call(database, cache, web::get().to(get_ready)).await
+    }
+
+    async fn call(database: Database, cache: Cache, route: Route) -> (StatusCode, ReadyResponse) {
+        let mut app = test::init_service(
+            App::new()
+                .app_data(database)
+                .app_data(cache)
+                .route("/", route),
+        )
+        .await;
+        let response = test::call_service(&mut app, test::TestRequest::get().to_request()).await;
+        let status = response.status();
+        let body = test::read_body(response).await;
+        (status, serde_json::from_slice(&body).unwrap())
+    }
 
     #[actix_rt::test]
     async fn test_get_health() {
         let response = get_health().await.unwrap();
         assert_eq!(response.into_inner().status, "ok".to_string());
     }
+
+    #[actix_rt::test]
+    async fn test_get_live() {
+        let response = get_live().await.unwrap();
+        assert_eq!(response.into_inner().status, Status::Ok);
+    }
+
+    #[actix_rt::test]
+    async fn it_is_ready_when_every_check_passes() {
+        let (status, response) = ready(database(get_pool()), get_cache()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.checks.database.error, None);
+        assert_eq!(response.checks.migrations.error, None);
+    }
+
+    #[actix_rt::test]
+    async fn it_is_unavailable_with_pending_migrations() {
+        let manager = ConnectionManager::<TestConnection>::new(":memory:");
+        let pool = TestPool::builder().max_size(1).build(manager).unwrap();
+        // Create the migrations table without running any migration
+        setup_database(&*pool.get().unwrap()).unwrap();
+        let (status, response) = ready(database(pool), get_cache()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.status, Status::Unavailable);
+        assert_eq!(response.checks.database.status, Status::Ok);
+        assert_eq!(
+            response.checks.migrations.error,
+            Some("Pending migrations: 20190930115154, 20200401000000".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_is_degraded_when_a_check_that_isnt_critical_fails() {
+        let cache = Data::new(Box::new(DownCache) as Box<dyn CacheBackend>);
+        let (status, response) = ready(database(get_pool()), cache).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response.status, Status::Degraded);
+        assert_eq!(
+            response.checks.cache.error,
+            Some("Connection refused".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_is_unavailable_while_draining() {
+        let route =
+            web::get().to(|database: Database, cache: Cache| readiness(database, cache, true));
+        let (status, response) = call(database(get_pool()), get_cache(), route).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.status, Status::Unavailable);
+        assert!(response.shutting_down);
+        assert_eq!(response.checks.database.status, Status::Ok);
+    }
 }
@@ -0,0 +1,20 @@
+use crate::metrics::{render, CONTENT_TYPE};
+use actix_web::HttpResponse;
+
+/// Handler to scrape the Prometheus metrics
+pub async fn get_metrics() -> HttpResponse {
+    HttpResponse::Ok().content_type(CONTENT_TYPE).body(render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::CONTENT_TYPE as CONTENT_TYPE_HEADER;
+
+    #[actix_rt::test]
+    async fn test_get_metrics() {
+        let response = get_metrics().await;
+        let content_type = response.headers().get(CONTENT_TYPE_HEADER).unwrap();
+        assert_eq!(content_type, CONTENT_TYPE);
+    }
+}
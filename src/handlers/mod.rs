@@ -1,3 +1,6 @@
 pub mod auth;
 pub mod health;
+pub mod metrics;
+pub mod redirect;
+pub mod session;
 pub mod user;
\ No newline at end of file
@@ -0,0 +1,55 @@
+use crate::config::CONFIG;
+use actix_web::{http::header::LOCATION, HttpRequest, HttpResponse};
+
+/// Handler to send plain HTTP requests to the same path over HTTPS
+///
+/// Redirects to `TLS_REDIRECT_HOST` rather than the request's `Host` or
+/// `X-Forwarded-Host`, which clients control, so it can't redirect to
+/// another site. Uses 308 Permanent Redirect so that the method and body
+/// are kept.
+pub async fn redirect_to_https(request: HttpRequest) -> HttpResponse {
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    HttpResponse::PermanentRedirect()
+        .header(LOCATION, https_url(&CONFIG.tls_redirect_host, path))
+        .finish()
+}
+
+/// The path on the host over HTTPS
+fn https_url(host: &str, path: &str) -> String {
+    format!("https://{}{}", host, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+
+    #[actix_rt::test]
+    async fn it_redirects_to_https_on_the_configured_host() {
+        let request = test::TestRequest::post()
+            .uri("/api/v1/auth/login?next=%2F")
+            .header("Host", "evil.com")
+            .header("X-Forwarded-Host", "evil.com")
+            .to_http_request();
+        let response = redirect_to_https(request).await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        let location = response.headers().get(LOCATION).unwrap().to_str().unwrap();
+        assert_eq!(
+            location,
+            https_url(&CONFIG.tls_redirect_host, "/api/v1/auth/login?next=%2F")
+        );
+        assert!(!location.contains("evil.com"));
+    }
+
+    #[test]
+    fn it_builds_the_https_url() {
+        assert_eq!(https_url("example.com", "/"), "https://example.com/");
+        assert_eq!(
+            https_url("localhost:3000", "/health"),
+            "https://localhost:3000/health"
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use crate::cache::Cache;
+use crate::config::CONFIG;
+use crate::errors::ApiError;
+use crate::helpers::{respond_json, respond_ok};
+use crate::models::user::AuthUser;
+use crate::session::{self, Session};
+use actix_web::web::{HttpResponse, Json, Path};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+/// A session without its identity, which would let anyone impersonate the user
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct SessionResponse {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct SessionsResponse(pub Vec<SessionResponse>);
+
+/// List the active sessions of a user
+pub async fn get_sessions(
+    user: AuthUser,
+    user_id: Path<Uuid>,
+    cache: Cache,
+) -> Result<Json<SessionsResponse>, ApiError> {
+    authorize(&user, *user_id)?;
+    let sessions = session::list_sessions(cache, *user_id).await?;
+    respond_json(sessions.into())
+}
+
+/// Kill every session of a user
+pub async fn delete_sessions(
+    user: AuthUser,
+    user_id: Path<Uuid>,
+    cache: Cache,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&user, *user_id)?;
+    session::delete_sessions(cache, *user_id).await?;
+    respond_ok()
+}
+
+/// Kill a session of a user
+pub async fn delete_session(
+    user: AuthUser,
+    path: Path<(Uuid, Uuid)>,
+    cache: Cache,
+) -> Result<HttpResponse, ApiError> {
+    let (user_id, session_id) = *path;
+    authorize(&user, user_id)?;
+    session::delete_session(cache, user_id, session_id).await?;
+    respond_ok()
+}
+
+/// Users may manage their own sessions, and the `ADMIN_USERS` anyone's
+fn authorize(user: &AuthUser, user_id: Uuid) -> Result<(), ApiError> {
+    let is_admin = CONFIG
+        .admin_users
+        .iter()
+        .any(|id| Uuid::parse_str(id).ok() == Some(user.id));
+    if user.id == user_id || is_admin {
+        return Ok(());
+    }
+    Err(ApiError::Forbidden(format!(
+        "Not allowed to manage the sessions of user {}",
+        user_id
+    )))
+}
+
+impl From<Session> for SessionResponse {
+    fn from(session: Session) -> Self {
+        SessionResponse {
+            id: session.id,
+            user_id: session.user_id,
+            ip: session.ip,
+            user_agent: session.user_agent,
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+        }
+    }
+}
+
+impl From<Vec<Session>> for SessionsResponse {
+    fn from(sessions: Vec<Session>) -> Self {
+        SessionsResponse(sessions.into_iter().map(|session| session.into()).collect())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::auth::{create_jwt, PrivateClaim};
+    use crate::session::create_session;
+    use crate::tests::helpers::tests::get_cache;
+
+    fn auth_user(id: Uuid) -> AuthUser {
+        AuthUser {
+            id,
+            email: "test@test.com".into(),
+        }
+    }
+
+    async fn start_session(cache: Cache, user_id: Uuid) -> Session {
+        let identity = create_jwt(PrivateClaim::new(user_id, "test@test.com".into())).unwrap();
+        let session = Session::new(identity, None, None).unwrap();
+        create_session(cache, &session).await.unwrap();
+        session
+    }
+
+    #[actix_rt::test]
+    async fn it_gets_a_users_sessions() {
+        let cache = get_cache();
+        let user_id = Uuid::new_v4();
+        let session = start_session(cache.clone(), user_id).await;
+        start_session(cache.clone(), Uuid::new_v4()).await;
+        let response = get_sessions(auth_user(user_id), Path::from(user_id), cache)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.into_inner(),
+            SessionsResponse(vec![session.into()])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_get_another_users_sessions() {
+        let user_id = Uuid::new_v4();
+        let response =
+            get_sessions(auth_user(Uuid::new_v4()), Path::from(user_id), get_cache()).await;
+        let expected_error = ApiError::Forbidden(format!(
+            "Not allowed to manage the sessions of user {}",
+            user_id
+        ));
+        assert_eq!(response.unwrap_err(), expected_error);
+    }
+
+    #[actix_rt::test]
+    async fn it_deletes_a_session() {
+        let cache = get_cache();
+        let user_id = Uuid::new_v4();
+        let session = start_session(cache.clone(), user_id).await;
+        let other = start_session(cache.clone(), user_id).await;
+        let path = Path::from((user_id, session.id));
+        delete_session(auth_user(user_id), path, cache.clone())
+            .await
+            .unwrap();
+        let response = get_sessions(auth_user(user_id), Path::from(user_id), cache)
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner(), SessionsResponse(vec![other.into()]));
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_find_a_session_to_delete() {
+        let user_id = Uuid::new_v4();
+        let session_id = Uuid::new_v4();
+        let path = Path::from((user_id, session_id));
+        let response = delete_session(auth_user(user_id), path, get_cache()).await;
+        let expected_error = ApiError::NotFound(format!("Session {} not found", session_id));
+        assert_eq!(response.unwrap_err(), expected_error);
+    }
+
+    #[actix_rt::test]
+    async fn it_deletes_all_sessions_of_a_user() {
+        let cache = get_cache();
+        let user_id = Uuid::new_v4();
+        start_session(cache.clone(), user_id).await;
+        start_session(cache.clone(), user_id).await;
+        delete_sessions(auth_user(user_id), Path::from(user_id), cache.clone())
+            .await
+            .unwrap();
+        let response = get_sessions(auth_user(user_id), Path::from(user_id), cache)
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner(), SessionsResponse(vec![]));
+    }
+}
@@ -1,9 +1,14 @@
-use crate::database::PoolType;
+use crate::cache::{cached, invalidate, key, Cache};
 use crate::errors::ApiError;
+use crate::extractors::ValidatedJson;
 use crate::helpers::{respond_json, respond_ok};
-use crate::models::user::{create, delete, find, get_all, update, NewUser, UpdateUser, User};
-use crate::validate::validate;
-use actix_web::web::{block, Data, HttpResponse, Json, Path};
+use crate::models::user::{NewUser, UpdateUser, User};
+use crate::repositories::user::UserRepo;
+use crate::telemetry::block;
+use crate::validate::{validate_with, FieldErrors, ValidateWith};
+use crate::validators::{email_name, unique_email, PasswordPolicy};
+use actix_web::web::{HttpResponse, Json, Path};
+use async_trait::async_trait;
 use rayon::prelude::*;
 use serde::Serialize;
 use uuid::Uuid;
@@ -22,119 +27,167 @@ pub struct UsersResponse(pub Vec<UserResponse>);
 
 #[derive(Clone, Debug, Deserialize, Serialize, Validate)]
 pub struct CreateUserRequest {
-    #[validate(length(
-        min = 3,
-        message = "first_name is required and must be at least 3 characters"
-    ))]
+    #[validate(length(min = 3))]
     pub first_name: String,
 
-    #[validate(length(
-        min = 3,
-        message = "last_name is required and must be at least 3 characters"
-    ))]
+    #[validate(length(min = 3))]
     pub last_name: String,
 
-    #[validate(email(message = "email must be a valid email"))]
+    #[validate(email)]
     pub email: String,
 
-    #[validate(length(
-        min = 6,
-        message = "password is required and must be at least 6 characters"
-    ))]
+    /// Checked against the password policy
     pub password: String,
 }
 
+#[async_trait(?Send)]
+impl ValidateWith for CreateUserRequest {
+    type Context = UserRepo;
+
+    async fn validate_with(
+        &self,
+        users: &UserRepo,
+        errors: &mut FieldErrors,
+    ) -> Result<(), ApiError> {
+        let personal = [
+            self.first_name.as_str(),
+            self.last_name.as_str(),
+            email_name(&self.email),
+        ];
+        PasswordPolicy::from_config().check(errors, "password", &self.password, &personal);
+        unique_email(errors, "email", users, &self.email, None).await
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, Validate)]
 pub struct UpdateUserRequest {
-    #[validate(length(
-        min = 3,
-        message = "first_name is required and must be at least 3 characters"
-    ))]
+    #[validate(length(min = 3))]
     pub first_name: String,
 
-    #[validate(length(
-        min = 3,
-        message = "last_name is required and must be at least 3 characters"
-    ))]
+    #[validate(length(min = 3))]
     pub last_name: String,
 
-    #[validate(email(message = "email must be a valid email"))]
+    #[validate(email)]
     pub email: String,
 }
 
+#[async_trait(?Send)]
+impl ValidateWith for UpdateUserRequest {
+    /// The users and the id of the user being updated
+    type Context = (UserRepo, Uuid);
+
+    async fn validate_with(
+        &self,
+        context: &(UserRepo, Uuid),
+        errors: &mut FieldErrors,
+    ) -> Result<(), ApiError> {
+        let (users, user_id) = context;
+        unique_email(errors, "email", users, &self.email, Some(*user_id)).await
+    }
+}
+
+/// Cache key of a single user
+fn user_key(user_id: Uuid) -> String {
+    key("users", user_id)
+}
+
+/// Cache key of the list of all users
+fn users_key() -> String {
+    key("users", "all")
+}
+
 /// Get a user
 pub async fn get_user(
     user_id: Path<Uuid>,
-    pool: Data<PoolType>,
+    users: UserRepo,
+    cache: Cache,
 ) -> Result<Json<UserResponse>, ApiError> {
-    let user = block(move || find(&pool, *user_id)).await?;
+    let user_id = *user_id;
+    let user = cached(cache, &user_key(user_id), || async move {
+        block("users.find", move || users.find(user_id))
+            .await
+            .map_err(Into::into)
+    })
+    .await?;
     respond_json(user)
 }
 
 /// Get all users
-pub async fn get_users(pool: Data<PoolType>) -> Result<Json<UsersResponse>, ApiError> {
-    let users = block(move || get_all(&pool)).await?;
+pub async fn get_users(users: UserRepo, cache: Cache) -> Result<Json<UsersResponse>, ApiError> {
+    let users = cached(cache, &users_key(), || async move {
+        block("users.get_all", move || users.get_all())
+            .await
+            .map_err(Into::into)
+    })
+    .await?;
     respond_json(users)
 }
 
 /// Create a user
 pub async fn create_user(
-    pool: Data<PoolType>,
-    params: Json<CreateUserRequest>,
+    users: UserRepo,
+    params: ValidatedJson<CreateUserRequest>,
+    cache: Cache,
 ) -> Result<Json<UserResponse>, ApiError> {
-    validate(&params)?;
+    validate_with(&*params, &users).await?;
 
     // temporarily use the new user's id for created_at/updated_at
     // update when auth is added
     let user_id = Uuid::new_v4();
     let new_user: User = NewUser {
-        id: user_id.to_string(),
+        id: user_id,
         first_name: params.first_name.to_string(),
         last_name: params.last_name.to_string(),
         email: params.email.to_string(),
         password: params.password.to_string(),
-        created_by: user_id.to_string(),
-        updated_by: user_id.to_string(),
+        created_by: user_id,
+        updated_by: user_id,
     }
     .into();
-    let user = block(move || create(&pool, &new_user)).await?;
-    respond_json(user.into())
+    let user = block("users.create", move || users.create(&new_user)).await?;
+    invalidate(cache, &[users_key()]).await;
+    respond_json(user)
 }
 
 /// Update a user
 pub async fn update_user(
     user_id: Path<Uuid>,
-    pool: Data<PoolType>,
-    params: Json<UpdateUserRequest>,
+    users: UserRepo,
+    params: ValidatedJson<UpdateUserRequest>,
+    cache: Cache,
 ) -> Result<Json<UserResponse>, ApiError> {
-    validate(&params)?;
+    validate_with(&*params, &(users.clone(), *user_id)).await?;
 
     // temporarily use the user's id for updated_at
     // update when auth is added
     let update_user = UpdateUser {
-        id: user_id.to_string(),
+        id: *user_id,
         first_name: params.first_name.to_string(),
         last_name: params.last_name.to_string(),
         email: params.email.to_string(),
-        updated_by: user_id.to_string(),
+        updated_by: *user_id,
     };
-    let user = block(move || update(&pool, &update_user)).await?;
-    respond_json(user.into())
+    let user = block("users.update", move || users.update(&update_user)).await?;
+    invalidate(cache, &[user_key(*user_id), users_key()]).await;
+    respond_json(user)
 }
 
 /// Delete a user
 pub async fn delete_user(
     user_id: Path<Uuid>,
-    pool: Data<PoolType>,
+    users: UserRepo,
+    cache: Cache,
 ) -> Result<HttpResponse, ApiError> {
-    block(move || delete(&pool, *user_id)).await?;
+    let user_id = *user_id;
+    block("users.delete", move || users.delete(user_id)).await?;
+    invalidate(cache, &[user_key(user_id), users_key()]).await;
     respond_ok()
 }
 
 impl From<User> for UserResponse {
     fn from(user: User) -> Self {
         UserResponse {
-            id: Uuid::parse_str(&user.id).unwrap(),
+            id: user.id,
             first_name: user.first_name.to_string(),
             last_name: user.last_name.to_string(),
             email: user.email.to_string(),
@@ -151,67 +204,89 @@ impl From<Vec<User>> for UsersResponse {
 #[cfg(test)]
 pub mod tests {
     use super::*;
-    use crate::models::user::tests::create_user as model_create_user;
-    use crate::tests::helpers::tests::{get_data_pool, get_pool};
+    use crate::tests::helpers::tests::{get_cache, get_data_user_repository};
 
-    pub fn get_all_users() -> UsersResponse {
-        let pool = get_pool();
-        get_all(&pool).unwrap()
-    }
-
-    pub fn get_first_users_id() -> Uuid {
-        get_all_users().0[0].id
+    pub fn get_first_user(users: &UserRepo) -> UserResponse {
+        users.get_all().unwrap().0.remove(0)
     }
 
     #[actix_rt::test]
     async fn it_gets_a_user() {
-        let first_user = &get_all_users().0[0];
-        let user_id: Path<Uuid> = get_first_users_id().into();
-        let response = get_user(user_id, get_data_pool()).await.unwrap();
-        assert_eq!(response.into_inner(), *first_user);
+        let users = get_data_user_repository();
+        let first_user = get_first_user(&users);
+        let user_id: Path<Uuid> = first_user.id.into();
+        let response = get_user(user_id, users, get_cache()).await.unwrap();
+        assert_eq!(response.into_inner(), first_user);
     }
 
     #[actix_rt::test]
     async fn it_doesnt_find_a_user() {
         let uuid = Uuid::new_v4();
         let user_id: Path<Uuid> = uuid.into();
-        let response = get_user(user_id, get_data_pool()).await;
-        let expected_error = ApiError::NotFound(format!("User {} not found", uuid.to_string()));
+        let response = get_user(user_id, get_data_user_repository(), get_cache()).await;
+        let expected_error = ApiError::NotFound(format!("User {} not found", uuid));
         assert!(response.is_err());
         assert_eq!(response.unwrap_err(), expected_error);
     }
 
     #[actix_rt::test]
     async fn it_gets_all_users() {
-        let response = get_users(get_data_pool()).await;
+        let users = get_data_user_repository();
+        let first_user = get_first_user(&users);
+        let response = get_users(users, get_cache()).await;
         assert!(response.is_ok());
-        assert_eq!(response.unwrap().into_inner().0[0], get_all_users().0[0]);
+        assert_eq!(response.unwrap().into_inner().0[0], first_user);
     }
 
     #[actix_rt::test]
     async fn it_creates_a_user() {
-        let params = Json(CreateUserRequest {
+        let params = CreateUserRequest {
             first_name: "Satoshi".into(),
             last_name: "Nakamoto".into(),
             email: "satoshi@nakamotoinstitute.org".into(),
             password: "123456".into(),
-        });
-        let response = create_user(get_data_pool(), Json(params.clone()))
-            .await
-            .unwrap();
+        };
+        let response = create_user(
+            get_data_user_repository(),
+            ValidatedJson(params.clone()),
+            get_cache(),
+        )
+        .await
+        .unwrap();
         assert_eq!(response.into_inner().first_name, params.first_name);
     }
 
+    #[actix_rt::test]
+    async fn it_doesnt_create_a_user_with_a_taken_email() {
+        let users = get_data_user_repository();
+        let first_user = get_first_user(&users);
+        let params = ValidatedJson(CreateUserRequest {
+            first_name: "Satoshi".into(),
+            last_name: "Nakamoto".into(),
+            email: first_user.email,
+            password: "nakamoto".into(),
+        });
+        let response = create_user(users, params, get_cache()).await;
+        match response.unwrap_err() {
+            ApiError::ValidationError(errors) => {
+                assert_eq!(errors["email"][0].code, "unique");
+                assert_eq!(errors["password"][0].code, "password_personal");
+            }
+            error => panic!("Unexpected error: {:?}", error),
+        }
+    }
+
     #[actix_rt::test]
     async fn it_updates_a_user() {
-        let first_user = &get_all_users().0[0];
-        let user_id: Path<Uuid> = get_first_users_id().into();
-        let params = Json(UpdateUserRequest {
-            first_name: first_user.first_name.clone(),
+        let users = get_data_user_repository();
+        let first_user = get_first_user(&users);
+        let user_id: Path<Uuid> = first_user.id.into();
+        let params = UpdateUserRequest {
+            first_name: "Updated".into(),
             last_name: first_user.last_name.clone(),
             email: first_user.email.clone(),
-        });
-        let response = update_user(user_id, get_data_pool(), Json(params.clone()))
+        };
+        let response = update_user(user_id, users, ValidatedJson(params.clone()), get_cache())
             .await
             .unwrap();
         assert_eq!(response.into_inner().first_name, params.first_name);
@@ -219,13 +294,51 @@ pub mod tests {
 
     #[actix_rt::test]
     async fn it_deletes_a_user() {
-        let created = model_create_user();
-        let user_id = created.unwrap().id;
+        let users = get_data_user_repository();
+        let user_id = get_first_user(&users).id;
         let user_id_path: Path<Uuid> = user_id.into();
-        let user = find(&get_pool(), user_id);
-        assert!(user.is_ok());
-        delete_user(user_id_path, get_data_pool()).await.unwrap();
-        let user = find(&get_pool(), user_id);
-        assert!(user.is_err());
+        assert!(users.find(user_id).is_ok());
+        delete_user(user_id_path, users.clone(), get_cache())
+            .await
+            .unwrap();
+        assert!(users.find(user_id).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_caches_a_user_until_it_is_updated() {
+        let users = get_data_user_repository();
+        let cache = get_cache();
+        let first_user = get_first_user(&users);
+        crate::cache::delete(cache.clone(), &user_key(first_user.id))
+            .await
+            .unwrap();
+        get_user(first_user.id.into(), users.clone(), cache.clone())
+            .await
+            .unwrap();
+
+        // Bypass the handlers so that only the cache has the old name
+        let stale_user = UpdateUser {
+            id: first_user.id,
+            first_name: "Stale".into(),
+            last_name: first_user.last_name.clone(),
+            email: first_user.email.clone(),
+            updated_by: first_user.id,
+        };
+        users.update(&stale_user).unwrap();
+        let cached_user = get_user(first_user.id.into(), users.clone(), cache.clone())
+            .await
+            .unwrap();
+        assert_eq!(cached_user.into_inner().first_name, first_user.first_name);
+
+        let params = ValidatedJson(UpdateUserRequest {
+            first_name: "Updated".into(),
+            last_name: first_user.last_name.clone(),
+            email: first_user.email.clone(),
+        });
+        update_user(first_user.id.into(), users.clone(), params, cache.clone())
+            .await
+            .unwrap();
+        let updated_user = get_user(first_user.id.into(), users, cache).await.unwrap();
+        assert_eq!(updated_user.into_inner().first_name, "Updated");
     }
 }
@@ -0,0 +1,271 @@
+//! Message catalogs and locale negotiation
+//!
+//! Catalogs live in `locales/{locale}.json`, keyed by `errors.{code}`,
+//! `titles.{code}` and `validation.{code}`. Messages interpolate `{name}`
+//! arguments. A message missing from a locale falls back to English.
+
+use crate::errors::Problem;
+use crate::validate::FieldError;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// The locale of messages when no other can be negotiated
+pub const DEFAULT_LOCALE: &str = "en";
+
+type Catalog = HashMap<String, String>;
+
+lazy_static! {
+    static ref CATALOGS: HashMap<&'static str, Catalog> = {
+        let mut catalogs = HashMap::new();
+        catalogs.insert("en", load(include_str!("../locales/en.json")));
+        catalogs.insert("es", load(include_str!("../locales/es.json")));
+        catalogs
+    };
+}
+
+fn load(json: &str) -> Catalog {
+    serde_json::from_str(json).expect("Invalid message catalog")
+}
+
+/// Pick the supported locale the client prefers from an `Accept-Language` header
+///
+/// e.g. `es-MX,es;q=0.9,en;q=0.8` negotiates `es`
+pub fn negotiate(accept_language: Option<&str>) -> &'static str {
+    let mut ranges = accept_language
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim().to_lowercase();
+            let quality = parts
+                .map(str::trim)
+                .find(|param| param.starts_with("q="))
+                .map_or(Some(1.0), |quality| quality[2..].trim().parse::<f32>().ok())?;
+            Some((tag, quality))
+        })
+        .filter(|(tag, quality)| !tag.is_empty() && *quality > 0.0)
+        .collect::<Vec<_>>();
+
+    // Stable, so equally preferred ranges keep the client's order
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    ranges
+        .iter()
+        .find_map(|(tag, _)| supported(tag).or_else(|| supported(tag.split('-').next()?)))
+        .unwrap_or(DEFAULT_LOCALE)
+}
+
+fn supported(tag: &str) -> Option<&'static str> {
+    CATALOGS.keys().find(|locale| **locale == tag).copied()
+}
+
+/// A message of the locale, or of English if the locale doesn't have it
+pub fn translate(locale: &str, key: &str, args: &HashMap<String, Value>) -> Option<String> {
+    let message = CATALOGS
+        .get(locale)
+        .and_then(|catalog| catalog.get(key))
+        .or_else(|| CATALOGS[DEFAULT_LOCALE].get(key))?;
+    Some(interpolate(message, args))
+}
+
+/// The message of a failed validation, named by its code
+///
+/// Length and range validations have a message for each of their bounds.
+pub fn field_message(locale: &str, field: &str, error: &FieldError) -> Option<String> {
+    let mut args = error.params.clone();
+    args.insert("field".into(), field.into());
+
+    let has = |param: &str| error.params.contains_key(param);
+    let bound = if has("min") && has("max") {
+        Some("between")
+    } else if has("min") {
+        Some("min")
+    } else if has("max") {
+        Some("max")
+    } else if has("equal") {
+        Some("equal")
+    } else {
+        None
+    };
+    bound
+        .and_then(|bound| {
+            let key = format!("validation.{}.{}", error.code, bound);
+            translate(locale, &key, &args)
+        })
+        .or_else(|| translate(locale, &format!("validation.{}", error.code), &args))
+}
+
+/// Render the title, detail and field errors of problem details in the locale
+///
+/// Specific English details like `User 1 not found`, whose code's English
+/// message is just `{detail}`, are kept rather than replaced with a generic
+/// message. So are field messages that don't come from the catalog, such as
+/// a validator's own `message`.
+pub fn localize(problem: &mut Problem, locale: &str) {
+    let mut args = HashMap::new();
+    args.insert("detail".to_string(), Value::from(problem.detail.clone()));
+
+    if let Some(title) = translate(locale, &format!("titles.{}", problem.code), &args) {
+        problem.title = title;
+    }
+    let key = format!("errors.{}", problem.code);
+    let specific = matches!(
+        CATALOGS[DEFAULT_LOCALE].get(&key),
+        Some(message) if message.contains("{detail}")
+    );
+    if !specific {
+        if let Some(detail) = translate(locale, &key, &args) {
+            problem.detail = detail;
+        }
+    }
+    for (field, errors) in problem.errors.iter_mut() {
+        for error in errors.iter_mut() {
+            let default = field_message(DEFAULT_LOCALE, field, error);
+            if default.as_ref() != Some(&error.message) {
+                continue;
+            }
+            if let Some(message) = field_message(locale, field, error) {
+                error.message = message;
+            }
+        }
+    }
+}
+
+/// Replace `{name}` with the argument of that name
+///
+/// A single pass, so arguments containing `{name}` are left as they are.
+/// Unknown names are kept.
+fn interpolate(message: &str, args: &HashMap<String, Value>) -> String {
+    let mut rendered = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        let value = placeholder
+            .find('}')
+            .and_then(|end| Some((end, args.get(&placeholder[1..end])?)));
+        match value {
+            Some((end, value)) => {
+                match value {
+                    Value::String(value) => rendered.push_str(value),
+                    value => rendered.push_str(&value.to_string()),
+                }
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &placeholder[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ApiError;
+    use crate::validate::FieldErrors;
+    use serde_json::json;
+
+    fn length_error(params: Value) -> FieldError {
+        FieldError {
+            code: "length".into(),
+            message: "".into(),
+            params: serde_json::from_value(params).unwrap(),
+        }
+    }
+
+    #[test]
+    fn it_negotiates_a_locale() {
+        assert_eq!(negotiate(Some("es")), "es");
+        assert_eq!(negotiate(Some("es-MX,en;q=0.8")), "es");
+        assert_eq!(negotiate(Some("en;q=0.5, es;q=0.9")), "es");
+        assert_eq!(negotiate(Some("de, es;q=0.1")), "es");
+    }
+
+    #[test]
+    fn it_falls_back_to_english() {
+        assert_eq!(negotiate(None), DEFAULT_LOCALE);
+        assert_eq!(negotiate(Some("de")), DEFAULT_LOCALE);
+        assert_eq!(negotiate(Some("es;q=0")), DEFAULT_LOCALE);
+        assert_eq!(negotiate(Some("*")), DEFAULT_LOCALE);
+        assert_eq!(negotiate(Some(";;,")), DEFAULT_LOCALE);
+    }
+
+    #[test]
+    fn it_loads_every_catalog() {
+        for catalog in CATALOGS.values() {
+            assert!(!catalog.is_empty());
+        }
+    }
+
+    #[test]
+    fn it_renders_field_messages_by_bound() {
+        let min = length_error(json!({"min": 3}));
+        let between = length_error(json!({"min": 3, "max": 10}));
+        assert_eq!(
+            field_message("en", "first_name", &min),
+            Some("first_name must be at least 3 characters".into())
+        );
+        assert_eq!(
+            field_message("es", "first_name", &between),
+            Some("first_name debe tener entre 3 y 10 caracteres".into())
+        );
+    }
+
+    #[test]
+    fn it_doesnt_render_unknown_codes() {
+        let error = FieldError {
+            code: "custom".into(),
+            message: "custom message".into(),
+            params: HashMap::new(),
+        };
+        assert_eq!(field_message("es", "field", &error), None);
+    }
+
+    #[test]
+    fn it_localizes_problem_details() {
+        let mut problem = ApiError::NotFound("User 1 not found".into()).problem();
+        localize(&mut problem, "en");
+        assert_eq!(problem.title, "Not Found");
+        assert_eq!(problem.detail, "User 1 not found");
+
+        localize(&mut problem, "es");
+        assert_eq!(problem.title, "No encontrado");
+        assert_eq!(problem.detail, "User 1 not found");
+
+        let mut problem = ApiError::ValidationError(FieldErrors::new()).problem();
+        localize(&mut problem, "es");
+        assert_eq!(problem.detail, "La solicitud no es válida");
+    }
+
+    #[test]
+    fn it_keeps_messages_that_arent_from_the_catalog() {
+        let mut errors = FieldErrors::new();
+        errors.insert(
+            "email".into(),
+            vec![FieldError {
+                code: "email".into(),
+                message: "Use your work email".into(),
+                params: HashMap::new(),
+            }],
+        );
+        let mut problem = ApiError::ValidationError(errors).problem();
+        localize(&mut problem, "es");
+        assert_eq!(problem.errors["email"][0].message, "Use your work email");
+    }
+
+    #[test]
+    fn it_interpolates_in_a_single_pass() {
+        let mut args = HashMap::new();
+        args.insert("detail".to_string(), json!("{field} {missing"));
+        args.insert("field".to_string(), json!("email"));
+        args.insert("min".to_string(), json!(3));
+        assert_eq!(
+            interpolate("{field}: {detail} {min} {other}", &args),
+            "email: {field} {missing 3 {other}"
+        );
+    }
+}
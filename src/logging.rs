@@ -0,0 +1,83 @@
+//! Log lines as text or JSON, set by `LOG_FORMAT`
+//!
+//! JSON lines have the time, level and target of the record. Messages that
+//! are themselves JSON objects, like the access log's, are merged into the
+//! line, any other message is kept under `message`.
+//!
+//! Levels are still filtered by `RUST_LOG`.
+
+use crate::config::CONFIG;
+use chrono::Utc;
+use log::Record;
+use serde_json::{json, Value};
+use std::io::Write;
+
+/// How log lines are written
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// env_logger's lines
+    Text,
+    /// A JSON object per line
+    Json,
+}
+
+/// Start the logger in the configured format
+pub fn init() {
+    let mut builder = env_logger::Builder::from_default_env();
+    if CONFIG.log_format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_line(record)));
+    }
+    builder.init();
+}
+
+fn json_line(record: &Record) -> Value {
+    let mut line = json!({
+        "time": Utc::now().to_rfc3339(),
+        "level": record.level().to_string(),
+        "target": record.target(),
+    });
+    let message = record.args().to_string();
+    match serde_json::from_str(&message) {
+        Ok(Value::Object(fields)) => {
+            for (key, value) in fields {
+                line[key] = value;
+            }
+        }
+        _ => line["message"] = Value::from(message),
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    fn line(message: &str) -> Value {
+        json_line(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .level(Level::Info)
+                .target("test")
+                .build(),
+        )
+    }
+
+    #[test]
+    fn it_writes_a_message_as_json() {
+        let line = line("Starting 4 workers");
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], "test");
+        assert_eq!(line["message"], "Starting 4 workers");
+        assert!(line["time"].is_string());
+    }
+
+    #[test]
+    fn it_merges_json_messages() {
+        let line = line(r#"{"request_id": "abc-123", "status": 200}"#);
+        assert_eq!(line["request_id"], "abc-123");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line.get("message"), None);
+    }
+}
@@ -1,6 +1,8 @@
 #[macro_use]
 extern crate diesel;
 #[macro_use]
+extern crate diesel_migrations;
+#[macro_use]
 extern crate lazy_static;
 #[macro_use]
 extern crate redis_async;
@@ -19,16 +21,31 @@ mod errors;
 mod extractors;
 pub mod handlers;
 mod helpers;
+mod locale;
+mod logging;
+mod metrics;
 mod middleware;
 mod models;
+mod repositories;
 mod routes;
 mod schema;
 mod server;
+mod session;
+mod shutdown;
+mod sql_types;
 mod state;
+mod telemetry;
+mod tls;
 mod tests;
+mod transaction;
 mod validate;
+mod validators;
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
+    if let Err(error) = config::init() {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
     server().await
 }
@@ -0,0 +1,454 @@
+//! Prometheus metrics, rendered in the text exposition format
+//!
+//! Counters and histograms are recorded as requests are served, and gauges
+//! (pools, cache, process stats and the build) are read when `/metrics` is
+//! scraped.
+//!
+//! Every metric lives in a lazy_static, so they're shared by all workers.
+
+use crate::cache::stats;
+use chrono::{TimeZone, Utc};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+/// Content type of the text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Latency buckets in seconds, the defaults of the Prometheus clients
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: Counter = Counter::new(
+        "http_requests_total",
+        "HTTP requests by method, matched route and status class",
+        &["method", "route", "status"],
+    );
+    pub static ref HTTP_REQUEST_DURATION: Histogram = Histogram::new(
+        "http_request_duration_seconds",
+        "HTTP request latencies by method, matched route and status class",
+        &["method", "route", "status"],
+        LATENCY_BUCKETS,
+    );
+    pub static ref DB_POOL_WAIT: Histogram = Histogram::new(
+        "db_pool_wait_seconds",
+        "Time spent waiting to check a connection out of the pool",
+        &[],
+        LATENCY_BUCKETS,
+    );
+    pub static ref DB_POOL_TIMEOUTS: Counter = Counter::new(
+        "db_pool_timeouts_total",
+        "Connection checkouts that timed out",
+        &[],
+    );
+    pub static ref REDIS_COMMAND_DURATION: Histogram = Histogram::new(
+        "redis_command_duration_seconds",
+        "Redis command latencies by command",
+        &["command"],
+        LATENCY_BUCKETS,
+    );
+    pub static ref LOGINS: Counter =
+        Counter::new("logins_total", "Login attempts by result", &["result"]);
+    static ref POOLS: Mutex<Vec<Box<dyn Fn() -> r2d2::State + Send>>> = Mutex::new(Vec::new());
+}
+
+/// A counter for each combination of label values
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl Counter {
+    pub fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Increment the counter of the label values
+    pub fn inc(&self, values: &[&str]) {
+        let mut counters = self.values.lock().expect("Could not acquire lock");
+        *counters.entry(label_values(values)).or_insert(0) += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        let counters = self.values.lock().expect("Could not acquire lock");
+        for (values, count) in counters.iter() {
+            let labels = labels(self.labels, values, None);
+            writeln!(out, "{}{} {}", self.name, labels, count).ok();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Observations {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// A histogram for each combination of label values
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    buckets: &'static [f64],
+    values: Mutex<BTreeMap<Vec<String>, Observations>>,
+}
+
+impl Histogram {
+    pub fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+        buckets: &'static [f64],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            buckets,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record an observation, e.g. a latency in seconds
+    pub fn observe(&self, values: &[&str], value: f64) {
+        let mut histograms = self.values.lock().expect("Could not acquire lock");
+        let observations = histograms
+            .entry(label_values(values))
+            .or_insert_with(|| Observations {
+                buckets: vec![0; self.buckets.len()],
+                ..Default::default()
+            });
+        for (bucket, count) in self.buckets.iter().zip(observations.buckets.iter_mut()) {
+            if value <= *bucket {
+                *count += 1;
+            }
+        }
+        observations.sum += value;
+        observations.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        let histograms = self.values.lock().expect("Could not acquire lock");
+        for (values, observations) in histograms.iter() {
+            // Buckets are cumulative, each counts the observations up to its bound
+            for (bucket, count) in self.buckets.iter().zip(observations.buckets.iter()) {
+                let labels = labels(self.labels, values, Some(&bucket.to_string()));
+                writeln!(out, "{}_bucket{} {}", self.name, labels, count).ok();
+            }
+            let inf = labels(self.labels, values, Some("+Inf"));
+            writeln!(out, "{}_bucket{} {}", self.name, inf, observations.count).ok();
+            let labels = labels(self.labels, values, None);
+            writeln!(out, "{}_sum{} {}", self.name, labels, observations.sum).ok();
+            writeln!(out, "{}_count{} {}", self.name, labels, observations.count).ok();
+        }
+    }
+}
+
+/// Report the connections of a pool in the pool gauges
+///
+/// Each worker has its own pools, so the gauges are summed across pools.
+pub fn register_pool<M: r2d2::ManageConnection>(pool: &r2d2::Pool<M>) {
+    let pool = pool.clone();
+    let mut pools = POOLS.lock().expect("Could not acquire lock");
+    pools.push(Box::new(move || pool.state()));
+}
+
+/// Drop the registered pools, so that their connections close with the workers'
+pub fn unregister_pools() {
+    POOLS.lock().expect("Could not acquire lock").clear();
+}
+
+/// Records how long checkouts from a pool wait, set as its event handler
+#[derive(Debug)]
+pub struct PoolEvents;
+
+impl r2d2::HandleEvent for PoolEvents {
+    fn handle_checkout(&self, event: r2d2::event::CheckoutEvent) {
+        DB_POOL_WAIT.observe(&[], event.duration().as_secs_f64());
+    }
+
+    fn handle_timeout(&self, _: r2d2::event::TimeoutEvent) {
+        DB_POOL_TIMEOUTS.inc(&[]);
+    }
+}
+
+/// Render every metric in the text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+    HTTP_REQUESTS.render(&mut out);
+    HTTP_REQUEST_DURATION.render(&mut out);
+    LOGINS.render(&mut out);
+    REDIS_COMMAND_DURATION.render(&mut out);
+
+    let (connections, idle) = POOLS
+        .lock()
+        .expect("Could not acquire lock")
+        .iter()
+        .map(|state| state())
+        .fold((0, 0), |(connections, idle), state| {
+            (
+                connections + state.connections,
+                idle + state.idle_connections,
+            )
+        });
+    gauge(
+        &mut out,
+        "db_pool_connections",
+        "Open connections",
+        connections,
+    );
+    gauge(
+        &mut out,
+        "db_pool_idle_connections",
+        "Idle connections",
+        idle,
+    );
+    DB_POOL_WAIT.render(&mut out);
+    DB_POOL_TIMEOUTS.render(&mut out);
+
+    let cache = stats();
+    header(
+        &mut out,
+        "cache_hits_total",
+        "Read-through cache hits",
+        "counter",
+    );
+    writeln!(out, "cache_hits_total {}", cache.hits).ok();
+    header(
+        &mut out,
+        "cache_misses_total",
+        "Read-through cache misses",
+        "counter",
+    );
+    writeln!(out, "cache_misses_total {}", cache.misses).ok();
+
+    build_info(&mut out);
+    process::render(&mut out);
+    out
+}
+
+/// The build recorded by build.rs, as labels of a gauge that's always 1
+fn build_info(out: &mut String) {
+    let timestamp = env!("BUILD_TIMESTAMP").parse().unwrap_or_default();
+    let values = [
+        env!("CARGO_PKG_VERSION").to_string(),
+        env!("GIT_SHA").to_string(),
+        Utc.timestamp(timestamp, 0).to_rfc3339(),
+        env!("FEATURES").to_string(),
+    ];
+    let names = ["version", "git_sha", "build_time", "features"];
+    header(out, "build_info", "The build of the server", "gauge");
+    writeln!(out, "build_info{} 1", labels(&names, &values, None)).ok();
+}
+
+fn gauge<T: std::fmt::Display>(out: &mut String, name: &str, help: &str, value: T) {
+    header(out, name, help, "gauge");
+    writeln!(out, "{} {}", name, value).ok();
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}", name, help).ok();
+    writeln!(out, "# TYPE {} {}", name, kind).ok();
+}
+
+fn label_values(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+/// Format label pairs, with the `le` label of a histogram bucket if any
+fn labels(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect::<Vec<_>>();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        return "".into();
+    }
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Stats of the current process, read from /proc
+#[cfg(target_os = "linux")]
+mod process {
+    use super::{gauge, header};
+    use std::fmt::Write;
+    use std::fs;
+
+    /// Clock ticks per second of /proc/self/stat times, 100 on every common kernel
+    const TICKS_PER_SECOND: f64 = 100.0;
+
+    pub fn render(out: &mut String) {
+        if let Ok(stat) = fs::read_to_string("/proc/self/stat") {
+            // The command name may contain spaces, so fields are counted after it
+            let fields = stat
+                .rsplit(')')
+                .next()
+                .unwrap_or_default()
+                .split_whitespace()
+                .collect::<Vec<_>>();
+            let field = |index: usize| {
+                fields
+                    .get(index)
+                    .and_then(|value| value.parse::<f64>().ok())
+                    .unwrap_or_default()
+            };
+            // utime and stime, the 14th and 15th fields
+            let cpu = (field(11) + field(12)) / TICKS_PER_SECOND;
+            header(
+                out,
+                "process_cpu_seconds_total",
+                "CPU time in seconds",
+                "counter",
+            );
+            writeln!(out, "process_cpu_seconds_total {}", cpu).ok();
+            // starttime, the 22nd field, is in ticks since boot
+            if let Some(boot_time) = boot_time() {
+                let start_time = boot_time + field(19) / TICKS_PER_SECOND;
+                gauge(
+                    out,
+                    "process_start_time_seconds",
+                    "Start time since the epoch in seconds",
+                    start_time,
+                );
+            }
+            gauge(out, "process_threads", "OS threads", field(17));
+        }
+
+        if let Ok(status) = fs::read_to_string("/proc/self/status") {
+            let kilobytes = |key: &str| {
+                status
+                    .lines()
+                    .find(|line| line.starts_with(key))
+                    .and_then(|line| line.split_whitespace().nth(1))
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .unwrap_or_default()
+            };
+            let resident = kilobytes("VmRSS:") * 1024;
+            let virtual_memory = kilobytes("VmSize:") * 1024;
+            gauge(
+                out,
+                "process_resident_memory_bytes",
+                "Resident memory in bytes",
+                resident,
+            );
+            gauge(
+                out,
+                "process_virtual_memory_bytes",
+                "Virtual memory in bytes",
+                virtual_memory,
+            );
+        }
+
+        if let Ok(fds) = fs::read_dir("/proc/self/fd") {
+            gauge(
+                out,
+                "process_open_fds",
+                "Open file descriptors",
+                fds.count(),
+            );
+        }
+    }
+
+    /// Seconds since the epoch when the system booted
+    fn boot_time() -> Option<f64> {
+        fs::read_to_string("/proc/stat")
+            .ok()?
+            .lines()
+            .find(|line| line.starts_with("btime"))?
+            .split_whitespace()
+            .nth(1)?
+            .parse()
+            .ok()
+    }
+}
+
+/// Process stats are only read on Linux
+#[cfg(not(target_os = "linux"))]
+mod process {
+    pub fn render(_: &mut String) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_renders_counters() {
+        let counter = Counter::new("test_total", "Test counter", &["method", "route"]);
+        counter.inc(&["GET", "/user/{id}"]);
+        counter.inc(&["GET", "/user/{id}"]);
+        let mut out = String::new();
+        counter.render(&mut out);
+        assert_eq!(
+            out,
+            "# HELP test_total Test counter\n\
+             # TYPE test_total counter\n\
+             test_total{method=\"GET\",route=\"/user/{id}\"} 2\n"
+        );
+    }
+
+    #[test]
+    fn it_renders_cumulative_histogram_buckets() {
+        let histogram = Histogram::new("test_seconds", "Test histogram", &[], &[0.1, 1.0]);
+        histogram.observe(&[], 0.05);
+        histogram.observe(&[], 0.5);
+        histogram.observe(&[], 5.0);
+        let mut out = String::new();
+        histogram.render(&mut out);
+        assert_eq!(
+            out,
+            "# HELP test_seconds Test histogram\n\
+             # TYPE test_seconds histogram\n\
+             test_seconds_bucket{le=\"0.1\"} 1\n\
+             test_seconds_bucket{le=\"1\"} 2\n\
+             test_seconds_bucket{le=\"+Inf\"} 3\n\
+             test_seconds_sum 5.55\n\
+             test_seconds_count 3\n"
+        );
+    }
+
+    #[test]
+    fn it_escapes_label_values() {
+        let values = vec!["a \"quoted\"\\path\n".to_string()];
+        assert_eq!(
+            labels(&["route"], &values, None),
+            "{route=\"a \\\"quoted\\\"\\\\path\\n\"}"
+        );
+    }
+
+    #[test]
+    fn it_renders_every_metric() {
+        LOGINS.inc(&["success"]);
+        let out = render();
+        assert!(out.contains("logins_total{result=\"success\"}"));
+        assert!(out.contains("# TYPE db_pool_connections gauge"));
+        assert!(out.contains("# TYPE cache_hits_total counter"));
+        assert!(out.contains(&format!(
+            "build_info{{version=\"{}\"",
+            env!("CARGO_PKG_VERSION")
+        )));
+    }
+}
@@ -0,0 +1,205 @@
+//! Log each request, as text or as a JSON object by `LOG_FORMAT`
+//!
+//! Lines have the request id, the logged in user's id, the matched route,
+//! the status and the latency. They're logged under the `access` target.
+//!
+//! Wrap it inside RequestId and the identity service, so that the request
+//! id and user are known.
+
+use crate::auth::{decode_jwt, request_jwt};
+use crate::config::CONFIG;
+use crate::logging::LogFormat;
+use crate::middleware::metrics::route;
+use crate::middleware::request_id::CurrentRequestId;
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    Error, HttpMessage,
+};
+use futures::{
+    future::{ok, Ready},
+    Future,
+};
+use log::info;
+use serde::Serialize;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use uuid::Uuid;
+
+/// A line of the access log
+#[derive(Debug, Serialize, PartialEq)]
+pub struct AccessEntry {
+    pub request_id: Option<String>,
+    pub user_id: Option<Uuid>,
+    pub method: String,
+    pub path: String,
+    pub route: String,
+    pub status: u16,
+    pub latency_ms: f64,
+    pub remote: Option<String>,
+}
+
+impl AccessEntry {
+    /// e.g. `127.0.0.1 "GET /api/v1/user/1" 200 1.500ms request_id=abc user_id=-`
+    fn to_text(&self) -> String {
+        format!(
+            "{} \"{} {}\" {} {:.3}ms request_id={} user_id={}",
+            self.remote.as_deref().unwrap_or("-"),
+            self.method,
+            self.path,
+            self.status,
+            self.latency_ms,
+            self.request_id.as_deref().unwrap_or("-"),
+            self.user_id
+                .map_or_else(|| "-".to_string(), |user_id| user_id.to_string()),
+        )
+    }
+
+    /// Log the entry in the `LOG_FORMAT`
+    fn log(&self) {
+        match CONFIG.log_format {
+            LogFormat::Text => info!(target: "access", "{}", self.to_text()),
+            LogFormat::Json => {
+                if let Ok(json) = serde_json::to_string(self) {
+                    info!(target: "access", "{}", json);
+                }
+            }
+        }
+    }
+}
+
+pub struct AccessLog;
+
+impl<S, B> Transform<S> for AccessLog
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AccessLogMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AccessLogMiddleware { service })
+    }
+}
+pub struct AccessLogMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for AccessLogMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let request_id = req
+            .extensions()
+            .get::<CurrentRequestId>()
+            .map(|request_id| request_id.0.clone());
+        let user_id = request_jwt(&req)
+            .and_then(|identity| decode_jwt(&identity).ok())
+            .map(|private_claim| private_claim.user_id);
+        let method = req.method().to_string();
+        let path = req.path().to_string();
+        let remote = req.connection_info().remote().map(String::from);
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            // An error returned instead of a response is rendered by actix
+            // with its status, but its route is unknown
+            let (route, status) = match &res {
+                Ok(res) => (route(res.request()), res.status()),
+                Err(error) => ("unknown".into(), error.as_response_error().status_code()),
+            };
+            let entry = AccessEntry {
+                request_id,
+                user_id,
+                method,
+                path,
+                route,
+                status: status.as_u16(),
+                latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+                remote,
+            };
+            entry.log();
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ApiError;
+    use crate::middleware::request_id::RequestId;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_rt::test]
+    async fn it_passes_the_response_through() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(AccessLog)
+                .wrap(RequestId)
+                .route("/user/{id}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/user/1").to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert!(response.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn it_passes_an_error_through() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(AccessLog)
+                .wrap_fn(|_, _| async {
+                    Err::<ServiceResponse, _>(ApiError::Unauthorized("Unauthorized".into()).into())
+                })
+                .route("/user/{id}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/user/1").to_request();
+        let error = app.call(req).await.err().unwrap();
+        assert_eq!(error.as_response_error().status_code(), 401);
+    }
+
+    #[test]
+    fn it_formats_an_entry() {
+        let entry = AccessEntry {
+            request_id: Some("abc-123".into()),
+            user_id: None,
+            method: "GET".into(),
+            path: "/api/v1/user/1".into(),
+            route: "/api/v1/user/{id}".into(),
+            status: 200,
+            latency_ms: 1.5,
+            remote: Some("127.0.0.1:54321".into()),
+        };
+        assert_eq!(
+            entry.to_text(),
+            "127.0.0.1:54321 \"GET /api/v1/user/1\" 200 1.500ms request_id=abc-123 user_id=-"
+        );
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["request_id"], "abc-123");
+        assert_eq!(json["route"], "/api/v1/user/{id}");
+        assert_eq!(json["user_id"], serde_json::Value::Null);
+    }
+}
@@ -1,15 +1,17 @@
-use crate::auth::{decode_jwt, PrivateClaim};
+use crate::auth::{decode_jwt, request_jwt, PrivateClaim};
 use crate::errors::ApiError;
-use actix_identity::RequestIdentity;
 use actix_service::{Service, Transform};
 use actix_web::{
     dev::{ServiceRequest, ServiceResponse},
-    Error, HttpResponse,
+    Error, ResponseError,
 };
 use futures::{Future, future::{ok, Ready}};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
+/// Paths that are reached before logging in
+const PUBLIC_PATHS: [&str; 2] = ["/api/v1/auth/login", "/api/v1/auth/token"];
+
 pub struct Auth;
 
 impl<S, B> Transform<S> for Auth
@@ -47,14 +49,15 @@ where
     }
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        let identity = RequestIdentity::get_identity(&req).unwrap_or("".into());
+        let identity = request_jwt(&req).unwrap_or_default();
         let private_claim: Result<PrivateClaim, ApiError> = decode_jwt(&identity);
         let is_logged_in = private_claim.is_ok();
-        let unauthorized = !is_logged_in && req.path() != "/api/v1/auth/login";
+        let unauthorized = !is_logged_in && !PUBLIC_PATHS.contains(&req.path());
 
         if unauthorized {
             return Box::pin(async move {    
-                Ok(req.into_response(HttpResponse::Unauthorized().finish().into_body()))
+                let error = ApiError::Unauthorized("Unauthorized".into());
+                Ok(req.into_response(error.error_response().into_body()))
             })
         }
 
@@ -0,0 +1,581 @@
+//! Allow cross-origin requests from the configured origins
+//!
+//! Origins are exact, e.g. `https://app.example.com`, or match every
+//! subdomain, e.g. `https://*.example.com`. Nothing is allowed by default.
+//!
+//! Preflight requests are answered here, before authentication, and rejected
+//! with 403 Forbidden when the origin, method or a header isn't allowed.
+//! Other requests from an origin that isn't allowed are served without CORS
+//! headers, so the browser keeps the response from the page.
+//!
+//! Scopes can have their own policy, matched by the longest path prefix.
+//! `CORS_PUBLIC_PATHS` opts prefixes into a public policy, allowing any
+//! origin without credentials:
+//!
+//! ```ignore
+//! Cors::new(CorsPolicy::from_config()).scope("/api/v1/public", CorsPolicy::public())
+//! ```
+
+use crate::config::CONFIG;
+use crate::errors::ApiError;
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{
+            HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+            ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+            ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+        },
+        Method,
+    },
+    Error, HttpResponse, ResponseError,
+};
+use futures::{
+    future::{ok, Ready},
+    Future,
+};
+use std::cmp::Reverse;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// Which cross-origin requests are allowed
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorsPolicy {
+    /// Exact origins, `scheme://*.domain` for every subdomain or `*` for any
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub expose_headers: Vec<String>,
+    /// Seconds browsers may cache a preflight
+    pub max_age: u64,
+    /// Send cookies cross-origin, which `*` never allows
+    pub allow_credentials: bool,
+}
+
+impl CorsPolicy {
+    /// The policy set by the `CORS_*` settings
+    pub fn from_config() -> Self {
+        Self {
+            allowed_origins: list(&CONFIG.cors_allowed_origins),
+            allowed_methods: list(&CONFIG.cors_allowed_methods),
+            allowed_headers: list(&CONFIG.cors_allowed_headers),
+            expose_headers: list(&CONFIG.cors_expose_headers),
+            max_age: CONFIG.cors_max_age,
+            allow_credentials: CONFIG.cors_allow_credentials,
+        }
+    }
+
+    /// The configured policy, opened to any origin without credentials
+    pub fn public() -> Self {
+        Self {
+            allowed_origins: vec!["*".into()],
+            allow_credentials: false,
+            ..Self::from_config()
+        }
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| origin_matches(allowed, origin))
+    }
+
+    /// Check a preflight, returning the headers that allow it
+    fn preflight(&self, origin: &str, request: &HeaderMap) -> Result<HeaderMap, ApiError> {
+        if !self.allows_origin(origin) {
+            return Err(forbidden(format!("Origin {} is not allowed", origin)));
+        }
+        let method = header(request, &ACCESS_CONTROL_REQUEST_METHOD);
+        if !self
+            .allowed_methods
+            .iter()
+            .any(|allowed| allowed == &method)
+        {
+            return Err(forbidden(format!("Method {} is not allowed", method)));
+        }
+        let requested = header(request, &ACCESS_CONTROL_REQUEST_HEADERS).to_lowercase();
+        for name in requested
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            if !self.allowed_headers.iter().any(|allowed| allowed == name) {
+                return Err(forbidden(format!("Header {} is not allowed", name)));
+            }
+        }
+
+        let mut headers = self.origin_headers(origin);
+        insert(
+            &mut headers,
+            ACCESS_CONTROL_ALLOW_METHODS,
+            &self.allowed_methods.join(", "),
+        );
+        if !self.allowed_headers.is_empty() {
+            insert(
+                &mut headers,
+                ACCESS_CONTROL_ALLOW_HEADERS,
+                &self.allowed_headers.join(", "),
+            );
+        }
+        insert(
+            &mut headers,
+            ACCESS_CONTROL_MAX_AGE,
+            &self.max_age.to_string(),
+        );
+        Ok(headers)
+    }
+
+    /// Whether responses depend on the Origin, which they do unless any
+    /// origin is allowed without credentials
+    ///
+    /// `*` can't be sent with credentials, so the origin is echoed instead.
+    fn echoes_origin(&self) -> bool {
+        self.allow_credentials || !self.allowed_origins.iter().any(|allowed| allowed == "*")
+    }
+
+    /// The headers of every response to an allowed origin
+    fn origin_headers(&self, origin: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if self.echoes_origin() {
+            insert(&mut headers, ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        } else {
+            insert(&mut headers, ACCESS_CONTROL_ALLOW_ORIGIN, "*");
+        }
+        if self.allow_credentials {
+            insert(&mut headers, ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+        headers
+    }
+}
+
+/// Apply a policy to every request, or another to a scope's
+#[derive(Clone, Debug)]
+pub struct Cors {
+    policy: Rc<CorsPolicy>,
+    scopes: Vec<(String, Rc<CorsPolicy>)>,
+}
+
+impl Cors {
+    pub fn new(policy: CorsPolicy) -> Self {
+        Self {
+            policy: Rc::new(policy),
+            scopes: Vec::new(),
+        }
+    }
+
+    /// The configured policy, and the public one under `CORS_PUBLIC_PATHS`
+    pub fn from_config() -> Self {
+        let mut cors = Self::new(CorsPolicy::from_config());
+        for prefix in list(&CONFIG.cors_public_paths) {
+            cors = cors.scope(&prefix, CorsPolicy::public());
+        }
+        cors
+    }
+
+    /// Apply a different policy to the paths under a prefix, e.g. `/api/v1/public`
+    pub fn scope(mut self, prefix: &str, policy: CorsPolicy) -> Self {
+        self.scopes
+            .push((prefix.trim_end_matches('/').into(), Rc::new(policy)));
+        // The longest prefix wins
+        self.scopes.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+        self
+    }
+
+    fn policy(&self, path: &str) -> Rc<CorsPolicy> {
+        self.scopes
+            .iter()
+            .find(|(prefix, _)| path == prefix || path.starts_with(&format!("{}/", prefix)))
+            .map_or_else(|| self.policy.clone(), |(_, policy)| policy.clone())
+    }
+}
+
+impl<S, B> Transform<S> for Cors
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CorsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CorsMiddleware {
+            service,
+            cors: self.clone(),
+        })
+    }
+}
+pub struct CorsMiddleware<S> {
+    service: S,
+    cors: Cors,
+}
+
+impl<S, B> Service for CorsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let policy = self.cors.policy(req.path());
+        // Same-origin requests don't need to send an Origin, but a cache
+        // must still tell their responses apart from cross-origin ones
+        let origin = match req.headers().get(ORIGIN).and_then(|o| o.to_str().ok()) {
+            Some(origin) => origin.to_string(),
+            None => {
+                let fut = self.service.call(req);
+                return Box::pin(async move {
+                    let mut res = fut.await?;
+                    if policy.echoes_origin() {
+                        vary(res.headers_mut());
+                    }
+                    Ok(res)
+                });
+            }
+        };
+
+        let preflight = req.method() == Method::OPTIONS
+            && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+        if preflight {
+            let response = match policy.preflight(&origin, req.headers()) {
+                Ok(headers) => {
+                    let mut response = HttpResponse::NoContent().finish();
+                    extend(response.headers_mut(), headers);
+                    response
+                }
+                Err(error) => error.error_response(),
+            };
+            let mut response = req.into_response(response.into_body());
+            vary(response.headers_mut());
+            return Box::pin(ok(response));
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if policy.allows_origin(&origin) {
+                let mut headers = policy.origin_headers(&origin);
+                if !policy.expose_headers.is_empty() {
+                    insert(
+                        &mut headers,
+                        ACCESS_CONTROL_EXPOSE_HEADERS,
+                        &policy.expose_headers.join(", "),
+                    );
+                }
+                extend(res.headers_mut(), headers);
+            }
+            vary(res.headers_mut());
+            Ok(res)
+        })
+    }
+}
+
+/// Whether an origin matches an allowed origin, ignoring case
+///
+/// `https://*.example.com` matches `https://api.example.com` but not
+/// `https://example.com`.
+fn origin_matches(allowed: &str, origin: &str) -> bool {
+    if allowed == "*" {
+        return true;
+    }
+    let (allowed, origin) = (allowed.to_lowercase(), origin.to_lowercase());
+    match allowed.find("://*.") {
+        Some(index) => {
+            let scheme = &allowed[..index + 3];
+            let domain = &allowed[index + 4..];
+            origin.starts_with(scheme)
+                && origin.ends_with(domain)
+                && origin.len() > scheme.len() + domain.len()
+        }
+        None => allowed == origin,
+    }
+}
+
+/// Trimmed entries of a comma separated setting, without empty ones
+fn list(values: &[String]) -> Vec<String> {
+    values
+        .iter()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+fn header(headers: &HeaderMap, name: &HeaderName) -> String {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn insert(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+fn extend(headers: &mut HeaderMap, other: HeaderMap) {
+    for (name, value) in other.iter() {
+        headers.insert(name.clone(), value.clone());
+    }
+}
+
+/// Caches must key responses by origin, since their CORS headers differ
+fn vary(headers: &mut HeaderMap) {
+    headers.append(VARY, HeaderValue::from_static("Origin"));
+}
+
+fn forbidden(message: String) -> ApiError {
+    ApiError::Forbidden(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{dev::Body, http::StatusCode, test, web, App};
+
+    fn policy() -> CorsPolicy {
+        CorsPolicy {
+            allowed_origins: vec![
+                "https://app.example.com".into(),
+                "https://*.example.org".into(),
+            ],
+            allowed_methods: vec!["GET".into(), "POST".into()],
+            allowed_headers: vec!["content-type".into(), "x-request-id".into()],
+            expose_headers: vec!["x-request-id".into()],
+            max_age: 600,
+            allow_credentials: true,
+        }
+    }
+
+    async fn call(cors: Cors, req: test::TestRequest) -> ServiceResponse<Body> {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(cors)
+                .route("/api/v1/user", web::get().to(HttpResponse::Ok))
+                .route("/api/v1/public", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        test::call_service(&mut app, req.to_request()).await
+    }
+
+    fn preflight(origin: &str, method: &str) -> test::TestRequest {
+        test::TestRequest::with_uri("/api/v1/user")
+            .method(Method::OPTIONS)
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, method)
+    }
+
+    fn get(response: &ServiceResponse<Body>, name: HeaderName) -> Option<&str> {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn it_answers_an_allowed_preflight() {
+        let req = preflight("https://app.example.com", "POST")
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, "Content-Type, X-Request-Id");
+        let response = call(Cors::new(policy()), req).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            get(&response, ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            get(&response, ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            Some("true")
+        );
+        assert_eq!(
+            get(&response, ACCESS_CONTROL_ALLOW_METHODS),
+            Some("GET, POST")
+        );
+        assert_eq!(
+            get(&response, ACCESS_CONTROL_ALLOW_HEADERS),
+            Some("content-type, x-request-id")
+        );
+        assert_eq!(get(&response, ACCESS_CONTROL_MAX_AGE), Some("600"));
+        assert_eq!(get(&response, VARY), Some("Origin"));
+    }
+
+    #[actix_rt::test]
+    async fn it_rejects_a_preflight_from_another_origin() {
+        let response = call(Cors::new(policy()), preflight("https://evil.com", "GET")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(get(&response, ACCESS_CONTROL_ALLOW_ORIGIN), None);
+    }
+
+    #[actix_rt::test]
+    async fn it_rejects_a_preflight_for_a_method_or_header_not_allowed() {
+        let response = call(
+            Cors::new(policy()),
+            preflight("https://app.example.com", "DELETE"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let req = preflight("https://app.example.com", "GET")
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, "x-not-allowed");
+        let response = call(Cors::new(policy()), req).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = test::read_body(response).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("x-not-allowed"));
+    }
+
+    #[actix_rt::test]
+    async fn it_allows_subdomains_of_a_wildcard_origin() {
+        let response = call(
+            Cors::new(policy()),
+            preflight("https://api.example.org", "GET"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = call(Cors::new(policy()), preflight("https://example.org", "GET")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn it_adds_headers_to_an_allowed_request() {
+        let req = test::TestRequest::get()
+            .uri("/api/v1/user")
+            .header(ORIGIN, "https://app.example.com");
+        let response = call(Cors::new(policy()), req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            get(&response, ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            get(&response, ACCESS_CONTROL_EXPOSE_HEADERS),
+            Some("x-request-id")
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_serves_other_origins_without_headers() {
+        let req = test::TestRequest::get()
+            .uri("/api/v1/user")
+            .header(ORIGIN, "https://evil.com");
+        let response = call(Cors::new(policy()), req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(get(&response, ACCESS_CONTROL_ALLOW_ORIGIN), None);
+
+        assert_eq!(get(&response, VARY), Some("Origin"));
+    }
+
+    #[actix_rt::test]
+    async fn it_varies_by_origin_without_one() {
+        let req = test::TestRequest::get().uri("/api/v1/user");
+        let response = call(Cors::new(policy()), req).await;
+        assert_eq!(get(&response, ACCESS_CONTROL_ALLOW_ORIGIN), None);
+        assert_eq!(get(&response, VARY), Some("Origin"));
+
+        let any = CorsPolicy {
+            allowed_origins: vec!["*".into()],
+            allow_credentials: false,
+            ..policy()
+        };
+        let req = test::TestRequest::get()
+            .uri("/api/v1/user")
+            .header(ORIGIN, "https://anywhere.com");
+        let response = call(Cors::new(any.clone()), req).await;
+        assert_eq!(get(&response, ACCESS_CONTROL_ALLOW_ORIGIN), Some("*"));
+        assert_eq!(get(&response, ACCESS_CONTROL_ALLOW_CREDENTIALS), None);
+
+        let req = test::TestRequest::get().uri("/api/v1/user");
+        let response = call(Cors::new(any), req).await;
+        assert_eq!(get(&response, VARY), None);
+    }
+
+    #[actix_rt::test]
+    async fn it_applies_a_scope_policy() {
+        let public = CorsPolicy {
+            allowed_origins: vec!["*".into()],
+            allow_credentials: false,
+            ..policy()
+        };
+        let cors = Cors::new(policy()).scope("/api/v1/public", public);
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/public")
+            .header(ORIGIN, "https://anywhere.com");
+        let response = call(cors.clone(), req).await;
+        assert_eq!(get(&response, ACCESS_CONTROL_ALLOW_ORIGIN), Some("*"));
+        assert_eq!(get(&response, ACCESS_CONTROL_ALLOW_CREDENTIALS), None);
+        let req = test::TestRequest::get().uri("/api/v1/public");
+        let response = call(cors.clone(), req).await;
+        assert_eq!(get(&response, VARY), None);
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/user")
+            .header(ORIGIN, "https://anywhere.com");
+        let response = call(cors.clone(), req).await;
+        assert_eq!(get(&response, ACCESS_CONTROL_ALLOW_ORIGIN), None);
+        let req = test::TestRequest::get().uri("/api/v1/user");
+        let response = call(cors, req).await;
+        assert_eq!(get(&response, VARY), Some("Origin"));
+    }
+
+    #[test]
+    fn it_matches_a_scope_by_the_longest_prefix() {
+        let public = CorsPolicy {
+            max_age: 1,
+            ..policy()
+        };
+        let nested = CorsPolicy {
+            max_age: 2,
+            ..policy()
+        };
+        let cors = Cors::new(policy())
+            .scope("/api/v1/public/", public)
+            .scope("/api/v1/public/nested", nested);
+        assert_eq!(cors.policy("/api/v1/public").max_age, 1);
+        assert_eq!(cors.policy("/api/v1/public/nested/1").max_age, 2);
+        assert_eq!(cors.policy("/api/v1/publicity").max_age, 600);
+    }
+
+    #[test]
+    fn it_matches_origins() {
+        assert!(origin_matches(
+            "https://app.example.com",
+            "HTTPS://App.Example.com"
+        ));
+        assert!(!origin_matches(
+            "https://app.example.com",
+            "http://app.example.com"
+        ));
+        assert!(origin_matches(
+            "https://*.example.com",
+            "https://a.b.example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://evilexample.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://example.com"
+        ));
+        assert!(origin_matches("*", "https://anywhere.com"));
+    }
+}
@@ -0,0 +1,281 @@
+//! Reject cross-site requests that ride on the identity cookie
+//!
+//! The token is an HMAC of the identity, keyed by `SESSION_KEY`, so it's
+//! tied to the login and changes with every one. Responses set it in a
+//! `csrf_token` cookie whenever it differs from the one sent, and remove the
+//! cookie at logout. Pages read it and send it back in the `X-CSRF-Token`
+//! header. Another site can't read the cookie, so it can't send the header,
+//! and a cookie it planted doesn't match the identity.
+//!
+//! Only requests that change state and authenticated via the identity cookie
+//! (or a session kept by it) are checked. Requests with an
+//! `Authorization: Bearer` token are exempt, as browsers never add one on
+//! their own.
+
+use crate::auth::bearer_token;
+use crate::config::CONFIG;
+use crate::errors::ApiError;
+use actix_identity::RequestIdentity;
+use actix_service::{Service, Transform};
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    dev::{ServiceRequest, ServiceResponse},
+    http::Method,
+    Error, HttpMessage, ResponseError,
+};
+use futures::{
+    future::{ok, Ready},
+    Future,
+};
+use ring::hmac;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// The cookie holding the token, readable by pages
+pub const CSRF_COOKIE: &str = "csrf_token";
+
+/// The header pages send the token back in
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+pub struct Csrf;
+
+impl<S, B> Transform<S> for Csrf
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CsrfMiddleware { service })
+    }
+}
+pub struct CsrfMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for CsrfMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let sent = req
+            .cookie(CSRF_COOKIE)
+            .map(|cookie| cookie.value().to_string())
+            .filter(|token| !token.is_empty());
+
+        if is_checked(&req) && !is_valid(&req) {
+            return Box::pin(async move {
+                let error = ApiError::Forbidden("Missing or invalid CSRF token".into());
+                Ok(req.into_response(error.error_response().into_body()))
+            });
+        }
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            // The identity after the handler, which logging in or out changes
+            let token = res
+                .request()
+                .get_identity()
+                .map(|identity| token(&identity));
+            if token != sent {
+                let cookie = cookie(token.as_deref().unwrap_or_default());
+                res.response_mut().add_cookie(&cookie)?;
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// Whether the request changes state with the identity cookie
+fn is_checked(req: &ServiceRequest) -> bool {
+    let safe = [Method::GET, Method::HEAD, Method::OPTIONS, Method::TRACE];
+    !safe.contains(req.method())
+        && bearer_token(req.headers()).is_none()
+        && req.get_identity().is_some()
+}
+
+/// Whether the header has the token of the request's identity
+fn is_valid(req: &ServiceRequest) -> bool {
+    let header = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match (req.get_identity(), header) {
+        (Some(identity), Some(header)) => {
+            constant_time_eq(token(&identity).as_bytes(), header.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+/// The token of an identity, as hex
+fn token(identity: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, CONFIG.session_key.as_bytes());
+    hmac::sign(&key, identity.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Compare without leaking how much of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The token cookie, or its removal when the token is empty
+fn cookie(token: &str) -> Cookie<'static> {
+    let mut cookie = Cookie::build(CSRF_COOKIE, token.to_string())
+        .path("/")
+        .secure(CONFIG.session_secure)
+        .same_site(SameSite::Strict);
+    if token.is_empty() {
+        cookie = cookie.max_age(0);
+    }
+    cookie.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::identity_service;
+    use crate::session::SessionStore;
+    use crate::tests::helpers::tests::get_cache;
+    use actix_identity::Identity;
+    use actix_web::{dev::Body, http::StatusCode, test, web, App, HttpResponse};
+
+    async fn call(req: test::TestRequest) -> ServiceResponse<Body> {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Csrf)
+                .wrap(identity_service(SessionStore::Cookie, get_cache()))
+                .route(
+                    "/login",
+                    web::get().to(|id: Identity| {
+                        id.remember("user".into());
+                        HttpResponse::Ok()
+                    }),
+                )
+                .route(
+                    "/relogin",
+                    web::get().to(|id: Identity| {
+                        id.remember("other".into());
+                        HttpResponse::Ok()
+                    }),
+                )
+                .route(
+                    "/logout",
+                    web::get().to(|id: Identity| {
+                        id.forget();
+                        HttpResponse::Ok()
+                    }),
+                )
+                .route("/user", web::get().to(HttpResponse::Ok))
+                .route("/user", web::delete().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let response =
+            test::call_service(&mut app, test::TestRequest::with_uri("/login").to_request()).await;
+        // Send the identity cookie, leaving the token cookie to each test
+        let identity = response
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() != CSRF_COOKIE)
+            .unwrap()
+            .into_owned();
+        test::call_service(&mut app, req.cookie(identity).to_request()).await
+    }
+
+    fn token_cookie(response: &ServiceResponse<Body>) -> Option<Cookie<'_>> {
+        response
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == CSRF_COOKIE)
+    }
+
+    #[actix_rt::test]
+    async fn it_sets_the_token_of_the_identity() {
+        let response = call(test::TestRequest::with_uri("/user")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookie = token_cookie(&response).unwrap();
+        assert_eq!(cookie.value(), token("user"));
+        assert_eq!(cookie.value().len(), 64);
+        assert_eq!(cookie.http_only(), None);
+
+        let req =
+            test::TestRequest::with_uri("/user").cookie(Cookie::new(CSRF_COOKIE, token("user")));
+        assert!(token_cookie(&call(req).await).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn it_rotates_the_token_at_login_and_removes_it_at_logout() {
+        let req =
+            test::TestRequest::with_uri("/relogin").cookie(Cookie::new(CSRF_COOKIE, token("user")));
+        let response = call(req).await;
+        assert_eq!(token_cookie(&response).unwrap().value(), token("other"));
+        assert_ne!(token("other"), token("user"));
+
+        let req =
+            test::TestRequest::with_uri("/logout").cookie(Cookie::new(CSRF_COOKIE, token("user")));
+        let response = call(req).await;
+        let cookie = token_cookie(&response).unwrap();
+        assert_eq!(cookie.value(), "");
+        assert!(cookie.to_string().contains("Max-Age=0"));
+    }
+
+    #[actix_rt::test]
+    async fn it_rejects_a_cookie_request_without_the_token() {
+        let req = test::TestRequest::delete().uri("/user");
+        assert_eq!(call(req).await.status(), StatusCode::FORBIDDEN);
+
+        // A double-submitted token that isn't the identity's
+        let req = test::TestRequest::delete()
+            .uri("/user")
+            .cookie(Cookie::new(CSRF_COOKIE, "token"))
+            .header(CSRF_HEADER, "token");
+        assert_eq!(call(req).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn it_accepts_a_cookie_request_with_the_token() {
+        let req = test::TestRequest::delete()
+            .uri("/user")
+            .cookie(Cookie::new(CSRF_COOKIE, token("user")))
+            .header(CSRF_HEADER, token("user"));
+        assert_eq!(call(req).await.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn it_exempts_bearer_token_requests() {
+        let req = test::TestRequest::delete()
+            .uri("/user")
+            .header("authorization", "Bearer token");
+        assert_eq!(call(req).await.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn it_compares_tokens() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"tok"));
+    }
+}
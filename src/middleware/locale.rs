@@ -0,0 +1,142 @@
+//! Render problem details in the locale negotiated from `Accept-Language`
+//!
+//! Wrap it inside RequestId, so that the request id is added to the
+//! localized problem.
+
+use crate::errors::Problem;
+use crate::locale::{localize, negotiate};
+use actix_service::{Service, Transform};
+use actix_web::{
+    body::{Body, ResponseBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderValue, ACCEPT_LANGUAGE, CONTENT_LANGUAGE},
+    Error,
+};
+use futures::{
+    future::{ok, Ready},
+    Future,
+};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub struct Localize;
+
+impl<S, B> Transform<S> for Localize
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = LocalizeMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(LocalizeMiddleware { service })
+    }
+}
+pub struct LocalizeMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for LocalizeMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let locale = negotiate(
+            req.headers()
+                .get(ACCEPT_LANGUAGE)
+                .and_then(|value| value.to_str().ok()),
+        );
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let problem = res.response().extensions().get::<Problem>().cloned();
+            if let Some(mut problem) = problem {
+                localize(&mut problem, locale);
+                if let Ok(json) = serde_json::to_string(&problem) {
+                    res = res.map_body(|_, _| ResponseBody::Other(Body::from(json)));
+                }
+                res.headers_mut()
+                    .insert(CONTENT_LANGUAGE, HeaderValue::from_static(locale));
+                // Keep the localized problem for outer middleware
+                res.response_mut().extensions_mut().insert(problem);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ApiError;
+    use crate::validate::{FieldError, FieldErrors};
+    use actix_web::{test, web, App, HttpResponse};
+    use std::collections::HashMap;
+
+    async fn problem(accept_language: &str) -> (String, Problem) {
+        let mut app = test::init_service(App::new().wrap(Localize).route(
+            "/",
+            web::get().to(|| async {
+                let mut errors = FieldErrors::new();
+                errors.insert(
+                    "email".into(),
+                    vec![FieldError {
+                        code: "email".into(),
+                        message: "email must be a valid email".into(),
+                        params: HashMap::new(),
+                    }],
+                );
+                Err::<HttpResponse, _>(ApiError::ValidationError(errors))
+            }),
+        ))
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header(ACCEPT_LANGUAGE, accept_language)
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+        let content_language = response.headers().get(CONTENT_LANGUAGE).unwrap();
+        let content_language = content_language.to_str().unwrap().to_string();
+        let body = test::read_body(response).await;
+        (content_language, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn it_localizes_problem_details() {
+        let (content_language, problem) = problem("es-ES,es;q=0.9").await;
+        assert_eq!(content_language, "es");
+        assert_eq!(problem.detail, "La solicitud no es válida");
+        assert_eq!(
+            problem.errors["email"][0].message,
+            "email debe ser un correo electrónico válido"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_falls_back_to_english() {
+        let (content_language, problem) = problem("de").await;
+        assert_eq!(content_language, "en");
+        assert_eq!(problem.detail, "The request is invalid");
+        assert_eq!(
+            problem.errors["email"][0].message,
+            "email must be a valid email"
+        );
+    }
+}
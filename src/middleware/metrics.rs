@@ -0,0 +1,190 @@
+//! Count and time requests for the Prometheus metrics
+//!
+//! Requests are labeled by their matched route pattern, e.g.
+//! `/api/v1/user/{id}`, so that ids don't blow up the number of series.
+//! Requests that didn't reach a route, e.g. 404s, static files or ones
+//! rejected by a scope's middleware, are labeled `unmatched`, and errors
+//! returned by inner middleware instead of a response are labeled `unknown`.
+
+use crate::metrics::{HTTP_REQUESTS, HTTP_REQUEST_DURATION};
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::StatusCode,
+    Error, HttpRequest,
+};
+use futures::{
+    future::{ok, Ready},
+    Future,
+};
+use std::fmt::Write;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+pub struct Metrics;
+
+impl<S, B> Transform<S> for Metrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = MetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(MetricsMiddleware { service })
+    }
+}
+pub struct MetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for MetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        // Routing needs to be the only owner of the request, so it isn't cloned
+        let method = req.method().to_string();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            let (route, status) = match &res {
+                Ok(res) => (route(res.request()), res.status()),
+                Err(error) => ("unknown".into(), error.as_response_error().status_code()),
+            };
+            let labels = [method.as_str(), &route, &status_class(status)];
+            HTTP_REQUESTS.inc(&labels);
+            HTTP_REQUEST_DURATION.observe(&labels, start.elapsed().as_secs_f64());
+            res
+        })
+    }
+}
+
+/// The label of requests that didn't reach a route
+pub const UNMATCHED: &str = "unmatched";
+
+/// The route pattern of a request, rebuilt from the parameters it matched
+///
+/// actix-web 2 doesn't expose the pattern. A route consumes the whole path,
+/// so a path with an unrouted remainder didn't reach one, and is `unmatched`.
+/// Each matched parameter is a slice of the path, which is replaced with its
+/// `{name}` by position.
+pub fn route(request: &HttpRequest) -> String {
+    let matched = request.match_info();
+    // The unrouted remainder, `unprocessed()` panics once a scope's
+    // pattern has skipped past the end
+    if !matched.path().is_empty() {
+        return UNMATCHED.into();
+    }
+    let path = matched.get_ref().path();
+    let start = path.as_ptr() as usize;
+    let mut params = matched
+        .iter()
+        .filter_map(|(name, value)| {
+            let from = (value.as_ptr() as usize).checked_sub(start)?;
+            let to = from + value.len();
+            if to > path.len() {
+                return None;
+            }
+            Some((from, to, name))
+        })
+        .collect::<Vec<_>>();
+    params.sort();
+
+    let mut pattern = String::with_capacity(path.len());
+    let mut end = 0;
+    for (from, to, name) in params {
+        if from < end {
+            continue;
+        }
+        pattern.push_str(&path[end..from]);
+        write!(pattern, "{{{}}}", name).ok();
+        end = to;
+    }
+    pattern.push_str(&path[end..]);
+    pattern
+}
+
+/// e.g. `2xx`
+fn status_class(status: StatusCode) -> String {
+    format!("{}xx", status.as_u16() / 100)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::render;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_rt::test]
+    async fn it_labels_requests_by_route_pattern() {
+        let mut app = test::init_service(App::new().wrap(Metrics).route(
+            "/metrics-test/{id}/sessions/{session_id}",
+            web::get().to(HttpResponse::Ok),
+        ))
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/metrics-test/1/sessions/2")
+            .to_request();
+        test::call_service(&mut app, req).await;
+
+        assert!(render().contains(
+            "http_requests_total{method=\"GET\",route=\"/metrics-test/{id}/sessions/{session_id}\",status=\"2xx\"}"
+        ));
+    }
+
+    async fn call(uri: &str) {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Metrics)
+                .service(
+                    web::scope("/metrics-scope/{id}")
+                        .wrap_fn(|req, _| async move {
+                            Ok(req.into_response(HttpResponse::Unauthorized().finish()))
+                        })
+                        .route("", web::get().to(HttpResponse::Ok)),
+                )
+                .route(
+                    "/metrics-missing/{id}/{id_copy}",
+                    web::get().to(HttpResponse::NotFound),
+                ),
+        )
+        .await;
+        let req = test::TestRequest::get().uri(uri).to_request();
+        test::call_service(&mut app, req).await;
+    }
+
+    #[actix_rt::test]
+    async fn it_labels_requests_that_dont_reach_a_route_unmatched() {
+        call("/metrics-not-a-route/1").await;
+        call("/metrics-scope/2/rejected").await;
+        let out = render();
+        assert!(!out.contains("metrics-not-a-route"));
+        assert!(!out.contains("rejected"));
+        assert!(out.contains("route=\"unmatched\",status=\"4xx\""));
+    }
+
+    #[actix_rt::test]
+    async fn it_labels_not_found_responses_of_a_route_by_its_pattern() {
+        call("/metrics-missing/3/3").await;
+        assert!(render().contains("route=\"/metrics-missing/{id}/{id_copy}\",status=\"4xx\""));
+    }
+}
@@ -1 +1,10 @@
-pub mod auth;
\ No newline at end of file
+pub mod access_log;
+pub mod auth;
+pub mod cors;
+pub mod csrf;
+pub mod locale;
+pub mod metrics;
+pub mod rate_limit;
+pub mod request_id;
+pub mod security_headers;
+pub mod tracing;
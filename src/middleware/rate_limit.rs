@@ -0,0 +1,418 @@
+//! Rate limit requests with a sliding window
+//!
+//! Requests are counted per fixed window in the application cache (Redis when
+//! `REDIS_URL` is set), so limits hold across instances. The count of the
+//! previous window is weighted by how much of it the sliding window still
+//! overlaps, which smooths out bursts at window boundaries.
+//!
+//! When the cache is missing or failing, requests are counted in process.
+//!
+//! Clients are identified by the connection's peer address. `X-Forwarded-For`
+//! is only honoured when the peer is one of `TRUSTED_PROXIES`, since anyone
+//! else could change it on every request to dodge the limit.
+
+use crate::auth::{decode_jwt, request_jwt};
+use crate::cache::memory::MemoryCache;
+use crate::cache::{Cache, CacheBackend};
+use crate::config::CONFIG;
+use crate::errors::ApiError;
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue, RETRY_AFTER},
+    Error, ResponseError,
+};
+use futures::{
+    future::{ok, Ready},
+    Future,
+};
+use log::warn;
+use std::cell::RefCell;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Header in which proxies append the address they received a request from
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+lazy_static! {
+    /// Counters used when the application cache is missing or failing
+    static ref FALLBACK: MemoryCache = MemoryCache::new(10_000);
+
+    /// Proxies whose `X-Forwarded-For` is trusted, checked at startup
+    static ref TRUSTED_PROXIES: Vec<IpAddr> = CONFIG
+        .trusted_proxies
+        .iter()
+        .filter_map(|ip| ip.parse().ok())
+        .collect();
+}
+
+/// What requests are counted by
+///
+/// Requests without a user are counted by IP.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateLimitBy {
+    Ip,
+    User,
+}
+
+/// Allow `limit` requests per `period` in a route scope
+///
+/// ```ignore
+/// web::scope("/auth").wrap(RateLimit::new("auth", CONFIG.rate_limit_auth, period()))
+/// ```
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    scope: &'static str,
+    limit: u64,
+    period: Duration,
+    by: RateLimitBy,
+}
+
+impl RateLimit {
+    /// Limit requests by IP, the scope names the counters
+    pub fn new(scope: &'static str, limit: u64, period: Duration) -> Self {
+        Self {
+            scope,
+            limit,
+            period,
+            by: RateLimitBy::Ip,
+        }
+    }
+
+    /// Count requests by something other than IP
+    pub fn by(mut self, by: RateLimitBy) -> Self {
+        self.by = by;
+        self
+    }
+}
+
+/// The configured period that limits are counted over
+pub fn period() -> Duration {
+    Duration::from_secs(CONFIG.rate_limit_period)
+}
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            config: self.clone(),
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    config: RateLimit,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let config = self.config.clone();
+        let client = client_key(&req, config.by);
+        let cache = req.app_data::<Box<dyn CacheBackend>>();
+
+        Box::pin(async move {
+            let usage = count(cache, &config, &client).await;
+
+            if usage.exceeded {
+                let error = ApiError::TooManyRequests(format!(
+                    "Rate limit of {} requests per {} seconds exceeded",
+                    config.limit,
+                    config.period.as_secs()
+                ));
+                let mut response = error.error_response();
+                usage.add_headers(response.headers_mut());
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(usage.reset));
+                return Ok(req.into_response(response.into_body()));
+            }
+
+            let fut = service.borrow_mut().call(req);
+            let mut res = fut.await?;
+            usage.add_headers(res.headers_mut());
+            Ok(res)
+        })
+    }
+}
+
+/// The state of a client's limit after counting a request
+#[derive(Debug, PartialEq)]
+struct Usage {
+    limit: u64,
+    remaining: u64,
+    /// Seconds until the current window ends
+    reset: u64,
+    exceeded: bool,
+}
+
+impl Usage {
+    /// Estimate the requests in the sliding window that ends `elapsed` into the current window
+    fn new(limit: u64, period: Duration, previous: u64, current: u64, elapsed: Duration) -> Self {
+        let overlap = 1.0 - elapsed.as_secs_f64() / period.as_secs_f64();
+        let estimate = (previous as f64 * overlap + current as f64).ceil() as u64;
+        let reset = (period - elapsed).as_secs_f64().ceil().max(1.0) as u64;
+        Self {
+            limit,
+            remaining: limit.saturating_sub(estimate),
+            reset,
+            exceeded: estimate > limit,
+        }
+    }
+
+    fn add_headers(&self, headers: &mut actix_web::http::HeaderMap) {
+        let headers_to_add = [
+            ("ratelimit-limit", self.limit),
+            ("ratelimit-remaining", self.remaining),
+            ("ratelimit-reset", self.reset),
+        ];
+        for (name, value) in headers_to_add.iter() {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(*value));
+        }
+    }
+}
+
+/// Count a request of the client, falling back to in-process counters
+async fn count(cache: Option<Cache>, config: &RateLimit, client: &str) -> Usage {
+    if let Some(cache) = cache {
+        match count_in(cache.get_ref().as_ref(), config, client).await {
+            Ok(usage) => return usage,
+            Err(error) => warn!("Rate limiting in process, cache unavailable: {:?}", error),
+        }
+    }
+
+    // An in-process counter can't fail, but never block requests if it does
+    count_in(&*FALLBACK, config, client)
+        .await
+        .unwrap_or_else(|_| Usage::new(config.limit, config.period, 0, 0, Duration::from_secs(0)))
+}
+
+async fn count_in(
+    cache: &dyn CacheBackend,
+    config: &RateLimit,
+    client: &str,
+) -> Result<Usage, ApiError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let period = config.period.as_secs().max(1);
+    let window = now.as_secs() / period;
+    let elapsed = now - Duration::from_secs(window * period);
+
+    // Keep each window for the whole next one, where it's the previous window
+    let ttl = Duration::from_secs(period * 2);
+    let current = cache
+        .increment(&counter_key(config.scope, client, window), ttl)
+        .await?;
+    let previous = cache
+        .get(&counter_key(config.scope, client, window - 1))
+        .await?
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+
+    Ok(Usage::new(
+        config.limit,
+        Duration::from_secs(period),
+        previous,
+        current,
+        elapsed,
+    ))
+}
+
+/// Cache key of a client's counter in a window
+///
+/// Counters aren't versioned, so bumping `CACHE_VERSION` doesn't reset limits.
+fn counter_key(scope: &str, client: &str, window: u64) -> String {
+    format!(
+        "{}:rate_limit:{}:{}:{}",
+        CONFIG.cache_prefix, scope, client, window
+    )
+}
+
+/// Identify the client of a request
+fn client_key(req: &ServiceRequest, by: RateLimitBy) -> String {
+    let client = match by {
+        RateLimitBy::Ip => None,
+        RateLimitBy::User => request_jwt(req)
+            .and_then(|identity| decode_jwt(&identity).ok())
+            .map(|private_claim| format!("user:{}", private_claim.user_id)),
+    };
+    client.unwrap_or_else(|| format!("ip:{}", client_ip(req, &TRUSTED_PROXIES)))
+}
+
+/// The peer's IP, or the client's that a trusted peer forwarded the request for
+///
+/// `X-Forwarded-For` is read from the right, skipping trusted proxies, since
+/// the entries left of the last one they appended are up to the client.
+fn client_ip(req: &ServiceRequest, trusted: &[IpAddr]) -> String {
+    let peer = match req.peer_addr() {
+        Some(peer) => peer.ip(),
+        None => return "unknown".into(),
+    };
+    if !trusted.contains(&peer) {
+        return peer.to_string();
+    }
+
+    let forwarded = req
+        .headers()
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+    for ip in forwarded.into_iter().rev() {
+        match ip.trim().parse::<IpAddr>() {
+            Ok(ip) if trusted.contains(&ip) => continue,
+            Ok(ip) => return ip.to_string(),
+            Err(_) => break,
+        }
+    }
+    peer.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::get_cache;
+    use actix_web::{test, web, App, HttpResponse};
+
+    const PERIOD: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn it_counts_the_current_window() {
+        let usage = Usage::new(10, PERIOD, 0, 4, Duration::from_secs(30));
+        assert_eq!(usage.remaining, 6);
+        assert_eq!(usage.reset, 30);
+        assert!(!usage.exceeded);
+    }
+
+    #[test]
+    fn it_weights_the_previous_window_by_its_overlap() {
+        let usage = Usage::new(10, PERIOD, 10, 4, Duration::from_secs(45));
+        assert_eq!(usage.remaining, 3);
+        let usage = Usage::new(10, PERIOD, 10, 4, Duration::from_secs(15));
+        assert_eq!(usage.remaining, 0);
+        assert!(usage.exceeded);
+    }
+
+    fn forwarded(peer: &str, forwarded_for: &str) -> ServiceRequest {
+        test::TestRequest::with_header(X_FORWARDED_FOR, forwarded_for)
+            .peer_addr(peer.parse().unwrap())
+            .to_srv_request()
+    }
+
+    #[test]
+    fn it_counts_by_the_peer_ip() {
+        let req = forwarded("10.0.0.1:52914", "1.2.3.4");
+        assert_eq!(client_ip(&req, &[]), "10.0.0.1");
+        let req = forwarded("[::1]:52914", "1.2.3.4");
+        assert_eq!(client_ip(&req, &[]), "::1");
+        let req = test::TestRequest::default().to_srv_request();
+        assert_eq!(client_ip(&req, &[]), "unknown");
+        assert!(client_key(&req, RateLimitBy::User).starts_with("ip:"));
+    }
+
+    #[test]
+    fn it_counts_by_the_forwarded_ip_behind_trusted_proxies() {
+        let trusted = ["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+        let req = forwarded("10.0.0.1:52914", "6.6.6.6, 1.2.3.4, 10.0.0.2");
+        assert_eq!(client_ip(&req, &trusted), "1.2.3.4");
+        let req = forwarded("10.0.0.1:52914", "not an ip");
+        assert_eq!(client_ip(&req, &trusted), "10.0.0.1");
+    }
+
+    #[actix_rt::test]
+    async fn it_limits_requests() {
+        let scope = "it_limits_requests";
+        let mut app = test::init_service(
+            App::new()
+                .app_data(get_cache())
+                .wrap(RateLimit::new(scope, 2, PERIOD))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for remaining in &["1", "0"] {
+            let req = test::TestRequest::get().uri("/").to_request();
+            let response = test::call_service(&mut app, req).await;
+            assert!(response.status().is_success());
+            let headers = response.headers();
+            assert_eq!(headers.get("ratelimit-limit").unwrap(), "2");
+            assert_eq!(headers.get("ratelimit-remaining").unwrap(), remaining);
+        }
+
+        let req = test::TestRequest::get().uri("/").to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), 429);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+    }
+
+    #[actix_rt::test]
+    async fn it_limits_requests_in_process_without_a_cache() {
+        let scope = "it_limits_requests_in_process_without_a_cache";
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(scope, 1, PERIOD))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert!(response.status().is_success());
+        let req = test::TestRequest::get().uri("/").to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), 429);
+    }
+
+    #[actix_rt::test]
+    async fn it_limits_a_client_that_changes_its_forwarded_for() {
+        let scope = "it_limits_a_client_that_changes_its_forwarded_for";
+        let mut app = test::init_service(
+            App::new()
+                .app_data(get_cache())
+                .wrap(RateLimit::new(scope, 1, PERIOD))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (forwarded_for, status) in &[("1.1.1.1", 200), ("2.2.2.2", 429)] {
+            let req = test::TestRequest::get()
+                .uri("/")
+                .header(X_FORWARDED_FOR, *forwarded_for)
+                .peer_addr("10.0.0.1:52914".parse().unwrap())
+                .to_request();
+            let response = test::call_service(&mut app, req).await;
+            assert_eq!(response.status(), *status);
+        }
+    }
+}
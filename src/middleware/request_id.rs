@@ -0,0 +1,158 @@
+//! Identify each request with the `X-Request-Id` header
+//!
+//! A valid incoming id is kept, so that a request can be traced across
+//! services, otherwise a new one is generated. The id is returned in the
+//! response header and in the body of problem details.
+
+use crate::errors::Problem;
+use actix_service::{Service, Transform};
+use actix_web::{
+    body::{Body, ResponseBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    Error, HttpMessage,
+};
+use futures::{
+    future::{ok, Ready},
+    Future,
+};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest incoming id that's kept
+const MAX_LENGTH: usize = 128;
+
+/// The id of the current request, kept in the request's extensions
+#[derive(Clone, Debug, PartialEq)]
+pub struct CurrentRequestId(pub String);
+
+pub struct RequestId;
+
+impl<S, B> Transform<S> for RequestId
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdMiddleware { service })
+    }
+}
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestIdMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| is_valid(value))
+            .map(String::from)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        req.extensions_mut()
+            .insert(CurrentRequestId(request_id.clone()));
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+
+            // Add the id to problem details
+            let problem = res.response().extensions().get::<Problem>().cloned();
+            if let Some(mut problem) = problem {
+                problem.request_id = Some(request_id);
+                if let Ok(json) = serde_json::to_string(&problem) {
+                    res = res.map_body(|_, _| ResponseBody::Other(Body::from(json)));
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// Keep short, printable ids so they can't be used to inject into logs
+fn is_valid(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= MAX_LENGTH
+        && request_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ApiError;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[test]
+    fn it_validates_an_incoming_id() {
+        assert!(is_valid("a421a56e-8652-4da6-90ee-59dfebb9d1b4"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("line\nbreak"));
+        assert!(!is_valid(&"a".repeat(MAX_LENGTH + 1)));
+    }
+
+    #[actix_rt::test]
+    async fn it_adds_the_id_to_the_response_and_problem_details() {
+        let mut app = test::init_service(App::new().wrap(RequestId).route(
+            "/",
+            web::get().to(|| async { Err::<HttpResponse, _>(ApiError::NotFound("nope".into())) }),
+        ))
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header(REQUEST_ID_HEADER, "abc-123")
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(
+            response.headers().get(REQUEST_ID_HEADER).unwrap(),
+            "abc-123"
+        );
+        let body = test::read_body(response).await;
+        let problem: Problem = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem.request_id, Some("abc-123".into()));
+    }
+
+    #[actix_rt::test]
+    async fn it_generates_an_id() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RequestId)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let response = test::call_service(&mut app, req).await;
+        let request_id = response.headers().get(REQUEST_ID_HEADER).unwrap();
+        assert!(Uuid::parse_str(request_id.to_str().unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,209 @@
+//! Set security headers on every response
+//!
+//! Headers already set, by a handler or a middleware closer to it, are kept,
+//! so a scope can wrap its own `SecurityHeaders`, e.g. with a CSP for a
+//! static site, inside the app-wide one. An empty value leaves a header out,
+//! unless an outer `SecurityHeaders` sets it.
+//!
+//! `Strict-Transport-Security` is only sent over HTTPS, including behind a
+//! proxy that sets `X-Forwarded-Proto`.
+
+use crate::config::CONFIG;
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{
+        HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, REFERRER_POLICY,
+        STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+    },
+    Error,
+};
+use futures::{
+    future::{ok, Ready},
+    Future,
+};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecurityHeaders {
+    content_security_policy: String,
+    frame_options: String,
+    referrer_policy: String,
+    /// Seconds browsers only use HTTPS for, 0 to leave HSTS out
+    hsts_max_age: u64,
+}
+
+impl SecurityHeaders {
+    /// The headers set by the `SECURITY_*` settings, with the API's CSP
+    pub fn from_config() -> Self {
+        Self {
+            content_security_policy: CONFIG.security_csp.clone(),
+            frame_options: CONFIG.security_frame_options.clone(),
+            referrer_policy: CONFIG.security_referrer_policy.clone(),
+            hsts_max_age: CONFIG.security_hsts_max_age,
+        }
+    }
+
+    pub fn content_security_policy(mut self, policy: &str) -> Self {
+        self.content_security_policy = policy.into();
+        self
+    }
+
+    fn headers(&self, https: bool) -> Vec<(HeaderName, String)> {
+        let mut headers = vec![
+            (
+                CONTENT_SECURITY_POLICY,
+                self.content_security_policy.clone(),
+            ),
+            (X_CONTENT_TYPE_OPTIONS, "nosniff".into()),
+            (X_FRAME_OPTIONS, self.frame_options.clone()),
+            (REFERRER_POLICY, self.referrer_policy.clone()),
+        ];
+        if https && self.hsts_max_age > 0 {
+            headers.push((
+                STRICT_TRANSPORT_SECURITY,
+                format!("max-age={}; includeSubDomains", self.hsts_max_age),
+            ));
+        }
+        headers
+    }
+}
+
+impl<S, B> Transform<S> for SecurityHeaders
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SecurityHeadersMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SecurityHeadersMiddleware {
+            service,
+            headers: Rc::new(self.clone()),
+        })
+    }
+}
+pub struct SecurityHeadersMiddleware<S> {
+    service: S,
+    headers: Rc<SecurityHeaders>,
+}
+
+impl<S, B> Service for SecurityHeadersMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let headers = self.headers.clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let https = res.request().connection_info().scheme() == "https";
+            for (name, value) in headers.headers(https) {
+                if value.is_empty() || res.headers().contains_key(&name) {
+                    continue;
+                }
+                // The settings are checked at startup, so only a value given
+                // in code can be invalid
+                if let Ok(value) = HeaderValue::from_str(&value) {
+                    res.headers_mut().insert(name, value);
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{dev::Body, test, web, App, HttpResponse};
+
+    fn headers() -> SecurityHeaders {
+        SecurityHeaders {
+            content_security_policy: "default-src 'none'".into(),
+            frame_options: "DENY".into(),
+            referrer_policy: "no-referrer".into(),
+            hsts_max_age: 600,
+        }
+    }
+
+    async fn call(outer: SecurityHeaders, req: test::TestRequest) -> ServiceResponse<Body> {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(outer)
+                .service(
+                    web::scope("/static")
+                        .wrap(headers().content_security_policy("default-src 'self'"))
+                        .route("", web::get().to(HttpResponse::Ok)),
+                )
+                .route("/api", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        test::call_service(&mut app, req.to_request()).await
+    }
+
+    fn get<'a>(response: &'a ServiceResponse<Body>, name: &HeaderName) -> Option<&'a str> {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn it_sets_security_headers() {
+        let response = call(headers(), test::TestRequest::with_uri("/api")).await;
+        assert_eq!(
+            get(&response, &CONTENT_SECURITY_POLICY),
+            Some("default-src 'none'")
+        );
+        assert_eq!(get(&response, &X_CONTENT_TYPE_OPTIONS), Some("nosniff"));
+        assert_eq!(get(&response, &X_FRAME_OPTIONS), Some("DENY"));
+        assert_eq!(get(&response, &REFERRER_POLICY), Some("no-referrer"));
+        assert_eq!(get(&response, &STRICT_TRANSPORT_SECURITY), None);
+    }
+
+    #[actix_rt::test]
+    async fn it_sets_hsts_over_https() {
+        let req = test::TestRequest::with_uri("/api").header("x-forwarded-proto", "https");
+        let response = call(headers(), req).await;
+        assert_eq!(
+            get(&response, &STRICT_TRANSPORT_SECURITY),
+            Some("max-age=600; includeSubDomains")
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_keeps_the_headers_of_a_scope() {
+        let response = call(headers(), test::TestRequest::with_uri("/static")).await;
+        assert_eq!(
+            get(&response, &CONTENT_SECURITY_POLICY),
+            Some("default-src 'self'")
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_leaves_out_empty_headers() {
+        let outer = headers().content_security_policy("");
+        let response = call(outer, test::TestRequest::with_uri("/api")).await;
+        assert_eq!(get(&response, &CONTENT_SECURITY_POLICY), None);
+        assert_eq!(get(&response, &X_FRAME_OPTIONS), Some("DENY"));
+    }
+}
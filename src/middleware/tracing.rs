@@ -0,0 +1,144 @@
+//! Trace each request in a server span
+//!
+//! An incoming `traceparent` header continues the caller's trace, otherwise
+//! a new trace is started. The span's `traceparent` is returned on the
+//! response, and the span is current while the request is handled, so spans
+//! of handlers, queries and Redis commands are its children.
+
+use crate::middleware::metrics::route;
+use crate::middleware::request_id::CurrentRequestId;
+use crate::telemetry::{in_context, Span, SpanContext, SpanKind, TRACEPARENT_HEADER};
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    Error,
+};
+use futures::{
+    future::{ok, Ready},
+    Future,
+};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub struct Tracing;
+
+impl<S, B> Transform<S> for Tracing
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TracingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TracingMiddleware { service })
+    }
+}
+pub struct TracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for TracingMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let parent = req
+            .headers()
+            .get(TRACEPARENT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(SpanContext::from_traceparent);
+        let method = req.method().to_string();
+        let mut span = Span::with_parent(method.clone(), SpanKind::Server, parent);
+        span.set_attribute("http.method", &method);
+        span.set_attribute("http.target", req.path());
+        let context = span.context().clone();
+
+        let fut = in_context(Some(context.clone()), self.service.call(req));
+
+        Box::pin(async move {
+            let mut res = match fut.await {
+                Ok(res) => res,
+                Err(error) => {
+                    span.set_error(&error);
+                    return Err(error);
+                }
+            };
+
+            let route = route(res.request());
+            span.set_name(format!("{} {}", method, route));
+            span.set_attribute("http.route", &route);
+            span.set_attribute("http.status_code", res.status().as_u16());
+            if let Some(request_id) = res.request().extensions().get::<CurrentRequestId>() {
+                span.set_attribute("http.request_id", &request_id.0);
+            }
+            if res.status().is_server_error() {
+                span.set_error(res.status());
+            }
+
+            if let Ok(value) = HeaderValue::from_str(&context.to_traceparent()) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(TRACEPARENT_HEADER), value);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::current;
+    use actix_web::{test, web, App, HttpResponse};
+
+    async fn traceparent(incoming: Option<&str>) -> SpanContext {
+        let mut app = test::init_service(App::new().wrap(Tracing).route(
+            "/",
+            web::get().to(|| {
+                // The handler runs in the server span
+                assert!(current().is_some());
+                HttpResponse::Ok()
+            }),
+        ))
+        .await;
+        let mut req = test::TestRequest::get().uri("/");
+        if let Some(incoming) = incoming {
+            req = req.header(TRACEPARENT_HEADER, incoming);
+        }
+        let response = test::call_service(&mut app, req.to_request()).await;
+        let traceparent = response.headers().get(TRACEPARENT_HEADER).unwrap();
+        SpanContext::from_traceparent(traceparent.to_str().unwrap()).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn it_continues_an_incoming_trace() {
+        let context = traceparent(Some(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ))
+        .await;
+        assert_eq!(context.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_ne!(context.span_id, "00f067aa0ba902b7");
+    }
+
+    #[actix_rt::test]
+    async fn it_starts_a_trace() {
+        let context = traceparent(None).await;
+        assert!(context.sampled);
+        assert_eq!(context.trace_id.len(), 32);
+    }
+}
@@ -1,123 +1,228 @@
 use crate::auth::hash;
-use crate::database::PoolType;
 use crate::errors::ApiError;
 use crate::handlers::user::{UserResponse, UsersResponse};
 use crate::schema::users;
-use chrono::{NaiveDateTime, Utc};
+use crate::sql_types::{self, DbTimestamp, DbUuid};
+use crate::telemetry::enter;
+use chrono::{DateTime, Utc};
+use diesel::deserialize::FromSql;
+use diesel::dsl::{exists, select};
 use diesel::prelude::*;
+use diesel::query_builder::InsertStatement;
+use diesel::query_dsl::methods::ExecuteDsl;
+use diesel::serialize::ToSql;
+use diesel::sql_types::{Bool, HasSqlType};
 use uuid::Uuid;
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable)]
 pub struct User {
-    pub id: String,
+    #[diesel(deserialize_as = "DbUuid")]
+    pub id: Uuid,
     pub first_name: String,
     pub last_name: String,
     pub email: String,
     pub password: String,
-    pub created_by: String,
-    pub created_at: NaiveDateTime,
-    pub updated_by: String,
-    pub updated_at: NaiveDateTime,
+    #[diesel(deserialize_as = "DbUuid")]
+    pub created_by: Uuid,
+    #[diesel(deserialize_as = "DbTimestamp")]
+    pub created_at: DateTime<Utc>,
+    #[diesel(deserialize_as = "DbUuid")]
+    pub updated_by: Uuid,
+    #[diesel(deserialize_as = "DbTimestamp")]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A user as it is written to the database
+#[derive(Insertable)]
+#[table_name = "users"]
+pub struct UserRow {
+    id: DbUuid,
+    first_name: String,
+    last_name: String,
+    email: String,
+    password: String,
+    created_by: DbUuid,
+    created_at: DbTimestamp,
+    updated_by: DbUuid,
+    updated_at: DbTimestamp,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NewUser {
-    pub id: String,
+    pub id: Uuid,
     pub first_name: String,
     pub last_name: String,
     pub email: String,
     pub password: String,
-    pub created_by: String,
-    pub updated_by: String,
+    pub created_by: Uuid,
+    pub updated_by: Uuid,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, AsChangeset)]
-#[table_name = "users"]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UpdateUser {
-    pub id: String,
+    pub id: Uuid,
     pub first_name: String,
     pub last_name: String,
     pub email: String,
-    pub updated_by: String,
+    pub updated_by: Uuid,
+}
+
+/// The columns that an update writes to the database
+#[derive(AsChangeset)]
+#[table_name = "users"]
+struct UserChangeset<'a> {
+    first_name: &'a str,
+    last_name: &'a str,
+    email: &'a str,
+    updated_by: DbUuid,
+    updated_at: DbTimestamp,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AuthUser {
-    pub id: String,
+    pub id: Uuid,
     pub email: String,
 }
 
 /// Get all users
-pub fn get_all(pool: &PoolType) -> Result<UsersResponse, ApiError> {
+pub fn get_all<C>(conn: &C) -> Result<UsersResponse, ApiError>
+where
+    C: Connection,
+    C::Backend: HasSqlType<users::SqlType>,
+    User: Queryable<users::SqlType, C::Backend>,
+{
     use crate::schema::users::dsl::users;
+    let _span = enter("models::user::get_all");
 
-    let conn = pool.get()?;
-    let all_users = users.load(&conn)?;
+    let all_users = users.load(conn)?;
 
     Ok(all_users.into())
 }
 
 /// Find a user by the user's id or error out
-pub fn find(pool: &PoolType, user_id: Uuid) -> Result<UserResponse, ApiError> {
+pub fn find<C>(conn: &C, user_id: Uuid) -> Result<UserResponse, ApiError>
+where
+    C: Connection,
+    C::Backend: HasSqlType<users::SqlType>
+        + HasSqlType<sql_types::Uuid>
+        + HasSqlType<sql_types::UtcTimestamp>,
+    User: Queryable<users::SqlType, C::Backend>,
+    DbUuid: ToSql<sql_types::Uuid, C::Backend>,
+    DbTimestamp: ToSql<sql_types::UtcTimestamp, C::Backend>,
+{
     use crate::schema::users::dsl::{id, users};
+    let _span = enter("models::user::find");
 
-    let not_found = format!("User {} not found", user_id);
-    let conn = pool.get()?;
     let user = users
-        .filter(id.eq(user_id.to_string()))
-        .first::<User>(&conn)
-        .map_err(|_| ApiError::NotFound(not_found))?;
+        .filter(id.eq(DbUuid(user_id)))
+        .first::<User>(conn)
+        .optional()?
+        .ok_or_else(|| ApiError::NotFound(format!("User {} not found", user_id)))?;
 
     Ok(user.into())
 }
 
 /// Find a user by the user's authentication information (email + password)
 /// Return an Unauthorized error if it doesn't match
-pub fn find_by_auth(
-    pool: &PoolType,
+pub fn find_by_auth<C>(
+    conn: &C,
     user_email: &str,
     user_password: &str,
-) -> Result<UserResponse, ApiError> {
+) -> Result<UserResponse, ApiError>
+where
+    C: Connection,
+    C::Backend: HasSqlType<users::SqlType>,
+    User: Queryable<users::SqlType, C::Backend>,
+{
     use crate::schema::users::dsl::{email, password, users};
+    let _span = enter("models::user::find_by_auth");
 
-    let conn = pool.get()?;
     let user = users
         .filter(email.eq(user_email.to_string()))
         .filter(password.eq(user_password.to_string()))
-        .first::<User>(&conn)
-        .map_err(|_| ApiError::Unauthorized("Invalid login".into()))?;
+        .first::<User>(conn)
+        .optional()?
+        .ok_or_else(|| ApiError::Unauthorized("Invalid login".into()))?;
     Ok(user.into())
 }
 
+/// Whether a user other than `except` has the email
+///
+/// Emails are compared case-sensitively, like the unique index on Postgres
+/// and SQLite. MySQL's default collation ignores case, so its index rejects
+/// an email that only differs in case instead.
+pub fn email_exists<C>(conn: &C, user_email: &str, except: Option<Uuid>) -> Result<bool, ApiError>
+where
+    C: Connection,
+    C::Backend: HasSqlType<Bool> + HasSqlType<sql_types::Uuid>,
+    bool: FromSql<Bool, C::Backend>,
+    DbUuid: ToSql<sql_types::Uuid, C::Backend>,
+{
+    use crate::schema::users::dsl::{email, id, users};
+    let _span = enter("models::user::email_exists");
+
+    let with_email = users.filter(email.eq(user_email.to_string()));
+    let found = match except {
+        Some(except) => select(exists(with_email.filter(id.ne(DbUuid(except))))).get_result(conn),
+        None => select(exists(with_email)).get_result(conn),
+    };
+    Ok(found?)
+}
+
 /// Create a new user
-pub fn create(pool: &PoolType, new_user: &User) -> Result<UserResponse, ApiError> {
+pub fn create<C>(conn: &C, new_user: &User) -> Result<UserResponse, ApiError>
+where
+    C: Connection,
+    C::Backend: HasSqlType<sql_types::UtcTimestamp>,
+    InsertStatement<users::table, <UserRow as Insertable<users::table>>::Values>: ExecuteDsl<C>,
+{
     use crate::schema::users::dsl::users;
+    let _span = enter("models::user::create");
 
-    let conn = pool.get()?;
-    diesel::insert_into(users).values(new_user).execute(&conn)?;
+    diesel::insert_into(users)
+        .values(UserRow::from(new_user))
+        .execute(conn)?;
     Ok(new_user.clone().into())
 }
 
 /// Update a user
-pub fn update(pool: &PoolType, update_user: &UpdateUser) -> Result<UserResponse, ApiError> {
+///
+/// Runs the UPDATE and the follow-up SELECT on the same connection, so wrap
+/// the call in `transaction::transaction` to make it atomic.
+pub fn update<C>(conn: &C, update_user: &UpdateUser) -> Result<UserResponse, ApiError>
+where
+    C: Connection,
+    C::Backend: HasSqlType<users::SqlType>
+        + HasSqlType<sql_types::Uuid>
+        + HasSqlType<sql_types::UtcTimestamp>,
+    User: Queryable<users::SqlType, C::Backend>,
+    DbUuid: ToSql<sql_types::Uuid, C::Backend>,
+    DbTimestamp: ToSql<sql_types::UtcTimestamp, C::Backend>,
+{
     use crate::schema::users::dsl::{id, users};
+    let _span = enter("models::user::update");
 
-    let conn = pool.get()?;
     diesel::update(users)
-        .filter(id.eq(update_user.id.clone()))
-        .set(update_user)
-        .execute(&conn)?;
-    find(&pool, Uuid::parse_str(&update_user.id)?)
+        .filter(id.eq(DbUuid(update_user.id)))
+        .set(UserChangeset::from(update_user))
+        .execute(conn)?;
+    find(conn, update_user.id)
 }
 
 /// Delete a user
-pub fn delete(pool: &PoolType, user_id: Uuid) -> Result<(), ApiError> {
+pub fn delete<C>(conn: &C, user_id: Uuid) -> Result<(), ApiError>
+where
+    C: Connection,
+    C::Backend: HasSqlType<sql_types::Uuid> + HasSqlType<sql_types::UtcTimestamp>,
+    DbUuid: ToSql<sql_types::Uuid, C::Backend>,
+    DbTimestamp: ToSql<sql_types::UtcTimestamp, C::Backend>,
+{
     use crate::schema::users::dsl::{id, users};
+    let _span = enter("models::user::delete");
 
-    let conn = pool.get()?;
     diesel::delete(users)
-        .filter(id.eq(user_id.to_string()))
-        .execute(&conn)?;
+        .filter(id.eq(DbUuid(user_id)))
+        .execute(conn)?;
     Ok(())
 }
 
@@ -130,82 +235,125 @@ impl From<NewUser> for User {
             email: user.email,
             password: hash(&user.password),
             created_by: user.created_by,
-            created_at: Utc::now().naive_utc(),
+            created_at: Utc::now(),
             updated_by: user.updated_by,
-            updated_at: Utc::now().naive_utc(),
+            updated_at: Utc::now(),
         }
     }
 }
 
-#[cfg(test)]
-pub mod tests {
-    use super::*;
-    use crate::tests::helpers::tests::get_pool;
-
-    pub fn get_all_users() -> Result<UsersResponse, ApiError> {
-        let pool = get_pool();
-        get_all(&pool)
+impl<'a> From<&'a User> for UserRow {
+    fn from(user: &'a User) -> Self {
+        UserRow {
+            id: DbUuid(user.id),
+            first_name: user.first_name.clone(),
+            last_name: user.last_name.clone(),
+            email: user.email.clone(),
+            password: user.password.clone(),
+            created_by: DbUuid(user.created_by),
+            created_at: DbTimestamp(user.created_at),
+            updated_by: DbUuid(user.updated_by),
+            updated_at: DbTimestamp(user.updated_at),
+        }
     }
+}
 
-    pub fn create_user() -> Result<UserResponse, ApiError> {
-        let user_id = Uuid::new_v4();
-        let new_user = NewUser {
-            id: user_id.to_string(),
-            first_name: "Model".to_string(),
-            last_name: "Test".to_string(),
-            email: "model-test@nothing.org".to_string(),
-            password: "123456".to_string(),
-            created_by: user_id.to_string(),
-            updated_by: user_id.to_string(),
-        };
-        let user: User = new_user.into();
-        create(&get_pool(), &user)
+impl<'a> From<&'a UpdateUser> for UserChangeset<'a> {
+    fn from(user: &'a UpdateUser) -> Self {
+        UserChangeset {
+            first_name: &user.first_name,
+            last_name: &user.last_name,
+            email: &user.email,
+            updated_by: DbUuid(user.updated_by),
+            updated_at: DbTimestamp(Utc::now()),
+        }
     }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::{create_user, get_conn, new_user};
 
     #[test]
-    fn it_gets_a_user() {
-        let users = get_all_users();
-        assert!(users.is_ok());
+    fn it_gets_all_users() {
+        let conn = get_conn();
+        let created = create_user(&conn);
+        let users = get_all(&*conn).unwrap();
+        assert!(users.0.contains(&created));
     }
 
     #[test]
     fn test_find() {
-        let users = get_all_users().unwrap();
-        let user = &users.0[0];
-        let found_user = find(&get_pool(), user.id).unwrap();
-        assert_eq!(user, &found_user);
+        let conn = get_conn();
+        let user = create_user(&conn);
+        let found_user = find(&*conn, user.id).unwrap();
+        assert_eq!(user, found_user);
     }
 
     #[test]
     fn it_doesnt_find_a_user() {
         let user_id = Uuid::new_v4();
-        let not_found_user = find(&get_pool(), user_id);
+        let not_found_user = find(&*get_conn(), user_id);
+        assert!(not_found_user.is_err());
+    }
+
+    #[test]
+    fn it_finds_a_user_by_auth() {
+        let conn = get_conn();
+        let user = new_user();
+        create(&*conn, &user).unwrap();
+        let found_user = find_by_auth(&*conn, &user.email, &user.password);
+        assert!(found_user.is_ok());
+        let not_found_user = find_by_auth(&*conn, &user.email, "not-the-password");
         assert!(not_found_user.is_err());
     }
 
     #[test]
     fn it_creates_a_user() {
-        let created = create_user();
+        let conn = get_conn();
+        let created = create(&*conn, &new_user());
         assert!(created.is_ok());
         let unwrapped = created.unwrap();
-        let found_user = find(&get_pool(), unwrapped.id.clone()).unwrap();
+        let found_user = find(&*conn, unwrapped.id).unwrap();
         assert_eq!(unwrapped, found_user);
     }
 
+    #[test]
+    fn it_checks_if_an_email_exists() {
+        let conn = get_conn();
+        let user = create_user(&conn);
+        assert!(email_exists(&*conn, &user.email, None).unwrap());
+        assert!(!email_exists(&*conn, &user.email, Some(user.id)).unwrap());
+        assert!(!email_exists(&*conn, "nobody@nothing.org", None).unwrap());
+        let other_case = user.email.to_uppercase();
+        assert!(!email_exists(&*conn, &other_case, None).unwrap());
+    }
+
+    #[test]
+    fn it_doesnt_create_a_user_with_a_duplicate_email() {
+        let conn = get_conn();
+        let user = create_user(&conn);
+        let mut duplicate = new_user();
+        duplicate.email = user.email;
+        let error = create(&*conn, &duplicate).unwrap_err();
+        assert_eq!(error, ApiError::already_exists("email"));
+    }
+
     #[test]
     fn it_updates_a_user() {
-        let users = get_all_users().unwrap();
-        let user = &users.0[1];
+        let conn = get_conn();
+        let user = create_user(&conn);
         let update_user = UpdateUser {
-            id: user.id.to_string(),
+            id: user.id,
             first_name: "ModelUpdate".to_string(),
             last_name: "TestUpdate".to_string(),
             email: "model-update-test@nothing.org".to_string(),
-            updated_by: user.id.to_string(),
+            updated_by: user.id,
         };
-        let updated = update(&get_pool(), &update_user);
+        let updated = update(&*conn, &update_user);
         assert!(updated.is_ok());
-        let found_user = find(&get_pool(), user.id).unwrap();
+        let found_user = find(&*conn, user.id).unwrap();
         assert_eq!(updated.unwrap(), found_user);
     }
 
@@ -213,24 +361,24 @@ pub mod tests {
     fn it_fails_to_update_a_nonexistent_user() {
         let user_id = Uuid::new_v4();
         let update_user = UpdateUser {
-            id: user_id.to_string(),
+            id: user_id,
             first_name: "ModelUpdateFailure".to_string(),
             last_name: "TestUpdateFailure".to_string(),
             email: "model-update-failure-test@nothing.org".to_string(),
-            updated_by: user_id.to_string(),
+            updated_by: user_id,
         };
-        let updated = update(&get_pool(), &update_user);
+        let updated = update(&*get_conn(), &update_user);
         assert!(updated.is_err());
     }
 
     #[test]
     fn it_deletes_a_user() {
-        let created = create_user();
-        let user_id = created.unwrap().id;
-        let user = find(&get_pool(), user_id);
+        let conn = get_conn();
+        let user_id = create_user(&conn).id;
+        let user = find(&*conn, user_id);
         assert!(user.is_ok());
-        delete(&get_pool(), user_id).unwrap();
-        let user = find(&get_pool(), user_id);
+        delete(&*conn, user_id).unwrap();
+        let user = find(&*conn, user_id);
         assert!(user.is_err());
     }
 }
@@ -0,0 +1,27 @@
+//! Repositories abstract the storage of models away from handlers.
+//!
+//! Handlers receive a repository through Actix data, so they can be backed
+//! by the database in the server and by an in-memory store in tests.
+
+use crate::database::{DatabaseCheck, Pool};
+use crate::repositories::user::{DieselUserRepository, UserRepository};
+use actix_web::web::ServiceConfig;
+
+pub mod user;
+
+/// Add the database-backed repositories to actix data
+///
+/// The repositories and the checks of the readiness endpoint share the
+/// pool, so every worker uses the same connections.
+pub fn add_repositories<C>(pool: Pool<C>) -> impl FnOnce(&mut ServiceConfig)
+where
+    C: diesel::Connection + 'static,
+    DieselUserRepository<C>: UserRepository,
+    Pool<C>: DatabaseCheck,
+{
+    move |cfg| {
+        let database: Box<dyn DatabaseCheck> = Box::new(pool.clone());
+        let user_repository: Box<dyn UserRepository> = Box::new(DieselUserRepository::new(pool));
+        cfg.data(user_repository).data(database);
+    }
+}
@@ -0,0 +1,313 @@
+use crate::database::Pool;
+use crate::errors::ApiError;
+use crate::handlers::user::{UserResponse, UsersResponse};
+use crate::models::user::{self, UpdateUser, User, UserRow};
+use crate::schema::users;
+use crate::sql_types::{self, DbTimestamp, DbUuid};
+use crate::transaction::{transaction, TransactionalConnection};
+use actix_web::web::Data;
+use diesel::deserialize::FromSql;
+use diesel::prelude::*;
+use diesel::query_builder::InsertStatement;
+use diesel::query_dsl::methods::ExecuteDsl;
+use diesel::serialize::ToSql;
+use diesel::sql_types::{Bool, HasSqlType};
+#[cfg(test)]
+use std::sync::RwLock;
+use uuid::Uuid;
+
+/// Extract the user repository in a handler
+///
+/// Actix data needs a sized type, so the trait object is boxed.
+pub type UserRepo = Data<Box<dyn UserRepository>>;
+
+/// Storage operations for users
+///
+/// Operations are blocking, so call them from within `web::block`.
+pub trait UserRepository: Send + Sync {
+    /// Get all users
+    fn get_all(&self) -> Result<UsersResponse, ApiError>;
+
+    /// Find a user by the user's id or error out
+    fn find(&self, user_id: Uuid) -> Result<UserResponse, ApiError>;
+
+    /// Find a user by the user's authentication information (email + password)
+    fn find_by_auth(&self, email: &str, password: &str) -> Result<UserResponse, ApiError>;
+
+    /// Whether a user other than `except` has the email
+    fn email_exists(&self, email: &str, except: Option<Uuid>) -> Result<bool, ApiError>;
+
+    /// Create a new user
+    fn create(&self, new_user: &User) -> Result<UserResponse, ApiError>;
+
+    /// Update a user
+    fn update(&self, update_user: &UpdateUser) -> Result<UserResponse, ApiError>;
+
+    /// Delete a user
+    fn delete(&self, user_id: Uuid) -> Result<(), ApiError>;
+}
+
+/// User repository backed by the diesel models
+pub struct DieselUserRepository<C: Connection + 'static> {
+    pool: Pool<C>,
+}
+
+impl<C: Connection + 'static> DieselUserRepository<C> {
+    pub fn new(pool: Pool<C>) -> Self {
+        Self { pool }
+    }
+}
+
+impl<C> UserRepository for DieselUserRepository<C>
+where
+    C: TransactionalConnection + 'static,
+    C::Backend: HasSqlType<users::SqlType>
+        + HasSqlType<sql_types::Uuid>
+        + HasSqlType<sql_types::UtcTimestamp>
+        + HasSqlType<Bool>,
+    User: Queryable<users::SqlType, C::Backend>,
+    bool: FromSql<Bool, C::Backend>,
+    DbUuid: ToSql<sql_types::Uuid, C::Backend>,
+    DbTimestamp: ToSql<sql_types::UtcTimestamp, C::Backend>,
+    InsertStatement<users::table, <UserRow as Insertable<users::table>>::Values>: ExecuteDsl<C>,
+{
+    fn get_all(&self) -> Result<UsersResponse, ApiError> {
+        let conn = self.pool.get()?;
+        user::get_all(&*conn)
+    }
+
+    fn find(&self, user_id: Uuid) -> Result<UserResponse, ApiError> {
+        let conn = self.pool.get()?;
+        user::find(&*conn, user_id)
+    }
+
+    fn find_by_auth(&self, email: &str, password: &str) -> Result<UserResponse, ApiError> {
+        let conn = self.pool.get()?;
+        user::find_by_auth(&*conn, email, password)
+    }
+
+    fn email_exists(&self, email: &str, except: Option<Uuid>) -> Result<bool, ApiError> {
+        let conn = self.pool.get()?;
+        user::email_exists(&*conn, email, except)
+    }
+
+    fn create(&self, new_user: &User) -> Result<UserResponse, ApiError> {
+        let conn = self.pool.get()?;
+        user::create(&*conn, new_user)
+    }
+
+    fn update(&self, update_user: &UpdateUser) -> Result<UserResponse, ApiError> {
+        transaction(&self.pool, |conn| user::update(conn, update_user))
+    }
+
+    fn delete(&self, user_id: Uuid) -> Result<(), ApiError> {
+        let conn = self.pool.get()?;
+        user::delete(&*conn, user_id)
+    }
+}
+
+/// User repository that keeps users in memory, used in tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct InMemoryUserRepository {
+    users: RwLock<Vec<User>>,
+}
+
+#[cfg(test)]
+impl InMemoryUserRepository {
+    /// Create a repository seeded with users
+    pub fn with_users(users: Vec<User>) -> Self {
+        Self {
+            users: RwLock::new(users),
+        }
+    }
+}
+
+#[cfg(test)]
+impl UserRepository for InMemoryUserRepository {
+    fn get_all(&self) -> Result<UsersResponse, ApiError> {
+        let users = self.users.read().expect("Could not acquire lock");
+        Ok(users.clone().into())
+    }
+
+    fn find(&self, user_id: Uuid) -> Result<UserResponse, ApiError> {
+        let users = self.users.read().expect("Could not acquire lock");
+        users
+            .iter()
+            .find(|user| user.id == user_id)
+            .map(|user| user.clone().into())
+            .ok_or_else(|| ApiError::NotFound(format!("User {} not found", user_id)))
+    }
+
+    fn find_by_auth(&self, email: &str, password: &str) -> Result<UserResponse, ApiError> {
+        let users = self.users.read().expect("Could not acquire lock");
+        users
+            .iter()
+            .find(|user| user.email == email && user.password == password)
+            .map(|user| user.clone().into())
+            .ok_or_else(|| ApiError::Unauthorized("Invalid login".into()))
+    }
+
+    fn email_exists(&self, email: &str, except: Option<Uuid>) -> Result<bool, ApiError> {
+        let users = self.users.read().expect("Could not acquire lock");
+        Ok(users
+            .iter()
+            .any(|user| user.email == email && Some(user.id) != except))
+    }
+
+    fn create(&self, new_user: &User) -> Result<UserResponse, ApiError> {
+        let mut users = self.users.write().expect("Could not acquire lock");
+        if users.iter().any(|user| user.id == new_user.id) {
+            return Err(ApiError::already_exists("id"));
+        }
+        if users.iter().any(|user| user.email == new_user.email) {
+            return Err(ApiError::already_exists("email"));
+        }
+        users.push(new_user.clone());
+        Ok(new_user.clone().into())
+    }
+
+    fn update(&self, update_user: &UpdateUser) -> Result<UserResponse, ApiError> {
+        let mut users = self.users.write().expect("Could not acquire lock");
+        if users
+            .iter()
+            .any(|user| user.email == update_user.email && user.id != update_user.id)
+        {
+            return Err(ApiError::already_exists("email"));
+        }
+        let user = users
+            .iter_mut()
+            .find(|user| user.id == update_user.id)
+            .ok_or_else(|| ApiError::NotFound(format!("User {} not found", update_user.id)))?;
+        user.first_name = update_user.first_name.clone();
+        user.last_name = update_user.last_name.clone();
+        user.email = update_user.email.clone();
+        user.updated_by = update_user.updated_by;
+        Ok(user.clone().into())
+    }
+
+    fn delete(&self, user_id: Uuid) -> Result<(), ApiError> {
+        let mut users = self.users.write().expect("Could not acquire lock");
+        users.retain(|user| user.id != user_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::hash;
+    use crate::tests::helpers::tests::{get_pool, new_user, TEST_PASSWORD};
+
+    fn get_repository() -> InMemoryUserRepository {
+        InMemoryUserRepository::with_users(vec![new_user()])
+    }
+
+    #[test]
+    fn it_gets_all_users() {
+        let users = get_repository().get_all().unwrap();
+        assert_eq!(users.0.len(), 1);
+    }
+
+    #[test]
+    fn it_finds_a_user() {
+        let repository = get_repository();
+        let user = &repository.get_all().unwrap().0[0];
+        let found_user = repository.find(user.id).unwrap();
+        assert_eq!(user, &found_user);
+    }
+
+    #[test]
+    fn it_doesnt_find_a_user() {
+        let user_id = Uuid::new_v4();
+        let not_found_user = get_repository().find(user_id);
+        let expected_error = ApiError::NotFound(format!("User {} not found", user_id));
+        assert_eq!(not_found_user.unwrap_err(), expected_error);
+    }
+
+    #[test]
+    fn it_finds_a_user_by_auth() {
+        let repository = get_repository();
+        let email = repository.get_all().unwrap().0[0].email.clone();
+        let found_user = repository.find_by_auth(&email, &hash(TEST_PASSWORD));
+        assert!(found_user.is_ok());
+        let not_found_user = repository.find_by_auth(&email, &hash("654321"));
+        assert!(not_found_user.is_err());
+    }
+
+    #[test]
+    fn it_creates_a_user() {
+        let repository = get_repository();
+        let created = repository.create(&new_user()).unwrap();
+        let found_user = repository.find(created.id).unwrap();
+        assert_eq!(created, found_user);
+    }
+
+    #[test]
+    fn it_doesnt_create_a_duplicate_user() {
+        let repository = get_repository();
+        let user = new_user();
+        repository.create(&user).unwrap();
+        assert!(repository.create(&user).is_err());
+    }
+
+    #[test]
+    fn it_updates_a_user() {
+        let repository = get_repository();
+        let user = &repository.get_all().unwrap().0[0];
+        let update_user = UpdateUser {
+            id: user.id,
+            first_name: "ModelUpdate".to_string(),
+            last_name: "TestUpdate".to_string(),
+            email: "model-update-test@nothing.org".to_string(),
+            updated_by: user.id,
+        };
+        let updated = repository.update(&update_user).unwrap();
+        assert_eq!(updated.first_name, "ModelUpdate");
+        assert_eq!(repository.find(user.id).unwrap(), updated);
+    }
+
+    #[test]
+    fn it_fails_to_update_a_nonexistent_user() {
+        let user_id = Uuid::new_v4();
+        let update_user = UpdateUser {
+            id: user_id,
+            first_name: "ModelUpdateFailure".to_string(),
+            last_name: "TestUpdateFailure".to_string(),
+            email: "model-update-failure-test@nothing.org".to_string(),
+            updated_by: user_id,
+        };
+        assert!(get_repository().update(&update_user).is_err());
+    }
+
+    #[test]
+    fn it_deletes_a_user() {
+        let repository = get_repository();
+        let user_id = repository.get_all().unwrap().0[0].id;
+        repository.delete(user_id).unwrap();
+        assert!(repository.find(user_id).is_err());
+    }
+
+    #[test]
+    fn it_creates_and_finds_a_user_in_the_database() {
+        let repository = DieselUserRepository::new(get_pool());
+        let created = repository.create(&new_user()).unwrap();
+        let found_user = repository.find(created.id).unwrap();
+        assert_eq!(created, found_user);
+    }
+
+    #[test]
+    fn it_updates_a_user_in_the_database() {
+        let repository = DieselUserRepository::new(get_pool());
+        let created = repository.create(&new_user()).unwrap();
+        let update_user = UpdateUser {
+            id: created.id,
+            first_name: "ModelUpdate".to_string(),
+            last_name: created.last_name.clone(),
+            email: created.email.clone(),
+            updated_by: created.id,
+        };
+        let updated = repository.update(&update_user).unwrap();
+        assert_eq!(updated.first_name, "ModelUpdate");
+    }
+}
@@ -1,30 +1,60 @@
 //! Place all Actix routes here, multiple route configs can be used and
 //! combined.
 
+use crate::config::CONFIG;
 use crate::handlers::{
-    auth::{login, logout},
-    health::get_health,
+    auth::{login, logout, token},
+    health::{get_health, get_live, get_ready},
+    metrics::get_metrics,
+    redirect::redirect_to_https,
+    session::{delete_session, delete_sessions, get_sessions},
     user::{create_user, delete_user, get_user, get_users, update_user},
 };
 use crate::middleware::auth::Auth as AuthMiddleware;
+use crate::middleware::csrf::Csrf;
+use crate::middleware::rate_limit::{period, RateLimit, RateLimitBy};
+use crate::middleware::security_headers::SecurityHeaders;
 use actix_files::Files;
 use actix_web::web;
 
 pub fn routes(cfg: &mut web::ServiceConfig) {
+    // Metrics are served here unless they have their own admin server
+    if CONFIG.metrics_server.is_empty() {
+        metrics_routes(cfg);
+    }
+
     cfg
         // Healthcheck
         .route("/health", web::get().to(get_health))
+        .route("/health/live", web::get().to(get_live))
+        .route("/health/ready", web::get().to(get_ready))
         // /api/v1 routes
         .service(
             web::scope("/api/v1")
+                // Check the CSRF token of cookie-authenticated changes
+                .wrap(Csrf)
                 // Lock down routes with AUTH Middleware
                 .wrap(AuthMiddleware)
+                // Limit each user, or IP when logged out
+                .wrap(RateLimit::new("api", CONFIG.rate_limit_api, period()).by(RateLimitBy::User))
                 // AUTH routes
                 .service(
                     web::scope("/auth")
+                        // Slow down password guessing
+                        .wrap(RateLimit::new("auth", CONFIG.rate_limit_auth, period()))
                         .route("/login", web::post().to(login))
+                        .route("/token", web::post().to(token))
                         .route("/logout", web::get().to(logout)),
                 )
+                // ADMIN routes
+                .service(
+                    web::scope("/admin").service(
+                        web::scope("/user/{id}/sessions")
+                            .route("/{session_id}", web::delete().to(delete_session))
+                            .route("", web::get().to(get_sessions))
+                            .route("", web::delete().to(delete_sessions)),
+                    ),
+                )
                 // USER routes
                 .service(
                     web::scope("/user")
@@ -37,18 +67,38 @@ pub fn routes(cfg: &mut web::ServiceConfig) {
         )
         // Serve secure static files from the static-private folder
         .service(
-            web::scope("/secure").wrap(AuthMiddleware).service(
-                Files::new("", "./static-secure")
-                    .index_file("index.html")
-                    .use_last_modified(true),
-            ),
+            web::scope("/secure")
+                .wrap(AuthMiddleware)
+                .wrap(static_security_headers())
+                .service(
+                    Files::new("", "./static-secure")
+                        .index_file("index.html")
+                        .use_last_modified(true),
+                ),
         )
         // Serve public static files from the static folder
         .service(
-            web::scope("").default_service(
-                Files::new("", "./static")
-                    .index_file("index.html")
-                    .use_last_modified(true),
-            ),
+            web::scope("")
+                .wrap(static_security_headers())
+                .default_service(
+                    Files::new("", "./static")
+                        .index_file("index.html")
+                        .use_last_modified(true),
+                ),
         );
 }
+
+/// The static sites load their own scripts and styles, unlike the API
+fn static_security_headers() -> SecurityHeaders {
+    SecurityHeaders::from_config().content_security_policy(&CONFIG.security_static_csp)
+}
+
+/// Every path of the HTTP listener set by `TLS_REDIRECT_SERVER`, redirected to HTTPS
+pub fn redirect_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/{path:.*}").to(redirect_to_https));
+}
+
+/// Prometheus metrics, on their own admin server when `METRICS_SERVER` is set
+pub fn metrics_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/metrics", web::get().to(get_metrics));
+}
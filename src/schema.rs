@@ -1,13 +1,16 @@
 table! {
+    use diesel::sql_types::Varchar;
+    use crate::sql_types::{UtcTimestamp, Uuid};
+
     users (id) {
-        id -> Varchar,
+        id -> Uuid,
         first_name -> Varchar,
         last_name -> Varchar,
         email -> Varchar,
         password -> Varchar,
-        created_by -> Varchar,
-        created_at -> Timestamp,
-        updated_by -> Varchar,
-        updated_at -> Timestamp,
+        created_by -> Uuid,
+        created_at -> UtcTimestamp,
+        updated_by -> Uuid,
+        updated_at -> UtcTimestamp,
     }
 }
@@ -1,41 +1,113 @@
 //! Spin up a HTTPServer
 
 use crate::auth::get_identity_service;
-use crate::cache::add_cache;
+use crate::cache::new_cache;
 use crate::config::CONFIG;
-use crate::database::add_pool;
-use crate::routes::routes;
-use crate::state::new_state;
-use actix_cors::Cors;
-use actix_web::{middleware::Logger, App, HttpServer};
+use crate::database::{add_pool, new_pool};
+use crate::extractors::{form_config, json_config, path_config, query_config};
+use crate::logging;
+use crate::middleware::access_log::AccessLog;
+use crate::middleware::cors::Cors;
+use crate::middleware::locale::Localize;
+use crate::middleware::metrics::Metrics;
+use crate::middleware::request_id::RequestId;
+use crate::middleware::security_headers::SecurityHeaders;
+use crate::middleware::tracing::Tracing;
+use crate::repositories::add_repositories;
+use crate::routes::{metrics_routes, redirect_routes, routes};
+use crate::shutdown;
+use crate::state::{new_state, start_eviction};
+use crate::telemetry::start_export;
+use crate::tls;
+use actix_web::{App, HttpServer};
+use futures::future::try_join_all;
 use listenfd::ListenFd;
 
 pub async fn server() -> std::io::Result<()> {
     dotenv::dotenv().ok();
-    env_logger::init();
+    logging::init();
 
-    // Create the application state
-    // String is used here, but it can be anything
-    // Invoke in hanlders using data: AppState<'_, String>
-    let data = new_state::<String>();
+    // Create the application state, dropping expired entries in the background
+    // Invoke in hanlders using data: AppState
+    let data = new_state();
+    start_eviction(data.clone());
+
+    // Create the cache once so that every worker shares the same backend
+    // Invoke in handlers using cache: Cache
+    let cache = new_cache();
+
+    // Create the database pool once so that every worker shares its connections
+    // Invoke in handlers using pool: Data<PoolType>
+    let pool = new_pool().expect("Failed to create connection pool");
+
+    // Export finished spans in the background, if an exporter is set
+    start_export();
+
+    // Keep a handle of each to close them at shutdown
+    let (shutdown_pool, shutdown_cache) = (pool.clone(), cache.clone());
 
     let mut listenfd = ListenFd::from_env();
     let mut server = HttpServer::new(move || {
         App::new()
-            .configure(add_cache)
-            .wrap(Cors::new().supports_credentials().finish())
-            .wrap(Logger::default())
-            .wrap(get_identity_service())
-            .configure(add_pool)
+            .app_data(cache.clone())
+            .wrap(Cors::from_config())
+            .wrap(SecurityHeaders::from_config())
+            .wrap(AccessLog)
+            .wrap(get_identity_service(cache.clone()))
+            .wrap(Localize)
+            .wrap(RequestId)
+            .wrap(Tracing)
+            .wrap(Metrics)
+            .app_data(json_config())
+            .app_data(query_config())
+            .app_data(path_config())
+            .app_data(form_config())
+            .configure(add_pool(pool.clone()))
+            .configure(add_repositories(pool.clone()))
             .app_data(data.clone())
             .configure(routes)
     });
 
-    server = if let Some(l) = listenfd.take_tcp_listener(0)? {
-        server.listen(l)?
+    // Terminate TLS when a certificate is set
+    let tls_config = if tls::enabled() {
+        Some(tls::server_config()?)
     } else {
-        server.bind(&CONFIG.server)?
+        None
+    };
+    server = match (listenfd.take_tcp_listener(0)?, tls_config) {
+        (Some(l), Some(config)) => server.listen_rustls(l, config)?,
+        (Some(l), None) => server.listen(l)?,
+        (None, Some(config)) => server.bind_rustls(&CONFIG.server, config)?,
+        (None, None) => server.bind(&CONFIG.server)?,
     };
 
-    server.run().await
+    // Shutdown is handled here, so that readiness fails before draining
+    let mut servers = vec![server
+        .disable_signals()
+        .shutdown_timeout(CONFIG.shutdown_timeout)
+        .run()];
+
+    // Keep metrics off the public address when an admin address is set
+    if !CONFIG.metrics_server.is_empty() {
+        let admin = HttpServer::new(|| App::new().configure(metrics_routes))
+            .workers(1)
+            .disable_signals()
+            .bind(&CONFIG.metrics_server)?;
+        servers.push(admin.run());
+    }
+
+    // Send plain HTTP requests to HTTPS
+    if !CONFIG.tls_redirect_server.is_empty() {
+        let redirect = HttpServer::new(|| App::new().configure(redirect_routes))
+            .workers(1)
+            .disable_signals()
+            .bind(&CONFIG.tls_redirect_server)?;
+        servers.push(redirect.run());
+    }
+
+    shutdown::stop_on_signal(servers.clone());
+    try_join_all(servers).await?;
+
+    shutdown::finish(shutdown_pool, shutdown_cache).await;
+    Ok(())
 }
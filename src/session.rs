@@ -0,0 +1,440 @@
+//! Server-side sessions for the identity service
+//!
+//! The cookie only holds an opaque session id. The session, including the
+//! JWT, is stored in the application cache (Redis when `REDIS_URL` is set)
+//! and expires after `SESSION_TIMEOUT` minutes without a request.
+//!
+//! The ids of a user's sessions are indexed in a set so that they can be
+//! listed and killed. Ids are added and removed atomically, the set expires
+//! along with the user's last active session, and expired ids are pruned
+//! from it when it's read.
+
+use crate::auth::decode_jwt;
+use crate::cache::{self, Cache};
+use crate::config::CONFIG;
+use crate::errors::ApiError;
+use actix_identity::IdentityPolicy;
+use actix_web::cookie::Cookie;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::USER_AGENT;
+use actix_web::{Error, HttpMessage};
+use chrono::{DateTime, Utc};
+use futures::future::{FutureExt, LocalBoxFuture};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Where the identity service keeps sessions
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStore {
+    /// The whole JWT in a private cookie
+    Cookie,
+    /// An opaque session id in the cookie, and the session in the cache
+    Redis,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub identity: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+/// The session id read from the cookie, kept for the response
+struct SessionId(Uuid);
+
+/// Cache key of a session
+///
+/// Sessions aren't versioned, so bumping `CACHE_VERSION` doesn't log everyone out.
+fn session_key(id: Uuid) -> String {
+    format!("{}:sessions:{}", CONFIG.cache_prefix, id)
+}
+
+/// Cache key of the set of ids of a user's sessions
+fn user_sessions_key(user_id: Uuid) -> String {
+    format!("{}:user_sessions:{}", CONFIG.cache_prefix, user_id)
+}
+
+/// Sessions expire after `SESSION_TIMEOUT` minutes without a request
+fn timeout() -> Duration {
+    Duration::from_secs(CONFIG.session_timeout as u64 * 60)
+}
+
+impl Session {
+    /// Start a session for the identity (a JWT)
+    pub fn new(
+        identity: String,
+        ip: Option<String>,
+        user_agent: Option<String>,
+    ) -> Result<Self, ApiError> {
+        let user_id = decode_jwt(&identity)?.user_id;
+        let now = Utc::now();
+        Ok(Self {
+            id: Uuid::new_v4(),
+            user_id,
+            identity,
+            ip,
+            user_agent,
+            created_at: now,
+            last_seen_at: now,
+        })
+    }
+}
+
+/// Store a new session and index it under its user
+pub async fn create_session(cache: Cache, session: &Session) -> Result<(), ApiError> {
+    cache::set_ex(cache.clone(), &session_key(session.id), session, timeout()).await?;
+    index_session(cache, session).await
+}
+
+/// Find a session, or None if it has expired or been killed
+pub async fn find_session(cache: Cache, id: Uuid) -> Result<Option<Session>, ApiError> {
+    cache::get(cache, &session_key(id)).await
+}
+
+/// Mark the session as seen now and restart its expiry, and its index's
+///
+/// Returns None, rather than storing it again, if the session was killed
+/// since it was found.
+pub async fn touch_session(
+    cache: Cache,
+    mut session: Session,
+) -> Result<Option<Session>, ApiError> {
+    session.last_seen_at = Utc::now();
+    let key = session_key(session.id);
+    if !cache::set_ex_if_present(cache.clone(), &key, &session, timeout()).await? {
+        return Ok(None);
+    }
+    index_session(cache, &session).await?;
+    Ok(Some(session))
+}
+
+/// List the active sessions of a user, oldest first
+pub async fn list_sessions(cache: Cache, user_id: Uuid) -> Result<Vec<Session>, ApiError> {
+    let ids = session_ids(cache.clone(), user_id).await?;
+    let keys = ids.iter().map(|id| session_key(*id)).collect::<Vec<_>>();
+    let found = cache::mget::<_, Session>(cache.clone(), &keys).await?;
+
+    let mut sessions = Vec::new();
+    let mut expired = Vec::new();
+    for (id, session) in ids.into_iter().zip(found) {
+        match session {
+            Some(session) => sessions.push(session),
+            None => expired.push(id.to_string()),
+        }
+    }
+
+    // Prune the ids of expired sessions
+    if !expired.is_empty() {
+        cache
+            .remove_members(&user_sessions_key(user_id), &expired)
+            .await?;
+    }
+    sessions.sort_by_key(|session| session.created_at);
+    Ok(sessions)
+}
+
+/// Kill a session of a user
+pub async fn delete_session(cache: Cache, user_id: Uuid, id: Uuid) -> Result<(), ApiError> {
+    let removed = cache
+        .remove_members(&user_sessions_key(user_id), &[id.to_string()])
+        .await?;
+    if removed == 0 {
+        return Err(ApiError::NotFound(format!("Session {} not found", id)));
+    }
+    cache::delete(cache, &session_key(id)).await
+}
+
+/// Kill every session of a user
+pub async fn delete_sessions(cache: Cache, user_id: Uuid) -> Result<(), ApiError> {
+    let ids = session_ids(cache.clone(), user_id).await?;
+    let keys = ids.iter().map(|id| session_key(*id)).collect::<Vec<_>>();
+    cache::delete_many(cache.clone(), &keys).await?;
+
+    // Only remove the killed ids, keeping any session started meanwhile
+    let ids = ids.iter().map(Uuid::to_string).collect::<Vec<_>>();
+    cache
+        .remove_members(&user_sessions_key(user_id), &ids)
+        .await
+        .map(|_| ())
+}
+
+/// Add the session to its user's index, which lives as long as the last active session
+async fn index_session(cache: Cache, session: &Session) -> Result<(), ApiError> {
+    let key = user_sessions_key(session.user_id);
+    cache
+        .add_member(&key, &session.id.to_string(), timeout())
+        .await
+}
+
+async fn session_ids(cache: Cache, user_id: Uuid) -> Result<Vec<Uuid>, ApiError> {
+    let members = cache.members(&user_sessions_key(user_id)).await?;
+    Ok(members
+        .iter()
+        .filter_map(|member| Uuid::parse_str(member).ok())
+        .collect())
+}
+
+/// Identity policy that keeps sessions on the server
+///
+/// Each request with a live session restarts its expiry, and so does the
+/// cookie. Logging in starts a new session and logging out kills it.
+pub struct SessionIdentityPolicy {
+    cache: Cache,
+}
+
+impl SessionIdentityPolicy {
+    pub fn new(cache: Cache) -> Self {
+        Self { cache }
+    }
+
+    /// The session cookie, removed by the browser after `SESSION_TIMEOUT` minutes
+    fn cookie(value: String, max_age: chrono::Duration) -> Cookie<'static> {
+        Cookie::build(CONFIG.session_name.clone(), value)
+            .path("/")
+            .http_only(true)
+            .secure(CONFIG.session_secure)
+            .max_age_time(max_age)
+            .finish()
+    }
+}
+
+impl IdentityPolicy for SessionIdentityPolicy {
+    type Future = LocalBoxFuture<'static, Result<Option<String>, Error>>;
+    type ResponseFuture = LocalBoxFuture<'static, Result<(), Error>>;
+
+    fn from_request(&self, request: &mut ServiceRequest) -> Self::Future {
+        let id = request
+            .cookie(&CONFIG.session_name)
+            .and_then(|cookie| Uuid::parse_str(cookie.value()).ok());
+        let id = match id {
+            Some(id) => id,
+            None => return async { Ok(None) }.boxed_local(),
+        };
+        request.extensions_mut().insert(SessionId(id));
+
+        let cache = self.cache.clone();
+        async move {
+            match find_session(cache.clone(), id).await? {
+                Some(session) => {
+                    let session = touch_session(cache, session).await?;
+                    Ok(session.map(|session| session.identity))
+                }
+                None => Ok(None),
+            }
+        }
+        .boxed_local()
+    }
+
+    fn to_response<B>(
+        &self,
+        identity: Option<String>,
+        changed: bool,
+        response: &mut ServiceResponse<B>,
+    ) -> Self::ResponseFuture {
+        let max_age = chrono::Duration::minutes(CONFIG.session_timeout);
+        let previous = response
+            .request()
+            .extensions()
+            .get::<SessionId>()
+            .map(|session_id| session_id.0);
+
+        // Slide the cookie's expiry along with the session's
+        if !changed {
+            if let (Some(id), Some(_)) = (previous, identity) {
+                let _ = response
+                    .response_mut()
+                    .add_cookie(&Self::cookie(id.to_string(), max_age));
+            }
+            return async { Ok(()) }.boxed_local();
+        }
+
+        let ip = response
+            .request()
+            .connection_info()
+            .remote()
+            .map(String::from);
+        let user_agent = response
+            .request()
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let cache = self.cache.clone();
+
+        match identity {
+            Some(identity) => {
+                let session = match Session::new(identity, ip, user_agent) {
+                    Ok(session) => session,
+                    Err(error) => return async { Err(error.into()) }.boxed_local(),
+                };
+                let _ = response
+                    .response_mut()
+                    .add_cookie(&Self::cookie(session.id.to_string(), max_age));
+                async move {
+                    // Kill the previous session so that its id can't be reused
+                    end_session(cache.clone(), previous).await?;
+                    create_session(cache, &session).await?;
+                    Ok(())
+                }
+                .boxed_local()
+            }
+            None => {
+                let _ = response
+                    .response_mut()
+                    .add_cookie(&Self::cookie("".into(), chrono::Duration::zero()));
+                async move {
+                    end_session(cache, previous).await?;
+                    Ok(())
+                }
+                .boxed_local()
+            }
+        }
+    }
+}
+
+/// Kill the session from the request's cookie, if it's still alive
+async fn end_session(cache: Cache, id: Option<Uuid>) -> Result<(), ApiError> {
+    if let Some(id) = id {
+        if let Some(session) = find_session(cache.clone(), id).await? {
+            return delete_session(cache, session.user_id, id).await;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{create_jwt, PrivateClaim};
+    use crate::tests::helpers::tests::get_cache;
+    use actix_identity::{Identity, IdentityService};
+    use actix_web::{test, web, App, HttpResponse};
+
+    fn jwt(user_id: Uuid) -> String {
+        create_jwt(PrivateClaim::new(user_id, "test@test.com".into())).unwrap()
+    }
+
+    /// An app whose routes login, read and forget the identity
+    macro_rules! app {
+        ($cache:expr, $user_id:expr) => {{
+            let identity = jwt($user_id);
+            test::init_service(
+                App::new()
+                    .wrap(IdentityService::new(SessionIdentityPolicy::new($cache)))
+                    .route(
+                        "/login",
+                        web::get().to(move |id: Identity| {
+                            id.remember(identity.clone());
+                            HttpResponse::Ok()
+                        }),
+                    )
+                    .route(
+                        "/identity",
+                        web::get().to(|id: Identity| match id.identity() {
+                            Some(_) => HttpResponse::Ok(),
+                            None => HttpResponse::Unauthorized(),
+                        }),
+                    )
+                    .route(
+                        "/logout",
+                        web::get().to(|id: Identity| {
+                            id.forget();
+                            HttpResponse::Ok()
+                        }),
+                    ),
+            )
+            .await
+        }};
+    }
+
+    macro_rules! call {
+        ($app:expr, $uri:expr, $cookie:expr) => {{
+            let request = test::TestRequest::get().uri($uri).cookie($cookie.clone());
+            test::call_service(&mut $app, request.to_request()).await
+        }};
+    }
+
+    macro_rules! login {
+        ($app:expr) => {{
+            let request = test::TestRequest::get().uri("/login").to_request();
+            let response = test::call_service(&mut $app, request).await;
+            response.response().cookies().next().unwrap().into_owned()
+        }};
+    }
+
+    #[actix_rt::test]
+    async fn it_keeps_an_opaque_session_id_in_the_cookie() {
+        let cache = get_cache();
+        let user_id = Uuid::new_v4();
+        let mut app = app!(cache.clone(), user_id);
+        let cookie = login!(app);
+        let id = Uuid::parse_str(cookie.value()).unwrap();
+        let session = find_session(cache, id).await.unwrap().unwrap();
+        assert_eq!(session.user_id, user_id);
+        assert_eq!(decode_jwt(&session.identity).unwrap().user_id, user_id);
+    }
+
+    #[actix_rt::test]
+    async fn it_restarts_the_expiry_of_a_session_on_each_request() {
+        let cache = get_cache();
+        let mut app = app!(cache.clone(), Uuid::new_v4());
+        let cookie = login!(app);
+        let id = Uuid::parse_str(cookie.value()).unwrap();
+        let before = find_session(cache.clone(), id).await.unwrap().unwrap();
+        let response = call!(app, "/identity", cookie);
+        assert!(response.status().is_success());
+        assert!(response.response().cookies().next().is_some());
+        let after = find_session(cache, id).await.unwrap().unwrap();
+        assert!(after.last_seen_at > before.last_seen_at);
+    }
+
+    #[actix_rt::test]
+    async fn it_ends_a_session_on_logout() {
+        let cache = get_cache();
+        let user_id = Uuid::new_v4();
+        let mut app = app!(cache.clone(), user_id);
+        let cookie = login!(app);
+        call!(app, "/logout", cookie);
+        let response = call!(app, "/identity", cookie);
+        assert_eq!(response.status(), 401);
+        assert!(list_sessions(cache, user_id).await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn it_rejects_a_killed_session() {
+        let cache = get_cache();
+        let user_id = Uuid::new_v4();
+        let mut app = app!(cache.clone(), user_id);
+        let cookie = login!(app);
+        login!(app);
+        assert_eq!(
+            list_sessions(cache.clone(), user_id).await.unwrap().len(),
+            2
+        );
+        delete_sessions(cache, user_id).await.unwrap();
+        let response = call!(app, "/identity", cookie);
+        assert_eq!(response.status(), 401);
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_restore_a_session_killed_before_it_is_touched() {
+        let cache = get_cache();
+        let user_id = Uuid::new_v4();
+        let session = Session::new(jwt(user_id), None, None).unwrap();
+        create_session(cache.clone(), &session).await.unwrap();
+
+        // The session was found by a request, then killed before it was touched
+        delete_session(cache.clone(), user_id, session.id)
+            .await
+            .unwrap();
+        let touched = touch_session(cache.clone(), session.clone()).await.unwrap();
+        assert_eq!(touched, None);
+        assert_eq!(find_session(cache.clone(), session.id).await.unwrap(), None);
+        assert!(list_sessions(cache, user_id).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,145 @@
+//! Shut down gracefully on SIGTERM or SIGINT
+//!
+//! On a signal, readiness fails first so that load balancers stop sending
+//! new requests. After `SHUTDOWN_DELAY` seconds the servers stop accepting
+//! connections and drain in-flight requests for up to `SHUTDOWN_TIMEOUT`
+//! seconds, after which the remaining connections are dropped.
+//!
+//! Once the servers have stopped, background tasks started with `spawn` are
+//! cancelled, pending spans are flushed, the cache is closed, which stops the
+//! Redis actor, and the database pool is closed.
+
+use crate::cache::Cache;
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::metrics::unregister_pools;
+use crate::telemetry;
+use actix_rt::time::delay_for;
+use actix_web::dev::Server;
+use futures::{
+    channel::oneshot,
+    future::{join_all, select},
+    Future,
+};
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref TASKS: Mutex<Vec<oneshot::Sender<()>>> = Mutex::new(Vec::new());
+}
+
+/// Whether a shutdown has started, which fails the readiness probe
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Run a background task on the current runtime until shutdown
+pub fn spawn<F>(task: F)
+where
+    F: Future<Output = ()> + 'static,
+{
+    let (cancel, cancelled) = oneshot::channel();
+    TASKS.lock().expect("Could not acquire lock").push(cancel);
+    actix_rt::spawn(async move {
+        select(Box::pin(task), cancelled).await;
+    });
+}
+
+/// Stop the servers gracefully on the first SIGTERM or SIGINT
+///
+/// The servers need their own signal handling disabled, so that they keep
+/// accepting connections while readiness fails.
+pub fn stop_on_signal(servers: Vec<Server>) {
+    actix_rt::spawn(async move {
+        signal().await;
+        SHUTTING_DOWN.store(true, Ordering::SeqCst);
+        info!(
+            "Shutting down, readiness fails for {}s before draining connections",
+            CONFIG.shutdown_delay
+        );
+        delay_for(Duration::from_secs(CONFIG.shutdown_delay)).await;
+        join_all(servers.iter().map(|server| server.stop(true))).await;
+    });
+}
+
+/// Clean up after the servers have stopped
+pub async fn finish(pool: PoolType, cache: Cache) {
+    cancel_tasks();
+    telemetry::flush().await;
+    cache.close().await;
+    close_pool(pool);
+    info!("Shut down");
+}
+
+/// Drop the handles of the pool kept outside of the workers
+///
+/// r2d2 closes the connections once the last handle is dropped. Those of the
+/// workers are dropped with them, and those of the metrics here.
+fn close_pool(pool: PoolType) {
+    unregister_pools();
+    let state = pool.state();
+    info!(
+        "Closing the database pool, {} of {} connections idle",
+        state.idle_connections, state.connections
+    );
+    drop(pool);
+}
+
+/// Cancel the tasks started with `spawn`
+fn cancel_tasks() {
+    for cancel in TASKS.lock().expect("Could not acquire lock").drain(..) {
+        let _ = cancel.send(());
+    }
+}
+
+#[cfg(unix)]
+async fn signal() {
+    use actix_rt::signal::{
+        ctrl_c,
+        unix::{signal, SignalKind},
+    };
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            select(Box::pin(terminate.recv()), Box::pin(ctrl_c())).await;
+        }
+        Err(error) => {
+            warn!("Could not listen for SIGTERM: {}", error);
+            let _ = ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() {
+    let _ = actix_rt::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[actix_rt::test]
+    async fn it_cancels_background_tasks() {
+        let ticks = Rc::new(Cell::new(0));
+        let counter = ticks.clone();
+        spawn(async move {
+            loop {
+                counter.set(counter.get() + 1);
+                delay_for(Duration::from_millis(10)).await;
+            }
+        });
+        delay_for(Duration::from_millis(50)).await;
+        cancel_tasks();
+        let cancelled_at = ticks.get();
+        delay_for(Duration::from_millis(50)).await;
+        assert!(cancelled_at > 0);
+        assert_eq!(ticks.get(), cancelled_at);
+    }
+}
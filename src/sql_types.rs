@@ -0,0 +1,168 @@
+//! SQL types that map to the native column type of every backend
+//!
+//! Diesel's own `Uuid` and `Timestamptz` types only exist on Postgres, so
+//! the schema uses these instead. Models bind and load values through the
+//! `DbUuid` and `DbTimestamp` wrappers, since diesel's traits can't be
+//! implemented for the foreign types directly. Values are converted without
+//! panicking: a malformed column is reported as a deserialization error.
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::mysql::Mysql;
+use diesel::pg::Pg;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types;
+use diesel::sqlite::Sqlite;
+use std::io::Write;
+
+/// A UUID: `UUID` on Postgres, `BINARY(16)` on MySQL and text on SQLite
+#[derive(Clone, Copy, Debug, Default, QueryId, SqlType)]
+#[postgres(oid = "2950", array_oid = "2951")]
+#[mysql_type = "Blob"]
+#[sqlite_type = "Text"]
+pub struct Uuid;
+
+/// A UTC timestamp: `TIMESTAMPTZ` on Postgres, `DATETIME` holding UTC on
+/// MySQL and text on SQLite
+#[derive(Clone, Copy, Debug, Default, QueryId, SqlType)]
+#[postgres(oid = "1184", array_oid = "1185")]
+#[mysql_type = "DateTime"]
+#[sqlite_type = "Text"]
+pub struct UtcTimestamp;
+
+/// A `uuid::Uuid` that can be bound to and loaded from a `Uuid` column
+#[derive(Clone, Copy, Debug, PartialEq, AsExpression, FromSqlRow)]
+#[sql_type = "Uuid"]
+pub struct DbUuid(pub uuid::Uuid);
+
+/// A `DateTime<Utc>` that can be bound to and loaded from a `UtcTimestamp` column
+#[derive(Clone, Copy, Debug, PartialEq, AsExpression, FromSqlRow)]
+#[sql_type = "UtcTimestamp"]
+pub struct DbTimestamp(pub DateTime<Utc>);
+
+impl From<DbUuid> for uuid::Uuid {
+    fn from(uuid: DbUuid) -> Self {
+        uuid.0
+    }
+}
+
+impl From<DbTimestamp> for DateTime<Utc> {
+    fn from(timestamp: DbTimestamp) -> Self {
+        timestamp.0
+    }
+}
+
+impl ToSql<Uuid, Pg> for DbUuid {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        ToSql::<sql_types::Uuid, Pg>::to_sql(&self.0, out)
+    }
+}
+
+impl FromSql<Uuid, Pg> for DbUuid {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        FromSql::<sql_types::Uuid, Pg>::from_sql(bytes).map(DbUuid)
+    }
+}
+
+impl ToSql<Uuid, Mysql> for DbUuid {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Mysql>) -> serialize::Result {
+        out.write_all(self.0.as_bytes())
+            .map(|_| IsNull::No)
+            .map_err(Into::into)
+    }
+}
+
+impl FromSql<Uuid, Mysql> for DbUuid {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let bytes = bytes.ok_or("Unexpected null for non-null column")?;
+        uuid::Uuid::from_slice(bytes)
+            .map(DbUuid)
+            .map_err(Into::into)
+    }
+}
+
+impl ToSql<Uuid, Sqlite> for DbUuid {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Sqlite>) -> serialize::Result {
+        let text = self.0.to_hyphenated().to_string();
+        ToSql::<sql_types::Text, Sqlite>::to_sql(&text, out)
+    }
+}
+
+impl FromSql<Uuid, Sqlite> for DbUuid {
+    fn from_sql(value: Option<&<Sqlite as Backend>::RawValue>) -> deserialize::Result<Self> {
+        let text = <String as FromSql<sql_types::Text, Sqlite>>::from_sql(value)?;
+        uuid::Uuid::parse_str(&text).map(DbUuid).map_err(Into::into)
+    }
+}
+
+impl ToSql<UtcTimestamp, Pg> for DbTimestamp {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        ToSql::<sql_types::Timestamptz, Pg>::to_sql(&self.0, out)
+    }
+}
+
+impl FromSql<UtcTimestamp, Pg> for DbTimestamp {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        FromSql::<sql_types::Timestamptz, Pg>::from_sql(bytes).map(DbTimestamp)
+    }
+}
+
+impl ToSql<UtcTimestamp, Mysql> for DbTimestamp {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Mysql>) -> serialize::Result {
+        ToSql::<sql_types::Datetime, Mysql>::to_sql(&self.0.naive_utc(), out)
+    }
+}
+
+impl FromSql<UtcTimestamp, Mysql> for DbTimestamp {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let naive = <NaiveDateTime as FromSql<sql_types::Datetime, Mysql>>::from_sql(bytes)?;
+        Ok(DbTimestamp(DateTime::from_utc(naive, Utc)))
+    }
+}
+
+impl ToSql<UtcTimestamp, Sqlite> for DbTimestamp {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Sqlite>) -> serialize::Result {
+        ToSql::<sql_types::Timestamp, Sqlite>::to_sql(&self.0.naive_utc(), out)
+    }
+}
+
+impl FromSql<UtcTimestamp, Sqlite> for DbTimestamp {
+    fn from_sql(value: Option<&<Sqlite as Backend>::RawValue>) -> deserialize::Result<Self> {
+        let naive = <NaiveDateTime as FromSql<sql_types::Timestamp, Sqlite>>::from_sql(value)?;
+        Ok(DbTimestamp(DateTime::from_utc(naive, Utc)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DbUuid;
+    use crate::models::user::{create, User};
+    use crate::schema::users::dsl::{id, users};
+    use crate::tests::helpers::tests::{get_conn, new_user};
+    use diesel::prelude::*;
+
+    #[test]
+    fn it_round_trips_uuids_and_timestamps() {
+        let conn = get_conn();
+        let user = new_user();
+        create(&*conn, &user).unwrap();
+        let found = users
+            .filter(id.eq(DbUuid(user.id)))
+            .first::<User>(&*conn)
+            .unwrap();
+        assert_eq!(found, user);
+    }
+
+    #[test]
+    fn it_reports_a_malformed_uuid_as_an_error() {
+        let conn = get_conn();
+        conn.execute(
+            "INSERT INTO users VALUES ('not-a-uuid', 'a', 'b', 'c', 'd', 'e', \
+             CURRENT_TIMESTAMP, 'f', CURRENT_TIMESTAMP)",
+        )
+        .unwrap();
+        let ids = users.select(id).load::<DbUuid>(&*conn);
+        assert!(ids.is_err());
+    }
+}
@@ -1,67 +1,242 @@
+//! A typed, concurrent in-process store
+//!
+//! Entries live in a sharded map, so requests only contend when their keys
+//! share a shard. Each entry is read and written through a typed `Slot`,
+//! letting one store hold feature flags, counters and small caches side by side.
+//!
+//! Entries may expire. Expired entries are never returned, and are dropped
+//! in the background by `start_eviction`.
+
+use crate::shutdown;
+use actix_rt::time::interval;
 use actix_web::web::Data;
-use std::collections::HashMap;
-use std::sync::Mutex;
-
-// State is just a hashmap
-pub type State<'a, T> = HashMap<&'a str, T>;
-pub type AppState<'a, T> = Data<Mutex<State<'a, T>>>;
-
-/// Create a new state instance and wrap in a mutex.
-/// Further wrap into an Actix Data instance.
-pub fn new_state<'a, T>() -> AppState<'a, T> {
-    let state = State::<T>::new();
-    Data::new(Mutex::new(state))
+use dashmap::DashMap;
+use std::any::Any;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+/// Extract the store in a handler
+pub type AppState = Data<Store>;
+
+/// How often expired entries are dropped
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A typed key in the store
+///
+/// e.g. `Slot::<u64>::new(format!("logins:{}", user_id))`
+pub struct Slot<T> {
+    key: String,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T> Slot<T> {
+    pub fn new<K: Into<String>>(key: K) -> Self {
+        Self {
+            key: key.into(),
+            value: PhantomData,
+        }
+    }
 }
 
-/// Sets an entry in the application state by key.
-/// Returns Some(T) only if the entry exists (update operation).
-/// Returns None if the entry did not alreay exist (insert operation).
-#[allow(dead_code)]
-pub fn set<'a, T>(data: AppState<'a, T>, key: &'a str, value: T) -> Option<T> {
-    let mut hashmap = data.lock().expect("Could not acquire lock");
-    hashmap.insert(key, value)
+struct Entry {
+    value: Box<dyn Any + Send + Sync>,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn new<T: Send + Sync + 'static>(value: T, ttl: Option<Duration>) -> Self {
+        Self {
+            value: Box::new(value),
+            expires_at: ttl.map(|ttl| Instant::now() + ttl),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Instant::now())
+    }
+
+    /// The value, if it hasn't expired and has the type of the slot
+    fn typed<T: 'static>(&self) -> Option<&T> {
+        if self.is_expired() {
+            return None;
+        }
+        self.value.downcast_ref()
+    }
+
+    fn into_value<T: 'static>(self) -> Option<T> {
+        if self.is_expired() {
+            return None;
+        }
+        self.value.downcast().ok().map(|value| *value)
+    }
 }
 
-/// Get a copy of an application state entry by key.
-/// Returns Some(T) only if the entry exists.
-#[allow(dead_code)]
-pub fn get<'a, T>(data: AppState<'a, T>, key: &'a str) -> Option<T>
-where
-    T: Clone,
-{
-    let hashmap = data.lock().expect("Could not acquire lock");
-    Some(hashmap.get(key)?.to_owned())
+#[derive(Default)]
+pub struct Store {
+    entries: DashMap<String, Entry>,
 }
 
-/// Removes an entry in the application state by key.
-/// Returns Some(T) only if the entry existed before removal.
-#[allow(dead_code)]
-pub fn delete<'a, T>(data: AppState<'a, T>, key: &'a str) -> Option<T> {
-    let mut hashmap = data.lock().expect("Could not acquire lock");
-    hashmap.remove(key)
+impl Store {
+    /// Get a copy of an entry
+    ///
+    /// Returns None if the entry is missing, expired or of another type.
+    pub fn get<T: Clone + 'static>(&self, slot: &Slot<T>) -> Option<T> {
+        self.entries.get(&slot.key)?.typed::<T>().cloned()
+    }
+
+    /// Atomically update an entry
+    ///
+    /// The update receives the current value, or None, and returns the new one.
+    /// A new entry expires after the ttl, if any, and an existing one keeps
+    /// its expiry.
+    pub fn update<T, F>(&self, slot: &Slot<T>, ttl: Option<Duration>, update: F) -> T
+    where
+        T: Clone + Send + Sync + 'static,
+        F: FnOnce(Option<T>) -> T,
+    {
+        let mut entry = self
+            .entries
+            .entry(slot.key.clone())
+            .or_insert_with(|| Entry::new((), None));
+        let current = entry.typed::<T>().cloned();
+        if current.is_none() {
+            entry.expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        }
+        let value = update(current);
+        entry.value = Box::new(value.clone());
+        value
+    }
+
+    /// Delete an entry
+    ///
+    /// Returns the deleted value, if any.
+    pub fn delete<T: 'static>(&self, slot: &Slot<T>) -> Option<T> {
+        self.entries
+            .remove(&slot.key)
+            .and_then(|(_, entry)| entry.into_value())
+    }
+
+    /// Drop every expired entry, returning how many were dropped
+    pub fn evict_expired(&self) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| !entry.is_expired());
+        before - self.entries.len()
+    }
+
+    /// The number of entries, including expired ones not yet evicted
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Create a new store wrapped in an Actix Data instance
+pub fn new_state() -> AppState {
+    Data::new(Store::default())
+}
+
+/// Drop expired entries every `EVICTION_INTERVAL` until shutdown
+pub fn start_eviction(state: AppState) {
+    shutdown::spawn(async move {
+        let mut interval = interval(EVICTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            state.evict_expired();
+        }
+    });
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::tests::helpers::tests::app_state;
+    use std::thread::sleep;
+
+    fn set<T: Clone + Send + Sync + 'static>(data: &AppState, slot: &Slot<T>, value: T) {
+        data.update(slot, None, |_| value);
+    }
 
     #[test]
     fn it_creates_new_application_state_and_sets_and_reads_it() {
         let data = app_state();
-        set(data.clone(), "testing", "123".into());
-        let value = get(data, "testing");
-        assert_eq!(value, Some("123".to_string()));
+        let slot = Slot::<String>::new("testing");
+        assert_eq!(data.get(&slot), None);
+        set(&data, &slot, "123".into());
+        assert_eq!(data.get(&slot), Some("123".to_string()));
     }
 
     #[test]
     fn it_removes_an_entry_in_application_state() {
         let data = app_state();
-        set(data.clone(), "testing", "123".into());
-        let value = get(data.clone(), "testing");
-        assert_eq!(value, Some("123".to_string()));
-        delete(data.clone(), "testing");
-        let value = get(data, "testing");
-        assert_eq!(value, None);
+        let slot = Slot::<String>::new("testing");
+        set(&data, &slot, "123".into());
+        assert_eq!(data.delete(&slot), Some("123".to_string()));
+        assert_eq!(data.get(&slot), None);
+    }
+
+    #[test]
+    fn it_keeps_values_of_different_types() {
+        let data = app_state();
+        let flag = Slot::<bool>::new("feature:signup");
+        let counter = Slot::<u64>::new(format!("logins:{}", 1));
+        set(&data, &flag, true);
+        set(&data, &counter, 3);
+        assert_eq!(data.get(&flag), Some(true));
+        assert_eq!(data.get(&counter), Some(3));
+        assert_eq!(data.get(&Slot::<String>::new("feature:signup")), None);
+    }
+
+    #[test]
+    fn it_expires_an_entry() {
+        let data = app_state();
+        let slot = Slot::<String>::new("testing");
+        let ttl = Some(Duration::from_millis(10));
+        data.update(&slot, ttl, |_| "123".to_string());
+        assert_eq!(data.get(&slot), Some("123".to_string()));
+        sleep(Duration::from_millis(20));
+        assert_eq!(data.get(&slot), None);
+        assert_eq!(data.evict_expired(), 1);
+        assert_eq!(data.len(), 0);
+    }
+
+    #[test]
+    fn it_updates_an_entry() {
+        let data = app_state();
+        let slot = Slot::<u64>::new("counter");
+        let increment = |count: Option<u64>| count.unwrap_or(0) + 1;
+        assert_eq!(data.update(&slot, None, increment), 1);
+        assert_eq!(data.update(&slot, None, increment), 2);
+        assert_eq!(data.get(&slot), Some(2));
+    }
+
+    #[test]
+    fn it_keeps_the_expiry_of_an_updated_entry() {
+        let data = app_state();
+        let slot = Slot::<u64>::new("counter");
+        let increment = |count: Option<u64>| count.unwrap_or(0) + 1;
+        data.update(&slot, Some(Duration::from_millis(10)), increment);
+        data.update(&slot, Some(Duration::from_secs(60)), increment);
+        sleep(Duration::from_millis(20));
+        assert_eq!(data.update(&slot, None, increment), 1);
+    }
+
+    #[test]
+    fn it_updates_entries_from_many_threads() {
+        let data = app_state();
+        let threads = (0..8)
+            .map(|_| {
+                let data = data.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        let slot = Slot::<u64>::new("counter");
+                        data.update(&slot, None, |count| count.unwrap_or(0) + 1);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(data.get(&Slot::<u64>::new("counter")), Some(800));
     }
 }
@@ -0,0 +1,536 @@
+//! Distributed tracing with W3C `traceparent` propagation
+//!
+//! The Tracing middleware starts a server span for each request, continuing
+//! the trace of an incoming `traceparent` header, and returns the span's
+//! `traceparent` on the response. Other spans are children of the current
+//! span, which follows the request:
+//!
+//! - through async code that runs `in_context`, as the middleware's does
+//! - onto the threadpool with `block`, in place of `web::block`
+//! - into blocking code that `enter`s a span, like the diesel queries
+//!
+//! New traces are sampled by the ratio of `OTEL_TRACES_SAMPLER_ARG`, and
+//! continued traces follow the sampled flag of their `traceparent`.
+//!
+//! Finished spans are exported in OTLP's JSON encoding every
+//! `EXPORT_INTERVAL`, to the log or an OTLP/HTTP collector as set by
+//! `OTEL_TRACES_EXPORTER`.
+
+use crate::config::CONFIG;
+use crate::shutdown;
+use actix_rt::time::interval;
+use actix_web::{client::Client, error::BlockingError, web};
+use futures::Future;
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::json;
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Header of the W3C trace context
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// How often finished spans are exported
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Finished spans kept for the next export, newer spans are dropped
+const MAX_BUFFERED_SPANS: usize = 2048;
+
+/// Where finished spans are sent
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceExporter {
+    /// Spans are propagated but not exported
+    None,
+    /// A JSON span per line, logged under the `traces` target
+    #[serde(alias = "stdout")]
+    Log,
+    /// POSTed to `{OTEL_EXPORTER_OTLP_ENDPOINT}/v1/traces`
+    Otlp,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<SpanContext>> = const { RefCell::new(None) };
+}
+
+lazy_static! {
+    static ref FINISHED: Mutex<Vec<SpanData>> = Mutex::new(Vec::new());
+}
+
+/// The ids that identify a span across services
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: String,
+    pub span_id: String,
+    pub sampled: bool,
+}
+
+impl SpanContext {
+    /// The first span of a new trace
+    fn root() -> Self {
+        let trace_id = Uuid::new_v4().to_simple().to_string();
+        Self {
+            sampled: is_sampled(&trace_id, CONFIG.otel_traces_sampler_arg),
+            trace_id,
+            span_id: span_id(),
+        }
+    }
+
+    /// A new span in the same trace
+    fn child(&self) -> Self {
+        Self {
+            span_id: span_id(),
+            ..self.clone()
+        }
+    }
+
+    /// Parse a `traceparent` header
+    ///
+    /// e.g. `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`
+    pub fn from_traceparent(traceparent: &str) -> Option<Self> {
+        let parts = traceparent.trim().split('-').collect::<Vec<_>>();
+        if parts.len() < 4 || parts[0] == "ff" || !is_hex(parts[0], 2) {
+            return None;
+        }
+        // Version 00 has exactly four parts, later versions may add more
+        if parts[0] == "00" && parts.len() != 4 {
+            return None;
+        }
+        if !is_id(parts[1], 32) || !is_id(parts[2], 16) || !is_hex(parts[3], 2) {
+            return None;
+        }
+        let flags = u8::from_str_radix(parts[3], 16).ok()?;
+        Some(Self {
+            trace_id: parts[1].into(),
+            span_id: parts[2].into(),
+            sampled: flags & 1 == 1,
+        })
+    }
+
+    pub fn to_traceparent(&self) -> String {
+        let flags = if self.sampled { "01" } else { "00" };
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, flags)
+    }
+}
+
+/// Lowercase hex of the length
+fn is_hex(value: &str, length: usize) -> bool {
+    value.len() == length
+        && value
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+/// A trace or span id, which is invalid if it's all zeros
+fn is_id(value: &str, length: usize) -> bool {
+    is_hex(value, length) && value.chars().any(|c| c != '0')
+}
+
+/// Whether a new trace is sampled, by the ratio of traces to sample
+///
+/// Like OpenTelemetry's `TraceIdRatioBased`, the decision is read from the
+/// trace id, so every service sampling by the same ratio agrees on it.
+fn is_sampled(trace_id: &str, ratio: f64) -> bool {
+    let random = u64::from_str_radix(&trace_id[16..], 16).unwrap_or_default();
+    ratio >= 1.0 || (random as f64) < ratio * u64::MAX as f64
+}
+
+fn span_id() -> String {
+    Uuid::new_v4().to_simple().to_string()[..16].into()
+}
+
+/// The context of the current span, if any
+///
+/// Send its `to_traceparent` on outgoing requests to continue the trace.
+pub fn current() -> Option<SpanContext> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Makes a context current until it's dropped
+struct ContextGuard {
+    previous: Option<SpanContext>,
+}
+
+impl ContextGuard {
+    fn set(context: Option<SpanContext>) -> Self {
+        let previous = CURRENT.with(|current| current.replace(context));
+        Self { previous }
+    }
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| current.replace(previous));
+    }
+}
+
+/// The kinds of span of OTLP
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// A span, recorded for export when it's dropped
+pub struct Span {
+    context: SpanContext,
+    data: SpanData,
+}
+
+impl Span {
+    /// Start a child of the current span, or the root of a new trace
+    pub fn start(name: impl Into<String>, kind: SpanKind) -> Self {
+        Self::with_parent(name, kind, current())
+    }
+
+    /// Start a child of the parent, or the root of a new trace
+    pub fn with_parent(
+        name: impl Into<String>,
+        kind: SpanKind,
+        parent: Option<SpanContext>,
+    ) -> Self {
+        let context = match &parent {
+            Some(parent) => parent.child(),
+            None => SpanContext::root(),
+        };
+        let data = SpanData {
+            trace_id: context.trace_id.clone(),
+            span_id: context.span_id.clone(),
+            parent_span_id: parent.map(|parent| parent.span_id),
+            name: name.into(),
+            kind: kind as u8,
+            start_time_unix_nano: now(),
+            end_time_unix_nano: "".into(),
+            attributes: vec![],
+            status: SpanStatus::default(),
+        };
+        Self { context, data }
+    }
+
+    pub fn context(&self) -> &SpanContext {
+        &self.context
+    }
+
+    pub fn set_name(&mut self, name: impl Into<String>) {
+        self.data.name = name.into();
+    }
+
+    pub fn set_attribute(&mut self, key: &str, value: impl ToString) {
+        self.data.attributes.push(Attribute {
+            key: key.into(),
+            value: AttributeValue {
+                string_value: value.to_string(),
+            },
+        });
+    }
+
+    /// Mark the span as failed
+    pub fn set_error(&mut self, message: impl ToString) {
+        self.data.status = SpanStatus {
+            code: STATUS_ERROR,
+            message: message.to_string(),
+        };
+    }
+
+    /// Make the span current for blocking code, until it's dropped
+    pub fn enter(self) -> EnteredSpan {
+        EnteredSpan {
+            _guard: ContextGuard::set(Some(self.context.clone())),
+            _span: self,
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if !self.context.sampled || CONFIG.otel_traces_exporter == TraceExporter::None {
+            return;
+        }
+        let mut data = self.data.clone();
+        data.end_time_unix_nano = now();
+        let mut finished = FINISHED.lock().expect("Could not acquire lock");
+        if finished.len() < MAX_BUFFERED_SPANS {
+            finished.push(data);
+        }
+    }
+}
+
+/// A span that's current until it's dropped
+///
+/// The previous span is current again before this one ends.
+pub struct EnteredSpan {
+    _guard: ContextGuard,
+    _span: Span,
+}
+
+/// Start an internal span and make it current, for blocking code
+///
+/// ```ignore
+/// let _span = telemetry::enter("models::user::find");
+/// ```
+pub fn enter(name: &str) -> EnteredSpan {
+    Span::start(name, SpanKind::Internal).enter()
+}
+
+/// A future that's polled with a span context current
+pub struct InContext<F> {
+    context: Option<SpanContext>,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for InContext<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let _guard = ContextGuard::set(self.context.clone());
+        self.inner.as_mut().poll(cx)
+    }
+}
+
+/// Poll a future with the context current, so its spans are children of it
+pub fn in_context<F: Future>(context: Option<SpanContext>, future: F) -> InContext<F> {
+    InContext {
+        context,
+        inner: Box::pin(future),
+    }
+}
+
+/// `web::block` in a span, with the span current on the threadpool
+pub async fn block<F, I, E>(name: &str, f: F) -> Result<I, BlockingError<E>>
+where
+    F: FnOnce() -> Result<I, E> + Send + 'static,
+    I: Send + 'static,
+    E: Send + Debug + 'static,
+{
+    let mut span = Span::start(name, SpanKind::Internal);
+    let context = Some(span.context().clone());
+    let result = web::block(move || {
+        let _guard = ContextGuard::set(context);
+        f()
+    })
+    .await;
+    if let Err(error) = &result {
+        span.set_error(format!("{:?}", error));
+    }
+    result
+}
+
+const STATUS_ERROR: u8 = 2;
+
+/// A finished span in OTLP's JSON encoding
+#[derive(Clone, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SpanData {
+    pub trace_id: String,
+    pub span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_span_id: Option<String>,
+    pub name: String,
+    pub kind: u8,
+    pub start_time_unix_nano: String,
+    pub end_time_unix_nano: String,
+    pub attributes: Vec<Attribute>,
+    pub status: SpanStatus,
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Attribute {
+    pub key: String,
+    pub value: AttributeValue,
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AttributeValue {
+    pub string_value: String,
+}
+
+/// Unset (0) unless the span failed (2)
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct SpanStatus {
+    pub code: u8,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub message: String,
+}
+
+/// Nanoseconds since the epoch, as a string like OTLP's JSON encoding
+fn now() -> String {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Export the finished spans every `EXPORT_INTERVAL` until shutdown
+pub fn start_export() {
+    if CONFIG.otel_traces_exporter == TraceExporter::None {
+        return;
+    }
+    shutdown::spawn(async move {
+        let mut interval = interval(EXPORT_INTERVAL);
+        loop {
+            interval.tick().await;
+            flush().await;
+        }
+    });
+}
+
+/// Export the spans finished since the last export
+pub async fn flush() {
+    let spans = FINISHED
+        .lock()
+        .expect("Could not acquire lock")
+        .drain(..)
+        .collect::<Vec<_>>();
+    if !spans.is_empty() {
+        export(spans).await;
+    }
+}
+
+async fn export(spans: Vec<SpanData>) {
+    match CONFIG.otel_traces_exporter {
+        TraceExporter::None => {}
+        TraceExporter::Log => {
+            for span in spans {
+                if let Ok(json) = serde_json::to_string(&span) {
+                    info!(target: "traces", "{}", json);
+                }
+            }
+        }
+        TraceExporter::Otlp => {
+            let url = format!(
+                "{}/v1/traces",
+                CONFIG.otel_exporter_otlp_endpoint.trim_end_matches('/')
+            );
+            let response = Client::new().post(&url).send_json(&otlp_body(spans)).await;
+            match response {
+                Ok(response) if response.status().is_success() => {}
+                Ok(response) => warn!("Could not export spans: {}", response.status()),
+                Err(error) => warn!("Could not export spans: {}", error),
+            }
+        }
+    }
+}
+
+/// An OTLP/HTTP export request of the service's spans
+fn otlp_body(spans: Vec<SpanData>) -> serde_json::Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{
+                    "key": "service.name",
+                    "value": { "stringValue": CONFIG.otel_service_name }
+                }]
+            },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans
+            }]
+        }]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn it_parses_a_traceparent() {
+        let context = SpanContext::from_traceparent(TRACEPARENT).unwrap();
+        assert_eq!(context.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(context.span_id, "00f067aa0ba902b7");
+        assert!(context.sampled);
+        assert_eq!(context.to_traceparent(), TRACEPARENT);
+    }
+
+    #[test]
+    fn it_rejects_an_invalid_traceparent() {
+        let invalid = [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        ];
+        for traceparent in invalid.iter() {
+            assert_eq!(SpanContext::from_traceparent(traceparent), None);
+        }
+    }
+
+    #[test]
+    fn it_samples_new_traces_by_ratio() {
+        let low = "4bf92f3577b34da60000000000000001";
+        let high = "4bf92f3577b34da6ffffffffffffff00";
+        assert!(is_sampled(low, 1.0));
+        assert!(is_sampled(high, 1.0));
+        assert!(is_sampled(low, 0.5));
+        assert!(!is_sampled(high, 0.5));
+        assert!(!is_sampled(low, 0.0));
+    }
+
+    #[test]
+    fn it_starts_a_trace_or_continues_the_current_one() {
+        let root = Span::start("root", SpanKind::Server);
+        assert_eq!(root.data.parent_span_id, None);
+        let root_context = root.context().clone();
+
+        let _entered = root.enter();
+        let child = Span::start("child", SpanKind::Internal);
+        assert_eq!(child.context().trace_id, root_context.trace_id);
+        assert_ne!(child.context().span_id, root_context.span_id);
+        assert_eq!(child.data.parent_span_id, Some(root_context.span_id));
+    }
+
+    #[test]
+    fn it_restores_the_previous_span() {
+        let outer = enter("outer");
+        let outer_context = current();
+        {
+            let _inner = enter("inner");
+            assert_ne!(current(), outer_context);
+        }
+        assert_eq!(current(), outer_context);
+        drop(outer);
+        assert_eq!(current(), None);
+    }
+
+    #[actix_rt::test]
+    async fn it_carries_the_context_onto_the_threadpool() {
+        let parent = SpanContext::from_traceparent(TRACEPARENT);
+        let context = in_context(parent, async {
+            block("test", || Ok::<_, ()>(current())).await.unwrap()
+        })
+        .await
+        .unwrap();
+        assert_eq!(context.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_ne!(context.span_id, "00f067aa0ba902b7");
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn it_encodes_spans_for_otlp() {
+        let mut span = Span::start("GET /api/v1/user/{id}", SpanKind::Server);
+        span.set_attribute("http.status_code", 500);
+        span.set_error("Internal Server Error");
+        let body = otlp_body(vec![span.data.clone()]);
+        let encoded = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(encoded["name"], "GET /api/v1/user/{id}");
+        assert_eq!(encoded["kind"], 2);
+        assert_eq!(encoded["attributes"][0]["key"], "http.status_code");
+        assert_eq!(encoded["attributes"][0]["value"]["stringValue"], "500");
+        assert_eq!(encoded["status"]["code"], 2);
+        assert_eq!(encoded.get("parentSpanId"), None);
+    }
+}
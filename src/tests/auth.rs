@@ -1,18 +1,48 @@
 #[cfg(test)]
 mod tests {
-    use crate::handlers::auth::LoginRequest;
-    use crate::tests::helpers::tests::{assert_get, assert_post};
+    use crate::handlers::auth::{LoginRequest, TokenResponse};
+    use crate::tests::helpers::tests::{assert_get, TestApp, TEST_PASSWORD};
+    use actix_web::test;
 
     const PATH: &str = "/api/v1/auth";
 
     #[actix_rt::test]
     async fn it_logs_a_user_in() {
+        let app = TestApp::new();
         let params = LoginRequest {
-            email: "satoshi@nakamotoinstitute.org".into(),
-            password: "123456".into(),
+            email: app.user.email.clone(),
+            password: TEST_PASSWORD.into(),
         };
         let url = format!("{}/login", PATH);
-        assert_post(&url, params).await;
+        let response = app.post(&url, params).await;
+        assert!(response.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_log_a_user_in_with_a_bad_password() {
+        let app = TestApp::new();
+        let params = LoginRequest {
+            email: app.user.email.clone(),
+            password: "not-the-password".into(),
+        };
+        let url = format!("{}/login", PATH);
+        let response = app.post(&url, params).await;
+        assert_eq!(response.status(), 401);
+    }
+
+    #[actix_rt::test]
+    async fn it_creates_a_bearer_token() {
+        let app = TestApp::new();
+        let params = LoginRequest {
+            email: app.user.email.clone(),
+            password: TEST_PASSWORD.into(),
+        };
+        let url = format!("{}/token", PATH);
+        let response = app.post(&url, params).await;
+        assert!(response.status().is_success());
+        let body = test::read_body(response).await;
+        let token: TokenResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(token.token_type, "Bearer");
     }
 
     #[actix_rt::test]
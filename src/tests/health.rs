@@ -6,4 +6,14 @@ mod tests {
     async fn test_health() {
         assert_get("/health").await;
     }
+
+    #[actix_rt::test]
+    async fn test_health_live() {
+        assert_get("/health/live").await;
+    }
+
+    #[actix_rt::test]
+    async fn test_health_ready() {
+        assert_get("/health/ready").await;
+    }
 }
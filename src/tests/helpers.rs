@@ -1,89 +1,185 @@
 #[cfg(test)]
 pub mod tests {
-    use crate::auth::get_identity_service;
-    use crate::cache::add_cache;
-    use crate::config::CONFIG;
-    use crate::database::{add_pool, init_pool, Pool};
+    use crate::auth::{create_jwt, identity_service, PrivateClaim};
+    use crate::cache::{memory::MemoryCache, Cache, CacheBackend};
+    use crate::database::{add_pool, run_migrations, Pool};
+    use crate::extractors::{form_config, json_config, path_config, query_config};
     use crate::handlers::auth::LoginRequest;
+    use crate::handlers::user::UserResponse;
+    use crate::middleware::csrf::{CSRF_COOKIE, CSRF_HEADER};
+    use crate::middleware::locale::Localize;
+    use crate::middleware::metrics::Metrics;
+    use crate::middleware::request_id::RequestId;
+    use crate::middleware::tracing::Tracing;
+    use crate::models::user::{create, NewUser, User};
+    use crate::repositories::add_repositories;
+    use crate::repositories::user::{InMemoryUserRepository, UserRepo, UserRepository};
     use crate::routes::routes;
+    use crate::session::SessionStore;
     use crate::state::{new_state, AppState};
     use actix_web::dev::ServiceResponse;
-    use actix_web::{test, web::Data, App};
-    use diesel::mysql::MysqlConnection;
+    use actix_web::http::header::AUTHORIZATION;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{web::Data, App};
+    use diesel::r2d2::{ConnectionManager, PooledConnection};
+    use diesel::sqlite::SqliteConnection;
     use serde::Serialize;
+    use uuid::Uuid;
+
+    /// Tests run against SQLite so that they don't need a database server
+    pub type TestConnection = SqliteConnection;
+    pub type TestPool = Pool<TestConnection>;
+
+    /// The password of every user created by the user factories
+    pub const TEST_PASSWORD: &str = "123456";
+
+    /// How `TestApp` authenticates as the test user
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Credentials {
+        /// The identity cookie from logging in, with the CSRF token
+        Cookie,
+        CookieWithoutCsrfToken,
+        /// An `Authorization: Bearer` JWT, without logging in
+        Bearer,
+    }
+
+    /// The full application on top of a fresh database
+    ///
+    /// Requests are sent as `user`, who is logged in before each request.
+    pub struct TestApp {
+        pub pool: TestPool,
+        pub user: UserResponse,
+        pub cache: Cache,
+        session_store: SessionStore,
+    }
+
+    impl TestApp {
+        pub fn new() -> Self {
+            Self::with_session_store(SessionStore::Cookie)
+        }
+
+        /// Keep sessions on the server, in `cache`
+        pub fn with_sessions() -> Self {
+            Self::with_session_store(SessionStore::Redis)
+        }
+
+        fn with_session_store(session_store: SessionStore) -> Self {
+            let pool = get_pool();
+            let user = create_user(&pool.get().unwrap());
+            Self {
+                pool,
+                user,
+                cache: get_cache(),
+                session_store,
+            }
+        }
+
+        /// Login as the test user and send the request with their cookie
+        pub async fn call(&self, request: TestRequest) -> ServiceResponse {
+            self.call_with(request, Credentials::Cookie).await
+        }
+
+        /// Send the request as the test user
+        pub async fn call_with(
+            &self,
+            request: TestRequest,
+            credentials: Credentials,
+        ) -> ServiceResponse {
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(app_state())
+                    .app_data(self.cache.clone())
+                    .wrap(identity_service(self.session_store, self.cache.clone()))
+                    .wrap(Localize)
+                    .wrap(RequestId)
+                    .wrap(Tracing)
+                    .wrap(Metrics)
+                    .app_data(json_config())
+                    .app_data(query_config())
+                    .app_data(path_config())
+                    .app_data(form_config())
+                    .configure(add_pool(self.pool.clone()))
+                    .configure(add_repositories(self.pool.clone()))
+                    .configure(routes),
+            )
+            .await;
+
+            if let Credentials::Bearer = credentials {
+                let private_claim = PrivateClaim::new(self.user.id, self.user.email.clone());
+                let header = format!("Bearer {}", create_jwt(private_claim).unwrap());
+                let request = request.header(AUTHORIZATION, header);
+                return test::call_service(&mut app, request.to_request()).await;
+            }
+
+            let login_request = LoginRequest {
+                email: self.user.email.clone(),
+                password: TEST_PASSWORD.into(),
+            };
+            let response = test::call_service(
+                &mut app,
+                TestRequest::post()
+                    .set_json(&login_request)
+                    .uri("/api/v1/auth/login")
+                    .to_request(),
+            )
+            .await;
+
+            // Send the cookies back, and the CSRF token as a page would
+            let mut request = request;
+            for cookie in response.response().cookies() {
+                if cookie.name() == CSRF_COOKIE {
+                    if let Credentials::CookieWithoutCsrfToken = credentials {
+                        continue;
+                    }
+                    request = request.header(CSRF_HEADER, cookie.value());
+                }
+                request = request.cookie(cookie.into_owned());
+            }
+            test::call_service(&mut app, request.to_request()).await
+        }
+
+        /// Send a HTTP GET request
+        pub async fn get(&self, route: &str) -> ServiceResponse {
+            self.call(TestRequest::get().uri(route)).await
+        }
+
+        /// Send a HTTP POST request with a json body
+        pub async fn post<T: Serialize>(&self, route: &str, params: T) -> ServiceResponse {
+            self.call(TestRequest::post().set_json(&params).uri(route))
+                .await
+        }
+
+        /// Send a HTTP PUT request with a json body
+        pub async fn put<T: Serialize>(&self, route: &str, params: T) -> ServiceResponse {
+            self.call(TestRequest::put().set_json(&params).uri(route))
+                .await
+        }
+
+        /// Send a HTTP DELETE request
+        pub async fn delete(&self, route: &str) -> ServiceResponse {
+            self.call(TestRequest::delete().uri(route)).await
+        }
+    }
 
     /// Helper for HTTP GET integration tests
     pub async fn test_get(route: &str) -> ServiceResponse {
-        let login_request = LoginRequest {
-            email: "satoshi@nakamotoinstitute.org".into(),
-            password: "123456".into(),
-        };
-
-        let mut app = test::init_service(
-            App::new()
-                .configure(add_cache)
-                .app_data(app_state())
-                .wrap(get_identity_service())
-                .configure(add_pool)
-                .configure(routes),
-        )
-        .await;
-
-        let response = test::call_service(
-            &mut app,
-            test::TestRequest::post()
-                .set_json(&login_request)
-                .uri("/api/v1/auth/login")
-                .to_request(),
-        )
-        .await;
-
-        let cookie = response.response().cookies().next().unwrap().to_owned();
-        test::call_service(
-            &mut app,
-            test::TestRequest::get()
-                .cookie(cookie.clone())
-                .uri(route)
-                .to_request(),
-        )
-        .await
+        TestApp::new().get(route).await
     }
 
     /// Helper for HTTP POST integration tests
     pub async fn test_post<T: Serialize>(route: &str, params: T) -> ServiceResponse {
-        let mut app = test::init_service(
-            App::new()
-                .configure(add_cache)
-                .app_data(app_state())
-                .wrap(get_identity_service())
-                .configure(add_pool)
-                .configure(routes),
-        )
-        .await;
-        let login = login().await;
-        let cookie = login.response().cookies().next().unwrap().to_owned();
-        test::call_service(
-            &mut app,
-            test::TestRequest::post()
-                .set_json(&params)
-                .cookie(cookie.clone())
-                .uri(route)
-                .to_request(),
-        )
-        .await
-    }
-
-    /// Helper to login for tests
-    // pub fn login_request() -> Request {
-    //     let login_request = LoginRequest {
-    //         email: "satoshi@nakamotoinstitute.org".into(),
-    //         password: "123456".into(),
-    //     };
-    //     test::TestRequest::post()
-    //         .set_json(&login_request)
-    //         .uri("/api/v1/auth/login")
-    //         .to_request()
-    // }
+        TestApp::new().post(route, params).await
+    }
+
+    /// Helper for HTTP PUT integration tests
+    pub async fn test_put<T: Serialize>(route: &str, params: T) -> ServiceResponse {
+        TestApp::new().put(route, params).await
+    }
+
+    /// Helper for HTTP DELETE integration tests
+    pub async fn test_delete(route: &str) -> ServiceResponse {
+        TestApp::new().delete(route).await
+    }
 
     /// Assert that a route is successful for HTTP GET requests
     pub async fn assert_get(route: &str) -> ServiceResponse {
@@ -99,41 +195,68 @@ pub mod tests {
         response
     }
 
-    /// Returns a r2d2 Pooled Connection to be used in tests
-    pub fn get_pool() -> Pool<MysqlConnection> {
-        init_pool::<MysqlConnection>(CONFIG.clone()).unwrap()
+    /// Assert that a route is successful for HTTP DELETE requests
+    pub async fn assert_delete(route: &str) -> ServiceResponse {
+        let response = test_delete(route).await;
+        assert!(response.status().is_success());
+        response
+    }
+
+    /// Returns a r2d2 Pool for a fresh, migrated in-memory SQLite database
+    ///
+    /// Every in-memory SQLite connection is a separate database, so the pool
+    /// holds a single connection that lives as long as the pool does.
+    pub fn get_pool() -> TestPool {
+        let manager = ConnectionManager::<TestConnection>::new(":memory:");
+        let pool = Pool::builder()
+            .max_size(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .build(manager)
+            .unwrap();
+        run_migrations(&*pool.get().unwrap()).unwrap();
+        pool
+    }
+
+    /// Returns a connection to a fresh, migrated in-memory SQLite database
+    pub fn get_conn() -> PooledConnection<ConnectionManager<TestConnection>> {
+        get_pool().get().unwrap()
+    }
+
+    /// Factory for a user with a unique email, not yet persisted
+    pub fn new_user() -> User {
+        let user_id = Uuid::new_v4();
+        NewUser {
+            id: user_id,
+            first_name: "Satoshi".to_string(),
+            last_name: "Nakamoto".to_string(),
+            email: format!("satoshi-{}@nakamotoinstitute.org", user_id),
+            password: TEST_PASSWORD.to_string(),
+            created_by: user_id,
+            updated_by: user_id,
+        }
+        .into()
+    }
+
+    /// Factory for a user with a unique email, persisted to the database
+    pub fn create_user(conn: &TestConnection) -> UserResponse {
+        create(conn, &new_user()).unwrap()
     }
 
-    /// Returns a r2d2 Pooled Connection wrappedn in Actix Application Data
-    pub fn get_data_pool() -> Data<Pool<MysqlConnection>> {
-        Data::new(get_pool())
+    /// Returns an in-memory user repository wrapped in Actix Application Data
+    /// Seeded with a single user
+    pub fn get_data_user_repository() -> UserRepo {
+        let users = InMemoryUserRepository::with_users(vec![new_user()]);
+        Data::new(Box::new(users) as Box<dyn UserRepository>)
     }
 
-    /// Login to routes  
-    pub async fn login() -> ServiceResponse {
-        let login_request = LoginRequest {
-            email: "satoshi@nakamotoinstitute.org".into(),
-            password: "123456".into(),
-        };
-        let mut app = test::init_service(
-            App::new()
-                .wrap(get_identity_service())
-                .configure(add_pool)
-                .configure(routes),
-        )
-        .await;
-        test::call_service(
-            &mut app,
-            test::TestRequest::post()
-                .set_json(&login_request)
-                .uri("/api/v1/auth/login")
-                .to_request(),
-        )
-        .await
+    /// Returns an empty in-memory cache wrapped in Actix Application Data
+    pub fn get_cache() -> Cache {
+        Data::new(Box::new(MemoryCache::new(100)) as Box<dyn CacheBackend>)
     }
 
     // Mock applicate state
-    pub fn app_state() -> AppState<'static, String> {
-        new_state::<String>()
+    pub fn app_state() -> AppState {
+        new_state()
     }
 }
@@ -0,0 +1,23 @@
+#[cfg(test)]
+mod tests {
+    use crate::tests::helpers::tests::{assert_get, TestApp};
+    use actix_web::test;
+
+    #[actix_rt::test]
+    async fn test_metrics() {
+        assert_get("/metrics").await;
+    }
+
+    #[actix_rt::test]
+    async fn it_counts_requests_and_logins() {
+        let app = TestApp::new();
+        app.get(&format!("/api/v1/user/{}", app.user.id)).await;
+        let response = app.get("/metrics").await;
+        let body = test::read_body(response).await;
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+        assert!(metrics.contains(
+            "http_requests_total{method=\"GET\",route=\"/api/v1/user/{id}\",status=\"2xx\"}"
+        ));
+        assert!(metrics.contains("logins_total{result=\"success\"}"));
+    }
+}
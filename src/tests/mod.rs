@@ -3,4 +3,6 @@
 pub mod auth;
 pub mod health;
 pub mod helpers;
+pub mod metrics;
+pub mod session;
 pub mod user;
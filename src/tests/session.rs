@@ -0,0 +1,56 @@
+#[cfg(test)]
+mod tests {
+    use crate::auth::{create_jwt, PrivateClaim};
+    use crate::handlers::session::SessionsResponse;
+    use crate::session::{create_session, list_sessions, Session};
+    use crate::tests::helpers::tests::TestApp;
+    use actix_web::{http::StatusCode, test};
+    use uuid::Uuid;
+
+    const PATH: &str = "/api/v1/admin/user";
+
+    #[actix_rt::test]
+    async fn it_gets_the_sessions_of_a_user() {
+        let app = TestApp::with_sessions();
+        let url = format!("{}/{}/sessions", PATH, app.user.id);
+        let response = app.get(&url).await;
+        assert!(response.status().is_success());
+        let body = test::read_body(response).await;
+        let sessions: SessionsResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(sessions.0.len(), 1);
+        assert_eq!(sessions.0[0].user_id, app.user.id);
+    }
+
+    #[actix_rt::test]
+    async fn it_forbids_a_non_admin_to_manage_another_users_sessions() {
+        let app = TestApp::with_sessions();
+        let url = format!("{}/{}/sessions", PATH, Uuid::new_v4());
+        assert_eq!(app.get(&url).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(app.delete(&url).await.status(), StatusCode::FORBIDDEN);
+        let url = format!("{}/{}", url, Uuid::new_v4());
+        assert_eq!(app.delete(&url).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn it_deletes_a_session_of_a_user() {
+        let app = TestApp::with_sessions();
+        let identity = create_jwt(PrivateClaim::new(app.user.id, app.user.email.clone())).unwrap();
+        let session = Session::new(identity, None, None).unwrap();
+        create_session(app.cache.clone(), &session).await.unwrap();
+        let url = format!("{}/{}/sessions/{}", PATH, app.user.id, session.id);
+        let response = app.delete(&url).await;
+        assert!(response.status().is_success());
+        let sessions = list_sessions(app.cache.clone(), app.user.id).await.unwrap();
+        assert!(!sessions.contains(&session));
+    }
+
+    #[actix_rt::test]
+    async fn it_deletes_all_sessions_of_a_user() {
+        let app = TestApp::with_sessions();
+        let url = format!("{}/{}/sessions", PATH, app.user.id);
+        let response = app.delete(&url).await;
+        assert!(response.status().is_success());
+        let sessions = list_sessions(app.cache.clone(), app.user.id).await.unwrap();
+        assert!(sessions.is_empty());
+    }
+}
@@ -1,17 +1,21 @@
 #[cfg(test)]
 mod tests {
-    use crate::handlers::user::{tests::get_first_users_id, CreateUserRequest};
-    use crate::tests::helpers::tests::{assert_get, assert_post};
-    use actix_web::web::Path;
+    use crate::errors::Problem;
+    use crate::handlers::user::{CreateUserRequest, UpdateUserRequest};
+    use crate::tests::helpers::tests::{
+        assert_delete, assert_get, assert_post, create_user, test_put, Credentials, TestApp,
+    };
+    use actix_web::test::{self, TestRequest};
     use uuid::Uuid;
 
     const PATH: &str = "/api/v1/user";
 
     #[actix_rt::test]
     async fn it_gets_a_user() {
-        let user_id: Path<Uuid> = get_first_users_id().into();
-        let url = format!("{}/{}", PATH, user_id);
-        assert_get(&url).await;
+        let app = TestApp::new();
+        let url = format!("{}/{}", PATH, app.user.id);
+        let response = app.get(&url).await;
+        assert!(response.status().is_success());
     }
 
     #[actix_rt::test]
@@ -29,4 +33,86 @@ mod tests {
         };
         assert_post(PATH, params).await;
     }
+
+    #[actix_rt::test]
+    async fn it_doesnt_create_a_user_with_a_taken_email() {
+        let app = TestApp::new();
+        let params = CreateUserRequest {
+            first_name: "Satoshi".into(),
+            last_name: "Nakamoto".into(),
+            email: app.user.email.clone(),
+            password: "123456".into(),
+        };
+        let response = app.post(PATH, params).await;
+        assert_eq!(response.status(), 409);
+        let body = test::read_body(response).await;
+        let problem: Problem = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem.code, "already_exists");
+        assert_eq!(problem.errors["email"][0].code, "unique");
+    }
+
+    #[actix_rt::test]
+    async fn it_updates_a_user() {
+        let app = TestApp::new();
+        let user = create_user(&app.pool.get().unwrap());
+        let params = UpdateUserRequest {
+            first_name: "Barbara".into(),
+            last_name: "Liskov".into(),
+            email: "bliskov@substitution.org".into(),
+        };
+        let url = format!("{}/{}", PATH, user.id);
+        let response = app.put(&url, params).await;
+        assert!(response.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_update_a_user_with_invalid_params() {
+        let params = UpdateUserRequest {
+            first_name: "S".into(),
+            last_name: "N".into(),
+            email: "satoshi".into(),
+        };
+        let url = format!("{}/{}", PATH, Uuid::new_v4());
+        let response = test_put(&url, params).await;
+        assert_eq!(response.status(), 422);
+    }
+
+    #[actix_rt::test]
+    async fn it_deletes_a_user() {
+        let app = TestApp::new();
+        let user = create_user(&app.pool.get().unwrap());
+        let url = format!("{}/{}", PATH, user.id);
+        let response = app.delete(&url).await;
+        assert!(response.status().is_success());
+        let response = app.get(&url).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[actix_rt::test]
+    async fn it_deletes_a_nonexistent_user() {
+        let url = format!("{}/{}", PATH, Uuid::new_v4());
+        assert_delete(&url).await;
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_delete_a_user_without_a_csrf_token() {
+        let app = TestApp::new();
+        let user = create_user(&app.pool.get().unwrap());
+        let url = format!("{}/{}", PATH, user.id);
+        let request = TestRequest::delete().uri(&url);
+        let response = app
+            .call_with(request, Credentials::CookieWithoutCsrfToken)
+            .await;
+        assert_eq!(response.status(), 403);
+    }
+
+    #[actix_rt::test]
+    async fn it_deletes_a_user_with_a_bearer_token() {
+        let app = TestApp::new();
+        let user = create_user(&app.pool.get().unwrap());
+        let url = format!("{}/{}", PATH, user.id);
+        let request = TestRequest::delete().uri(&url);
+        let response = app.call_with(request, Credentials::Bearer).await;
+        assert!(response.status().is_success());
+    }
 }
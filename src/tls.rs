@@ -0,0 +1,263 @@
+//! Terminate TLS with rustls when `TLS_CERT` and `TLS_KEY` are set
+//!
+//! The certificate chain and key are read again every `RELOAD_INTERVAL`, and
+//! new handshakes use them once they've changed, so renewed certificates are
+//! picked up without a restart. Files that fail to load are logged and the
+//! previous certificate is kept.
+//!
+//! Set `TLS_CLIENT_CA` to require client certificates signed by that CA
+//! (mTLS). The CA is only read at startup.
+
+use crate::config::CONFIG;
+use crate::shutdown;
+use actix_rt::time::interval;
+use log::{info, warn};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{
+    AllowAnyAuthenticatedClient, NoClientAuth, ProtocolVersion, ResolvesServerCert, RootCertStore,
+    ServerConfig, SignatureScheme,
+};
+use std::fs;
+use std::io::{self, BufReader, Error, ErrorKind};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use webpki::DNSNameRef;
+
+/// How often the certificate and key are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The oldest TLS version accepted
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    /// The versions rustls may negotiate, newest first
+    fn supported(self) -> Vec<ProtocolVersion> {
+        match self {
+            TlsVersion::Tls12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            TlsVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+        }
+    }
+}
+
+/// Whether the server should terminate TLS
+pub fn enabled() -> bool {
+    !CONFIG.tls_cert.is_empty()
+}
+
+/// Build the rustls config from `Config`, reloading the certificate until shutdown
+pub fn server_config() -> io::Result<ServerConfig> {
+    let resolver = Arc::new(CertResolver::load(&CONFIG.tls_cert, &CONFIG.tls_key)?);
+    start_reload(resolver.clone());
+
+    let verifier = if CONFIG.tls_client_ca.is_empty() {
+        NoClientAuth::new()
+    } else {
+        AllowAnyAuthenticatedClient::new(load_roots(&CONFIG.tls_client_ca)?)
+    };
+    let mut config = ServerConfig::new(verifier);
+    config.cert_resolver = resolver;
+    config.versions = CONFIG.tls_min_version.supported();
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    Ok(config)
+}
+
+/// Check the certificate for changes every `RELOAD_INTERVAL` until shutdown
+fn start_reload(resolver: Arc<CertResolver>) {
+    shutdown::spawn(async move {
+        let mut interval = interval(RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+            resolver.reload();
+        }
+    });
+}
+
+/// Hands every handshake the most recently loaded certificate
+pub struct CertResolver {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<LoadedCert>,
+}
+
+struct LoadedCert {
+    pem: (Vec<u8>, Vec<u8>),
+    certified_key: CertifiedKey,
+}
+
+impl CertResolver {
+    /// Load the certificate chain and key, failing if either is invalid
+    pub fn load(cert_path: &str, key_path: &str) -> io::Result<Self> {
+        let pem = (fs::read(cert_path)?, fs::read(key_path)?);
+        let certified_key = certified_key(&pem.0, &pem.1)?;
+        Ok(Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            current: RwLock::new(LoadedCert { pem, certified_key }),
+        })
+    }
+
+    /// Swap in the certificate and key if their files have changed
+    ///
+    /// Returns whether they were swapped.
+    pub fn reload(&self) -> bool {
+        let pem = match (fs::read(&self.cert_path), fs::read(&self.key_path)) {
+            (Ok(cert), Ok(key)) => (cert, key),
+            (Err(error), _) | (_, Err(error)) => {
+                warn!("Could not read the TLS certificate: {}", error);
+                return false;
+            }
+        };
+        if self.current.read().expect("Could not acquire lock").pem == pem {
+            return false;
+        }
+        match certified_key(&pem.0, &pem.1) {
+            Ok(certified_key) => {
+                *self.current.write().expect("Could not acquire lock") =
+                    LoadedCert { pem, certified_key };
+                info!("Reloaded the TLS certificate from {}", self.cert_path);
+                true
+            }
+            Err(error) => {
+                warn!("Could not reload the TLS certificate: {}", error);
+                false
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: Option<DNSNameRef>, _: &[SignatureScheme]) -> Option<CertifiedKey> {
+        let current = self.current.read().expect("Could not acquire lock");
+        Some(current.certified_key.clone())
+    }
+}
+
+/// Parse a PEM certificate chain and its PKCS#8 or RSA private key
+fn certified_key(cert: &[u8], key: &[u8]) -> io::Result<CertifiedKey> {
+    let chain = certs(&mut BufReader::new(cert)).map_err(|_| invalid("certificate"))?;
+    if chain.is_empty() {
+        return Err(invalid("certificate"));
+    }
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(key)).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(key)).unwrap_or_default();
+    }
+    let key = keys.first().ok_or_else(|| invalid("private key"))?;
+    let signing_key = any_supported_type(key).map_err(|_| invalid("private key"))?;
+    Ok(CertifiedKey::new(chain, Arc::new(signing_key)))
+}
+
+/// The CA certificates that client certificates must be signed by
+fn load_roots(path: &str) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    let pem = fs::read(path)?;
+    match roots.add_pem_file(&mut BufReader::new(pem.as_slice())) {
+        Ok((added, _)) if added > 0 => Ok(roots),
+        _ => Err(invalid("client CA certificate")),
+    }
+}
+
+fn invalid(what: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid TLS {}", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::process::Command;
+    use uuid::Uuid;
+
+    lazy_static! {
+        /// Certificates generated once for the tests, none are committed
+        static ref CERTS: String = {
+            let dir = temp_dir().join(format!("tls-certs-{}", Uuid::new_v4()));
+            let status = Command::new("sh")
+                .arg("scripts/gen-certs.sh")
+                .arg(&dir)
+                .status()
+                .expect("Could not run scripts/gen-certs.sh, which needs openssl");
+            assert!(status.success(), "Could not generate the test certificates");
+            dir.to_string_lossy().into()
+        };
+    }
+
+    /// The path of a generated certificate or key, e.g. `localhost.pem`
+    fn cert(name: &str) -> String {
+        format!("{}/{}", *CERTS, name)
+    }
+
+    fn resolve(resolver: &CertResolver) -> CertifiedKey {
+        resolver.resolve(None, &[]).unwrap()
+    }
+
+    fn copy_pair(cert: &str, key: &str) -> (String, String) {
+        let dir = temp_dir().join(format!("tls-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::copy(cert, &cert_path).unwrap();
+        fs::copy(key, &key_path).unwrap();
+        (
+            cert_path.to_string_lossy().into(),
+            key_path.to_string_lossy().into(),
+        )
+    }
+
+    #[test]
+    fn it_loads_a_certificate_and_key() {
+        let resolver = CertResolver::load(&cert("localhost.pem"), &cert("localhost-key.pem"));
+        assert_eq!(resolve(&resolver.unwrap()).cert.len(), 1);
+    }
+
+    #[test]
+    fn it_rejects_a_key_that_is_not_a_key() {
+        let resolver = CertResolver::load(&cert("localhost.pem"), &cert("localhost.pem"));
+        assert_eq!(resolver.err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn it_reloads_a_changed_certificate() {
+        let (cert_path, key_path) = copy_pair(&cert("localhost.pem"), &cert("localhost-key.pem"));
+        let resolver = CertResolver::load(&cert_path, &key_path).unwrap();
+        let before = resolve(&resolver).cert;
+        assert!(!resolver.reload());
+
+        fs::copy(cert("ca.pem"), &cert_path).unwrap();
+        fs::copy(cert("ca-key.pem"), &key_path).unwrap();
+        assert!(resolver.reload());
+        assert_ne!(resolve(&resolver).cert, before);
+    }
+
+    #[test]
+    fn it_keeps_the_certificate_when_a_reload_fails() {
+        let (cert_path, key_path) = copy_pair(&cert("localhost.pem"), &cert("localhost-key.pem"));
+        let resolver = CertResolver::load(&cert_path, &key_path).unwrap();
+        let before = resolve(&resolver).cert;
+
+        fs::write(&key_path, "not a key").unwrap();
+        assert!(!resolver.reload());
+        assert_eq!(resolve(&resolver).cert, before);
+    }
+
+    #[test]
+    fn it_loads_a_client_ca() {
+        assert_eq!(load_roots(&cert("ca.pem")).unwrap().len(), 1);
+        assert!(load_roots(&cert("localhost-key.pem")).is_err());
+    }
+
+    #[test]
+    fn it_limits_the_tls_versions() {
+        assert_eq!(
+            TlsVersion::Tls13.supported(),
+            vec![ProtocolVersion::TLSv1_3]
+        );
+        assert_eq!(TlsVersion::Tls12.supported().len(), 2);
+    }
+}
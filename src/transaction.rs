@@ -0,0 +1,206 @@
+//! Database transactions that model functions can share.
+//!
+//! Model functions take a connection, so several of them can be composed
+//! into a single unit of work:
+//!
+//! ```ignore
+//! let user = block(move || {
+//!     transaction(&pool, |conn| {
+//!         let user = update(conn, &update_user)?;
+//!         // ...more model calls on the same connection
+//!         Ok(user)
+//!     })
+//! })
+//! .await?;
+//! ```
+//!
+//! The closure is committed when it returns `Ok` and rolled back otherwise.
+//! On Postgres and CockroachDB, serialization failures are retried.
+
+use crate::database::Pool;
+use crate::errors::ApiError;
+use diesel::{
+    mysql::MysqlConnection, pg::PgConnection, sqlite::SqliteConnection, Connection, QueryResult,
+};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Base delay between retries, doubled on every attempt
+const RETRY_DELAY_MS: u64 = 10;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    fn to_sql(self) -> String {
+        let isolation_level = match self {
+            IsolationLevel::ReadUncommitted => "READ UNCOMMITTED",
+            IsolationLevel::ReadCommitted => "READ COMMITTED",
+            IsolationLevel::RepeatableRead => "REPEATABLE READ",
+            IsolationLevel::Serializable => "SERIALIZABLE",
+        };
+        format!("SET TRANSACTION ISOLATION LEVEL {}", isolation_level)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransactionOptions {
+    pub isolation_level: Option<IsolationLevel>,
+    pub max_retries: u32,
+}
+
+impl Default for TransactionOptions {
+    fn default() -> Self {
+        Self {
+            isolation_level: None,
+            max_retries: 3,
+        }
+    }
+}
+
+/// Per-backend transaction behavior
+pub trait TransactionalConnection: Connection {
+    /// Whether the backend reports serialization failures that can be retried
+    const RETRY_SERIALIZATION_FAILURES: bool = false;
+
+    /// Set the isolation level of the transaction that is about to begin
+    fn set_isolation_level_before_begin(
+        &self,
+        _isolation_level: IsolationLevel,
+    ) -> QueryResult<()> {
+        Ok(())
+    }
+
+    /// Set the isolation level of the transaction that has just begun
+    fn set_isolation_level_after_begin(&self, _isolation_level: IsolationLevel) -> QueryResult<()> {
+        Ok(())
+    }
+}
+
+// MySQL applies SET TRANSACTION to the next transaction that is started
+impl TransactionalConnection for MysqlConnection {
+    fn set_isolation_level_before_begin(&self, isolation_level: IsolationLevel) -> QueryResult<()> {
+        self.execute(&isolation_level.to_sql()).map(|_| ())
+    }
+}
+
+// Postgres and CockroachDB require SET TRANSACTION inside of the transaction
+impl TransactionalConnection for PgConnection {
+    const RETRY_SERIALIZATION_FAILURES: bool = true;
+
+    fn set_isolation_level_after_begin(&self, isolation_level: IsolationLevel) -> QueryResult<()> {
+        self.execute(&isolation_level.to_sql()).map(|_| ())
+    }
+}
+
+// SQLite transactions are always serializable
+impl TransactionalConnection for SqliteConnection {}
+
+/// Run a unit of work in a transaction using the default options
+pub fn transaction<C, T, F>(pool: &Pool<C>, f: F) -> Result<T, ApiError>
+where
+    C: TransactionalConnection + 'static,
+    F: FnMut(&C) -> Result<T, ApiError>,
+{
+    transaction_with(pool, TransactionOptions::default(), f)
+}
+
+/// Run a unit of work in a transaction
+///
+/// The closure may be called more than once if the transaction is retried,
+/// so it should not have side effects outside of the database.
+pub fn transaction_with<C, T, F>(
+    pool: &Pool<C>,
+    options: TransactionOptions,
+    mut f: F,
+) -> Result<T, ApiError>
+where
+    C: TransactionalConnection + 'static,
+    F: FnMut(&C) -> Result<T, ApiError>,
+{
+    let conn = pool.get()?;
+    let mut attempt = 0;
+
+    loop {
+        match run(&*conn, &options, &mut f) {
+            Err(ApiError::SerializationFailure(_))
+                if C::RETRY_SERIALIZATION_FAILURES && attempt < options.max_retries =>
+            {
+                sleep(Duration::from_millis(RETRY_DELAY_MS << attempt));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Begin, run and commit (or roll back) a single transaction attempt
+fn run<C, T, F>(conn: &C, options: &TransactionOptions, f: &mut F) -> Result<T, ApiError>
+where
+    C: TransactionalConnection,
+    F: FnMut(&C) -> Result<T, ApiError>,
+{
+    if let Some(isolation_level) = options.isolation_level {
+        conn.set_isolation_level_before_begin(isolation_level)?;
+    }
+
+    conn.transaction::<T, ApiError, _>(|| {
+        if let Some(isolation_level) = options.isolation_level {
+            conn.set_isolation_level_after_begin(isolation_level)?;
+        }
+        f(conn)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::user::{create, find};
+    use crate::tests::helpers::tests::{get_pool, new_user};
+
+    #[test]
+    fn it_commits_a_transaction() {
+        let pool = get_pool();
+        let user = new_user();
+        let created = transaction(&pool, |conn| create(conn, &user)).unwrap();
+        let found = transaction(&pool, |conn| find(conn, created.id));
+        assert_eq!(found.unwrap(), created);
+    }
+
+    #[test]
+    fn it_rolls_back_a_transaction() {
+        let pool = get_pool();
+        let user = new_user();
+        let user_id = user.id;
+        let result: Result<(), ApiError> = transaction(&pool, |conn| {
+            create(conn, &user)?;
+            Err(ApiError::InternalServerError("rollback".into()))
+        });
+        assert!(result.is_err());
+        let found = transaction(&pool, |conn| find(conn, user_id));
+        assert!(found.is_err());
+    }
+
+    #[test]
+    fn it_runs_a_transaction_with_an_isolation_level() {
+        let options = TransactionOptions {
+            isolation_level: Some(IsolationLevel::Serializable),
+            max_retries: 0,
+        };
+        let user = new_user();
+        let created = transaction_with(&get_pool(), options, |conn| create(conn, &user));
+        assert!(created.is_ok());
+    }
+
+    #[test]
+    fn it_builds_isolation_level_sql() {
+        let sql = IsolationLevel::RepeatableRead.to_sql();
+        assert_eq!(sql, "SET TRANSACTION ISOLATION LEVEL REPEATABLE READ");
+    }
+}
@@ -1,11 +1,33 @@
 //! Validation-related functions to work with the validator crate.
 
 use crate::errors::ApiError;
-use actix_web::web::Json;
-use validator::{Validate, ValidationErrors};
+use crate::locale::{field_message, DEFAULT_LOCALE};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use validator::{Validate, ValidationError, ValidationErrors, ValidationErrorsKind};
+
+/// Code of a value that's already taken, e.g. an email
+pub const UNIQUE: &str = "unique";
+
+/// Errors by field path, e.g. `email`, `address.city` or `items[0].name`
+pub type FieldErrors = BTreeMap<String, Vec<FieldError>>;
+
+/// A single failed validation of a field
+///
+/// `code` names the validation (e.g. `length` or `email`), so clients can
+/// show their own message, and `params` holds its arguments (e.g. `min`).
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct FieldError {
+  pub code: String,
+  pub message: String,
+  pub params: HashMap<String, Value>,
+}
 
 /// Validate a struct and collect and return the errors
-pub fn validate<T>(params: &Json<T>) -> Result<(), ApiError>
+///
+/// Prefer the ValidatedJson extractor, which calls this for you.
+pub fn validate<T>(params: &T) -> Result<(), ApiError>
 where
   T: Validate,
 {
@@ -15,21 +37,116 @@ where
   }
 }
 
-/// Collect ValidationErrors and return a vector of the messages
-/// Adds a default_error when none is supplied
-fn collect_errors(error: ValidationErrors) -> Vec<String> {
-  error
-    .field_errors()
+/// Checks that need more than a single field: cross-field rules and async
+/// checks against the database, e.g. that an email isn't taken
+///
+/// `Context` is what the checks need, such as a repository.
+#[async_trait(?Send)]
+pub trait ValidateWith {
+  type Context;
+
+  /// Add any failed checks to `errors`, which holds the field errors so far
+  /// An Err is only returned when a check can't be run
+  async fn validate_with(
+    &self,
+    context: &Self::Context,
+    errors: &mut FieldErrors,
+  ) -> Result<(), ApiError>;
+}
+
+/// Run the cross-field and async checks of a struct whose fields are valid,
+/// e.g. one extracted with `ValidatedJson`
+pub async fn validate_with<T>(params: &T, context: &T::Context) -> Result<(), ApiError>
+where
+  T: ValidateWith,
+{
+  let mut errors = FieldErrors::new();
+  params.validate_with(context, &mut errors).await?;
+  if errors.is_empty() {
+    Ok(())
+  } else {
+    Err(ApiError::from_field_errors(errors))
+  }
+}
+
+/// Add a failed check of a field
+pub fn add_error(
+  errors: &mut FieldErrors,
+  field: &str,
+  code: &str,
+  params: HashMap<String, Value>,
+) {
+  let error = FieldError::new(field, code, params, None);
+  errors
+    .entry(field.into())
+    .or_default()
+    .push(error);
+}
+
+impl FieldError {
+  /// Uses the supplied message, e.g. a validator's `message`, if there is
+  /// one, otherwise renders the English message of the code or a default one
+  pub fn new(
+    field: &str,
+    code: &str,
+    params: HashMap<String, Value>,
+    message: Option<String>,
+  ) -> Self {
+    let mut error = FieldError {
+      code: code.into(),
+      message: String::new(),
+      params,
+    };
+    error.message = message
+      .or_else(|| field_message(DEFAULT_LOCALE, field, &error))
+      .unwrap_or_else(|| format!("{} is invalid", field));
+    error
+  }
+}
+
+/// Collect ValidationErrors into errors by field path
+/// Includes nested structs and lists
+fn collect_errors(error: ValidationErrors) -> FieldErrors {
+  let mut errors = FieldErrors::new();
+  collect_nested_errors(&mut errors, None, error);
+  errors
+}
+
+fn collect_nested_errors(errors: &mut FieldErrors, parent: Option<&str>, error: ValidationErrors) {
+  for (field, kind) in error.errors() {
+    let path = match parent {
+      Some(parent) => format!("{}.{}", parent, field),
+      None => field.to_string(),
+    };
+    match kind {
+      ValidationErrorsKind::Field(field_errors) => {
+        let field_errors = field_errors
+          .into_iter()
+          .map(|error| to_field_error(&path, error))
+          .collect();
+        errors.insert(path, field_errors);
+      }
+      ValidationErrorsKind::Struct(nested) => collect_nested_errors(errors, Some(&path), *nested),
+      ValidationErrorsKind::List(items) => {
+        for (index, nested) in items {
+          let path = format!("{}[{}]", path, index);
+          collect_nested_errors(errors, Some(&path), *nested);
+        }
+      }
+    }
+  }
+}
+
+/// Drops the submitted value, which could be a password
+fn to_field_error(path: &str, error: ValidationError) -> FieldError {
+  let params = error
+    .params
     .into_iter()
-    .map(|error| {
-      let default_error = format!("{} is required", error.0);
-      error.1[0]
-        .message
-        .as_ref()
-        .unwrap_or(&std::borrow::Cow::Owned(default_error))
-        .to_string()
-    })
-    .collect()
+    .filter(|(name, _)| name != "value")
+    .map(|(name, value)| (name.to_string(), value))
+    .collect();
+  let message = error.message.map(|message| message.to_string());
+  FieldError::new(path, &error.code, params, message)
 }
 
 #[cfg(test)]
@@ -39,13 +156,32 @@ mod tests {
 
   #[derive(Debug, Deserialize, Serialize, Validate)]
   pub struct TestRequest {
-    #[validate(length(
-      min = 3,
-      message = "first_name is required and must be at least 3 characters"
-    ))]
+    #[validate(length(min = 3))]
+    pub first_name: String,
+  }
+
+  #[derive(Debug, Deserialize, Serialize, Validate)]
+  pub struct TestMessageRequest {
+    #[validate(length(min = 3, message = "Use your full name"))]
     pub first_name: String,
   }
 
+  #[derive(Debug, Deserialize, Serialize, Validate)]
+  pub struct TestAddress {
+    #[validate(length(min = 2))]
+    pub city: String,
+  }
+
+  #[derive(Debug, Deserialize, Serialize, Validate)]
+  pub struct TestNestedRequest {
+    #[validate(email)]
+    pub email: String,
+    #[validate]
+    pub address: TestAddress,
+    #[validate]
+    pub addresses: Vec<TestAddress>,
+  }
+
   fn get_test_request() -> TestRequest {
     let json = json!({"first_name": "a"});
     serde_json::from_value::<TestRequest>(json).unwrap()
@@ -56,16 +192,59 @@ mod tests {
     let request = get_test_request();
     let errors = request.validate().unwrap_err();
     let response = collect_errors(errors);
-    assert!(response.len() > 0);
+    assert!(!response.is_empty());
   }
 
   #[test]
   fn it_validates() {
     let request = get_test_request();
-    let response = validate(&Json(request)).unwrap_err();
-    let expected_error = ApiError::ValidationError(vec![
-      "first_name is required and must be at least 3 characters".to_string(),
-    ]);
-    assert_eq!(response, expected_error);
+    let response = validate(&request).unwrap_err();
+    let mut params = HashMap::new();
+    params.insert("min".to_string(), json!(3));
+    let mut expected_errors = FieldErrors::new();
+    expected_errors.insert(
+      "first_name".into(),
+      vec![FieldError {
+        code: "length".into(),
+        message: "first_name must be at least 3 characters".into(),
+        params,
+      }],
+    );
+    assert_eq!(response, ApiError::ValidationError(expected_errors));
+  }
+
+  #[test]
+  fn it_collects_nested_and_indexed_errors() {
+    let request = TestNestedRequest {
+      email: "satoshi".into(),
+      address: TestAddress { city: "a".into() },
+      addresses: vec![
+        TestAddress {
+          city: "Tokyo".into(),
+        },
+        TestAddress { city: "b".into() },
+      ],
+    };
+    let errors = collect_errors(request.validate().unwrap_err());
+    let paths = errors.keys().cloned().collect::<Vec<_>>();
+    assert_eq!(paths, vec!["address.city", "addresses[1].city", "email"]);
+    assert_eq!(errors["email"][0].code, "email");
+    assert_eq!(errors["email"][0].message, "email must be a valid email");
+  }
+
+  #[test]
+  fn it_prefers_the_validators_message() {
+    let request = TestMessageRequest {
+      first_name: "a".into(),
+    };
+    let errors = collect_errors(request.validate().unwrap_err());
+    assert_eq!(errors["first_name"][0].message, "Use your full name");
+  }
+
+  #[test]
+  fn it_doesnt_include_the_submitted_value() {
+    let request = get_test_request();
+    let errors = collect_errors(request.validate().unwrap_err());
+    assert!(!errors["first_name"][0].params.contains_key("value"));
   }
 }
@@ -0,0 +1,181 @@
+//! Checks used by `ValidateWith` implementations
+//!
+//! Failed checks are added to the field errors, in the same format as the
+//! validator crate's.
+
+use crate::config::CONFIG;
+use crate::errors::ApiError;
+use crate::repositories::user::UserRepo;
+use crate::validate::{add_error, FieldErrors, UNIQUE};
+use actix_web::web::block;
+use serde_json::Value;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Shortest name or email part that a password may not contain
+const MIN_PERSONAL_LENGTH: usize = 3;
+
+/// Password strength requirements
+#[derive(Clone, Debug, PartialEq)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub require_digit: bool,
+    pub require_mixed_case: bool,
+    pub require_symbol: bool,
+}
+
+impl PasswordPolicy {
+    /// The policy set in the config
+    pub fn from_config() -> Self {
+        Self {
+            min_length: CONFIG.password_min_length,
+            require_digit: CONFIG.password_require_digit,
+            require_mixed_case: CONFIG.password_require_mixed_case,
+            require_symbol: CONFIG.password_require_symbol,
+        }
+    }
+
+    /// Check a password against the policy
+    ///
+    /// `personal` holds the values of other fields, such as the user's name
+    /// and email, that the password may not contain.
+    pub fn check(&self, errors: &mut FieldErrors, field: &str, password: &str, personal: &[&str]) {
+        if password.chars().count() < self.min_length {
+            let mut params = HashMap::new();
+            params.insert("min".to_string(), Value::from(self.min_length));
+            add_error(errors, field, "length", params);
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_numeric()) {
+            add_error(errors, field, "password_digit", HashMap::new());
+        }
+        if self.require_mixed_case
+            && !(password.chars().any(char::is_uppercase)
+                && password.chars().any(char::is_lowercase))
+        {
+            add_error(errors, field, "password_mixed_case", HashMap::new());
+        }
+        if self.require_symbol && password.chars().all(char::is_alphanumeric) {
+            add_error(errors, field, "password_symbol", HashMap::new());
+        }
+
+        let password = password.to_lowercase();
+        let contains_personal = personal
+            .iter()
+            .filter(|value| value.chars().count() >= MIN_PERSONAL_LENGTH)
+            .any(|value| password.contains(&value.to_lowercase()));
+        if contains_personal {
+            add_error(errors, field, "password_personal", HashMap::new());
+        }
+    }
+}
+
+/// The part of an email before the @, which a password may not contain
+pub fn email_name(email: &str) -> &str {
+    email.split('@').next().unwrap_or_default()
+}
+
+/// Check that no user other than `except` has the email
+///
+/// Skipped if the email already failed validation.
+pub async fn unique_email(
+    errors: &mut FieldErrors,
+    field: &str,
+    users: &UserRepo,
+    email: &str,
+    except: Option<Uuid>,
+) -> Result<(), ApiError> {
+    if errors.contains_key(field) {
+        return Ok(());
+    }
+    let users = users.clone();
+    let email = email.to_string();
+    if block(move || users.email_exists(&email, except)).await? {
+        add_error(errors, field, UNIQUE, HashMap::new());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::get_data_user_repository;
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            require_digit: true,
+            require_mixed_case: true,
+            require_symbol: true,
+        }
+    }
+
+    fn codes(errors: &FieldErrors) -> Vec<String> {
+        errors
+            .get("password")
+            .map(|errors| errors.iter().map(|error| error.code.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn it_accepts_a_strong_password() {
+        let mut errors = FieldErrors::new();
+        policy().check(&mut errors, "password", "c0rrect-Horse", &["Satoshi"]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn it_reports_every_failed_requirement() {
+        let mut errors = FieldErrors::new();
+        policy().check(&mut errors, "password", "abc", &[]);
+        assert_eq!(
+            codes(&errors),
+            vec![
+                "length",
+                "password_digit",
+                "password_mixed_case",
+                "password_symbol"
+            ]
+        );
+        assert_eq!(
+            errors["password"][0].message,
+            "password must be at least 8 characters"
+        );
+    }
+
+    #[test]
+    fn it_rejects_a_password_containing_personal_values() {
+        let mut errors = FieldErrors::new();
+        let personal = [
+            "Satoshi",
+            "Nakamoto",
+            email_name("satoshi@nakamotoinstitute.org"),
+        ];
+        policy().check(&mut errors, "password", "Satoshi-2009!", &personal);
+        assert_eq!(codes(&errors), vec!["password_personal"]);
+    }
+
+    #[test]
+    fn it_gets_the_name_of_an_email() {
+        assert_eq!(email_name("satoshi@nakamotoinstitute.org"), "satoshi");
+        assert_eq!(email_name(""), "");
+    }
+
+    #[actix_rt::test]
+    async fn it_checks_that_an_email_is_unique() {
+        let users = get_data_user_repository();
+        let user = users.get_all().unwrap().0.remove(0);
+
+        let mut errors = FieldErrors::new();
+        unique_email(&mut errors, "email", &users, &user.email, None)
+            .await
+            .unwrap();
+        assert_eq!(errors["email"][0].code, "unique");
+        assert_eq!(errors["email"][0].message, "email is already taken");
+
+        let mut errors = FieldErrors::new();
+        unique_email(&mut errors, "email", &users, &user.email, Some(user.id))
+            .await
+            .unwrap();
+        assert!(errors.is_empty());
+    }
+}